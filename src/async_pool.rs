@@ -0,0 +1,97 @@
+use crate::buddy_error::BuddyError;
+use crate::thread_safe::{PoolGuard, ThreadSafeBuddyPool};
+use core::future::Future;
+use core::pin::Pin;
+use core::task::{Context, Poll};
+
+/// Wraps a ThreadSafeBuddyPool for async code. When the pool is locked, the allocating task
+/// registers its waker and yields to the executor instead of blocking its thread, and it is woken
+/// once the pool is unlocked.
+///
+/// The futures borrow raw pointers, so they are not Send and must be polled on the thread that
+/// created them.
+pub struct AsyncBuddyPool {
+    pool: ThreadSafeBuddyPool, // The shared pool
+}
+
+impl AsyncBuddyPool {
+    /// Create a new async pool around a thread-safe pool
+    ///
+    /// # Arguments
+    /// * pool - The pool to allocate from
+    ///
+    /// # Returns
+    /// a new AsyncBuddyPool struct
+    pub fn new(pool: ThreadSafeBuddyPool) -> AsyncBuddyPool {
+        AsyncBuddyPool { pool }
+    }
+
+    /// Returns the thread-safe pool, for callers that want to block instead of awaiting.
+    ///
+    /// # Returns
+    /// the wrapped pool
+    pub fn pool(&self) -> &ThreadSafeBuddyPool {
+        &self.pool
+    }
+
+    /// Waits until the pool can be locked without blocking the thread.
+    ///
+    /// # Returns
+    /// a future resolving to a guard giving access to the pool
+    pub fn lock(&self) -> Lock<'_> {
+        Lock { pool: &self.pool }
+    }
+
+    /// Waits for the pool and calls malloc on it, see BuddyPool::malloc
+    ///
+    /// # Arguments
+    /// * size - The size of the user requested memory block in bytes
+    ///
+    /// # Returns
+    /// a pointer to the memory block
+    pub async fn malloc(&self, size: usize) -> Result<*mut u8, BuddyError> {
+        self.lock().await.malloc(size)
+    }
+
+    /// Waits for the pool and calls free on it, see BuddyPool::free
+    ///
+    /// # Arguments
+    /// * ptr - Pointer to the memory block to free
+    pub async fn free(&self, ptr: *mut u8) {
+        self.lock().await.free(ptr)
+    }
+
+    /// Waits for the pool and calls realloc on it, see BuddyPool::realloc
+    ///
+    /// # Arguments
+    /// * ptr - Pointer to a memory block
+    /// * size - The new size of the memory block
+    ///
+    /// # Returns
+    /// a pointer to the new memory block
+    pub async fn realloc(&self, ptr: *mut u8, size: usize) -> Result<*mut u8, BuddyError> {
+        self.lock().await.realloc(ptr, size)
+    }
+}
+
+/// A future that resolves once the pool has been locked, see AsyncBuddyPool::lock.
+pub struct Lock<'a> {
+    pool: &'a ThreadSafeBuddyPool, // The pool to lock
+}
+
+impl<'a> Future for Lock<'a> {
+    type Output = PoolGuard<'a>;
+
+    /// Tries to lock the pool, and otherwise registers the task to be woken when it is unlocked.
+    /// The lock is tried again after registering, in case the pool was unlocked in between.
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<PoolGuard<'a>> {
+        if let Some(guard) = self.pool.try_lock() {
+            return Poll::Ready(guard);
+        }
+        self.pool.wait(cx.waker());
+        match self.pool.try_lock() {
+            Some(guard) => Poll::Ready(guard),
+            None => Poll::Pending,
+        }
+    }
+}
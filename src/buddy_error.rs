@@ -5,6 +5,12 @@ use core::fmt;
 pub enum BuddyError {
     NoMemory,
     CorruptedMemoryPool,
+    InvalidAlignment,
+    PoolNotEmpty,
+    ReserveFloor,
+    InvalidSize,
+    LiveAllocationLimit,
+    ConfigMismatch,
 }
 
 impl fmt::Debug for BuddyError {
@@ -12,7 +18,25 @@ impl fmt::Debug for BuddyError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match *self {
             BuddyError::NoMemory => write!(f, "Insufficient memory available"),
-            BuddyError::CorruptedMemoryPool => write!(f, "Memory pool is corrupted or invalid")
+            BuddyError::CorruptedMemoryPool => write!(f, "Memory pool is corrupted or invalid"),
+            BuddyError::InvalidAlignment => write!(f, "Alignment is not a power of two"),
+            BuddyError::PoolNotEmpty => {
+                write!(f, "Request needs the whole pool but part of it is in use")
+            }
+            BuddyError::ReserveFloor => write!(
+                f,
+                "Request would leave less free memory than the reserve floor"
+            ),
+            BuddyError::InvalidSize => {
+                write!(f, "Pool or block size is not a supported power of two")
+            }
+            BuddyError::LiveAllocationLimit => {
+                write!(f, "Request would exceed the live allocation limit")
+            }
+            BuddyError::ConfigMismatch => write!(
+                f,
+                "Pool configuration does not match the expected fingerprint"
+            ),
         }
     }
 }
@@ -22,4 +46,4 @@ impl fmt::Display for BuddyError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(f, "{:?}", self)
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,225 @@
+use crate::buddy_error::BuddyError;
+use crate::metadata::MetadataTable;
+use crate::{set_errno, Avail, BuddyPool, ENOMEM};
+
+/// How far a pool grows when it runs out of memory and is allowed to grow. The new size is always
+/// a power of two so that the buddy system can manage it.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum GrowthFactor {
+    /// Grow to the smallest power of two that fits the request
+    Fit,
+    /// Grow to double the smallest power of two that fits the request
+    Double,
+}
+
+/// The effective configuration of a BuddyPool, as returned by BuddyPool::config.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PoolConfig {
+    pub min_kval: usize,     // The kval of the smallest block the pool hands out
+    pub max_kval: usize,     // The largest kval the pool can grow to
+    pub kval_m: usize,       // The current kval of the whole pool
+    pub auto_grow: bool,     // Whether realloc may grow the pool
+    pub grow_in_place: bool, // Whether realloc may merge blocks with their free buddies
+    pub growth_factor: GrowthFactor, // How far the pool grows
+    pub no_coalesce: Option<(usize, usize)>, // The kval range that free does not coalesce
+    pub reserve_floor_bytes: usize, // The free memory malloc leaves for malloc_emergency
+    pub max_live_allocations: Option<usize>, // The most allocations that may be live at once
+    pub out_of_band_metadata: bool, // Whether malloc keeps block metadata out of band
+    pub trailing_headers: bool, // Whether malloc keeps the header at the end of the block
+    pub numa_node: Option<u32>, // The NUMA node the pool's pages are bound to
+    pub bump_region_bytes: usize, // The size of the region bump_alloc carves from
+    pub reserved_header_bytes: usize, // The bytes mapped past the pool for pool-level metadata
+}
+
+/// Builder for a BuddyPool with options that BuddyPool::new leaves at their defaults.
+pub struct BuddyPoolBuilder {
+    size: usize,                         // The size of the pool in bytes
+    auto_grow: bool,                     // Whether realloc may grow the pool
+    grow_in_place: bool,                 // Whether realloc may merge blocks with their free buddies
+    growth_factor: GrowthFactor,         // How far the pool grows
+    reserve_floor_bytes: usize,          // The free memory malloc leaves for malloc_emergency
+    max_live_allocations: Option<usize>, // The most allocations that may be live at once
+    out_of_band_metadata: bool,          // Whether malloc keeps block metadata out of band
+    trailing_headers: bool,              // Whether malloc keeps the header at the end of the block
+    numa_node: Option<u32>,              // The NUMA node to bind the pool's pages to
+    bump_region_bytes: usize,            // The size of the region bump_alloc carves from
+    reserved_header_bytes: usize,        // The bytes mapped past the pool for pool-level metadata
+}
+
+impl BuddyPoolBuilder {
+    /// Create a new builder with the same defaults as BuddyPool::new(0)
+    ///
+    /// # Returns
+    /// a new BuddyPoolBuilder
+    pub fn new() -> BuddyPoolBuilder {
+        BuddyPoolBuilder {
+            size: 0,
+            auto_grow: false,
+            grow_in_place: false,
+            growth_factor: GrowthFactor::Fit,
+            reserve_floor_bytes: 0,
+            max_live_allocations: None,
+            out_of_band_metadata: false,
+            trailing_headers: false,
+            numa_node: None,
+            bump_region_bytes: 0,
+            reserved_header_bytes: 0,
+        }
+    }
+
+    /// Sets the size of the pool in bytes, which is rounded the same way as in BuddyPool::new
+    ///
+    /// # Arguments
+    /// * size - The size of the pool in bytes
+    pub fn size(mut self, size: usize) -> BuddyPoolBuilder {
+        self.size = size;
+        self
+    }
+
+    /// Sets whether realloc may grow the pool, see BuddyPool::set_auto_grow
+    ///
+    /// # Arguments
+    /// * enabled - Whether realloc may grow the pool
+    pub fn auto_grow(mut self, enabled: bool) -> BuddyPoolBuilder {
+        self.auto_grow = enabled;
+        self
+    }
+
+    /// Sets whether realloc may grow blocks by merging them with the free buddies below them, see
+    /// BuddyPool::set_grow_in_place
+    ///
+    /// # Arguments
+    /// * enabled - Whether realloc may merge blocks with their free buddies
+    pub fn grow_in_place(mut self, enabled: bool) -> BuddyPoolBuilder {
+        self.grow_in_place = enabled;
+        self
+    }
+
+    /// Sets how far the pool grows when it runs out of memory. Memory constrained users can grow
+    /// just enough to fit each request, while throughput oriented users can double to grow less
+    /// often.
+    ///
+    /// # Arguments
+    /// * factor - How far the pool grows
+    pub fn growth_factor(mut self, factor: GrowthFactor) -> BuddyPoolBuilder {
+        self.growth_factor = factor;
+        self
+    }
+
+    /// Sets the amount of free memory that malloc must leave behind. A malloc that would drop the
+    /// free memory below the floor fails with ReserveFloor, keeping the rest as emergency headroom
+    /// that only malloc_emergency may use.
+    ///
+    /// # Arguments
+    /// * bytes - The free memory to keep in reserve, including headers
+    pub fn reserve_floor_bytes(mut self, bytes: usize) -> BuddyPoolBuilder {
+        self.reserve_floor_bytes = bytes;
+        self
+    }
+
+    /// Sets the most allocations that may be live at once, to bound tables that callers keep per
+    /// allocation. An allocation that would go over the limit fails with LiveAllocationLimit.
+    ///
+    /// # Arguments
+    /// * count - The most live allocations
+    pub fn max_live_allocations(mut self, count: usize) -> BuddyPoolBuilder {
+        self.max_live_allocations = Some(count);
+        self
+    }
+
+    /// Sets whether malloc keeps the metadata of the blocks it hands out in a separate table instead
+    /// of a header in front of each block. The returned pointer is then the start of the block, so
+    /// user data starts on the block's own alignment and its cache lines hold no allocator
+    /// metadata. The table takes a few bytes for every 32 bytes of pool.
+    ///
+    /// # Arguments
+    /// * enabled - Whether to keep metadata out of band
+    pub fn out_of_band_metadata(mut self, enabled: bool) -> BuddyPoolBuilder {
+        self.out_of_band_metadata = enabled;
+        self
+    }
+
+    /// Sets whether malloc keeps the header of the blocks it hands out at the end of the block
+    /// instead of in front of it. As with out of band metadata the returned pointer is the start
+    /// of the block, aligned to the block size, but no table is needed: free finds the header from
+    /// the pointer alone. The header still takes 32 bytes of each block. This has no effect on a
+    /// pool with out of band metadata, and the other allocation functions keep the header in front.
+    ///
+    /// # Arguments
+    /// * enabled - Whether to keep headers at the end of blocks
+    pub fn trailing_headers(mut self, enabled: bool) -> BuddyPoolBuilder {
+        self.trailing_headers = enabled;
+        self
+    }
+
+    /// Sets the NUMA node the pool's pages are bound to, so that memory is local to the threads
+    /// running on that node. Binding needs the numa feature and Linux with NUMA support, and is
+    /// skipped silently otherwise or if the node does not exist; BuddyPool::numa_node tells
+    /// whether it took effect.
+    ///
+    /// # Arguments
+    /// * node - The NUMA node to bind to
+    pub fn numa_node(mut self, node: u32) -> BuddyPoolBuilder {
+        self.numa_node = Some(node);
+        self
+    }
+
+    /// Sets the size of the region that BuddyPool::bump_alloc carves allocations from. The region
+    /// is reserved as one block the first time bump_alloc is called. With the default of 0,
+    /// bump_alloc always fails.
+    ///
+    /// # Arguments
+    /// * bytes - The size of the bump region in bytes
+    pub fn bump_region_bytes(mut self, bytes: usize) -> BuddyPoolBuilder {
+        self.bump_region_bytes = bytes;
+        self
+    }
+
+    /// Sets the size of a pool-level header mapped right after the memory the buddy system
+    /// manages, for metadata such as a magic number that must live next to the pool. The header
+    /// is not part of capacity, so mapped_bytes exceeds capacity by exactly this size, and it
+    /// moves along when the pool grows. BuddyPool::reserved_header gives access to it.
+    ///
+    /// # Arguments
+    /// * bytes - The size of the header in bytes
+    pub fn reserved_header_bytes(mut self, bytes: usize) -> BuddyPoolBuilder {
+        self.reserved_header_bytes = bytes;
+        self
+    }
+
+    /// Creates the memory pool. As with BuddyPool::new, the caller must call init on the pool.
+    ///
+    /// # Returns
+    /// the new memory pool
+    pub fn build(self) -> Result<BuddyPool, BuddyError> {
+        let mut pool = BuddyPool::new(self.size)?;
+        pool.auto_grow = self.auto_grow;
+        pool.grow_in_place = self.grow_in_place;
+        pool.growth_factor = self.growth_factor;
+        pool.reserve_floor = self.reserve_floor_bytes;
+        pool.max_live_allocations = self.max_live_allocations;
+        pool.bump_size = self.bump_region_bytes;
+        pool.trailing_headers = self.trailing_headers;
+        if self.reserved_header_bytes > 0 {
+            let Some(len) = pool.capacity().checked_add(self.reserved_header_bytes) else {
+                set_errno(ENOMEM);
+                return Err(BuddyError::NoMemory);
+            };
+            pool.remap(len)?;
+            pool.header_bytes = self.reserved_header_bytes;
+        }
+        if self.out_of_band_metadata {
+            pool.metadata = Some(MetadataTable::new(pool.capacity(), size_of::<Avail>())?);
+        }
+        if let Some(node) = self.numa_node {
+            pool.bind_numa(node);
+        }
+        Ok(pool)
+    }
+}
+
+impl Default for BuddyPoolBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
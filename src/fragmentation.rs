@@ -0,0 +1,53 @@
+/// The number of fragmentation samples kept before the oldest are overwritten.
+const FRAGMENTATION_SAMPLES: usize = 16;
+
+/// A ring buffer of recent fragmentation samples, used to tell whether fragmentation is getting
+/// worse or better over time.
+pub(crate) struct FragmentationHistory {
+    samples: [u32; FRAGMENTATION_SAMPLES], // The ring buffer of fragmentation in per mille
+    next: usize,                           // The index the next sample is written to
+    len: usize,                            // The number of valid samples
+}
+
+impl FragmentationHistory {
+    /// Create a new empty history
+    ///
+    /// # Returns
+    /// a new FragmentationHistory struct
+    pub(crate) fn new() -> FragmentationHistory {
+        FragmentationHistory {
+            samples: [0; FRAGMENTATION_SAMPLES],
+            next: 0,
+            len: 0,
+        }
+    }
+
+    /// Records a fragmentation sample, overwriting the oldest one if the history is full
+    ///
+    /// # Arguments
+    /// * sample - The fragmentation in per mille
+    pub(crate) fn record(&mut self, sample: u32) {
+        self.samples[self.next] = sample;
+        self.next = (self.next + 1) % FRAGMENTATION_SAMPLES;
+        self.len = (self.len + 1).min(FRAGMENTATION_SAMPLES);
+    }
+
+    /// Computes the least squares slope of the samples from oldest to newest
+    ///
+    /// # Returns
+    /// the change in fragmentation per sample in per mille, 0 with fewer than two samples
+    pub(crate) fn trend(&self) -> i32 {
+        let n = self.len as i64;
+        if n < 2 {
+            return 0;
+        }
+        let oldest = (self.next + FRAGMENTATION_SAMPLES - self.len) % FRAGMENTATION_SAMPLES;
+        // Positions are doubled and centred so that the mean position is 0 without fractions
+        let mut covariance = 0;
+        for x in 0..n {
+            let y = self.samples[(oldest + x as usize) % FRAGMENTATION_SAMPLES] as i64;
+            covariance += (2 * x - (n - 1)) * y;
+        }
+        (6 * covariance / (n * (n * n - 1))) as i32
+    }
+}
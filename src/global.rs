@@ -0,0 +1,94 @@
+use crate::sync_pool::SpinGuard;
+use crate::{Avail, BuddyPool};
+use core::alloc::{GlobalAlloc, Layout};
+use core::cell::UnsafeCell;
+use core::ptr;
+use core::sync::atomic::AtomicBool;
+
+/// A buddy pool that can back `#[global_allocator]`. The pool is created on the first allocation,
+/// in place inside the wrapper, so the wrapper can be built in a static. Every call takes a spin
+/// lock around the pool, as there is no operating system lock to fall back on in a no_std binary.
+///
+/// Allocation failures return null as GlobalAlloc requires, which makes the program abort with an
+/// allocation error.
+pub struct GlobalBuddy {
+    size: usize,        // The size of the pool in bytes, as for BuddyPool::new
+    locked: AtomicBool, // Whether a thread is using the pool
+    pool: UnsafeCell<Option<BuddyPool>>, // The pool, None until the first allocation
+}
+
+// SAFETY: the pool is only touched with the spin lock held, and the raw pointers in it only point
+// into its own mapping, which is not tied to a thread.
+unsafe impl Sync for GlobalBuddy {}
+
+impl GlobalBuddy {
+    /// Create a new global allocator whose pool will hold size bytes. Nothing is mapped until the
+    /// first allocation.
+    ///
+    /// # Arguments
+    /// * size - The size of the pool in bytes, rounded as in BuddyPool::new
+    ///
+    /// # Returns
+    /// a new GlobalBuddy struct
+    pub const fn new(size: usize) -> GlobalBuddy {
+        GlobalBuddy {
+            size,
+            locked: AtomicBool::new(false),
+            pool: UnsafeCell::new(None),
+        }
+    }
+
+    /// Runs f with the pool locked, creating the pool if this is the first use. This gives access
+    /// to the pool's statistics, but f must not allocate through the global allocator, as the lock
+    /// is not reentrant. The lock is released even if f panics.
+    ///
+    /// # Arguments
+    /// * f - The function to run with the pool
+    ///
+    /// # Returns
+    /// the result of f, or None if the pool could not be created
+    pub fn with_pool<R>(&self, f: impl FnOnce(&mut BuddyPool) -> R) -> Option<R> {
+        let _guard = SpinGuard::lock(&self.locked);
+        let slot = unsafe { &mut *self.pool.get() };
+        if slot.is_none() {
+            // The pool is created in its final place, so it can be initialized right away
+            *slot = BuddyPool::new(self.size).ok();
+            if let Some(pool) = slot.as_mut() {
+                pool.init();
+            }
+        }
+        slot.as_mut().map(f)
+    }
+}
+
+unsafe impl GlobalAlloc for GlobalBuddy {
+    /// Allocates a block for layout, over-allocating through malloc_aligned when the alignment is
+    /// larger than malloc's natural alignment.
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        self.with_pool(|pool| pool.malloc_aligned(layout.size(), layout.align()))
+            .and_then(Result::ok)
+            .unwrap_or(ptr::null_mut())
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, _layout: Layout) {
+        self.with_pool(|pool| pool.free(ptr));
+    }
+
+    /// Resizes the block with the pool's realloc. realloc only keeps malloc's natural alignment
+    /// when it moves a block, so blocks with a larger alignment are moved by hand instead.
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        if layout.align() <= size_of::<Avail>() {
+            return self
+                .with_pool(|pool| pool.realloc(ptr, new_size))
+                .and_then(Result::ok)
+                .unwrap_or(ptr::null_mut());
+        }
+        let new_layout = Layout::from_size_align_unchecked(new_size, layout.align());
+        let new_ptr = self.alloc(new_layout);
+        if !new_ptr.is_null() {
+            new_ptr.copy_from_nonoverlapping(ptr, layout.size().min(new_size));
+            self.dealloc(ptr, layout);
+        }
+        new_ptr
+    }
+}
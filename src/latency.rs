@@ -0,0 +1,68 @@
+/// The number of latency samples kept before the oldest are overwritten.
+const LATENCY_SAMPLES: usize = 64;
+
+/// A ring buffer of operation latencies timed with a caller-provided clock.
+pub(crate) struct LatencySampler {
+    clock: Option<fn() -> u64>,      // The clock used to time operations
+    samples: [u64; LATENCY_SAMPLES], // The ring buffer of durations
+    next: usize,                     // The index the next sample is written to
+    len: usize,                      // The number of valid samples
+    depth: usize,                    // The number of operations currently being timed
+}
+
+impl LatencySampler {
+    /// Create a new sampler with no clock installed
+    ///
+    /// # Returns
+    /// a new LatencySampler struct
+    pub(crate) fn new() -> LatencySampler {
+        LatencySampler {
+            clock: None,
+            samples: [0; LATENCY_SAMPLES],
+            next: 0,
+            len: 0,
+            depth: 0,
+        }
+    }
+
+    /// Installs the clock used to time operations
+    ///
+    /// # Arguments
+    /// * clock - A function returning the current time
+    pub(crate) fn set_clock(&mut self, clock: fn() -> u64) {
+        self.clock = Some(clock);
+    }
+
+    /// Starts timing an operation. Only the outermost of nested operations reads the clock.
+    ///
+    /// # Returns
+    /// the start time, or None if the operation should not be sampled
+    pub(crate) fn begin(&mut self) -> Option<u64> {
+        self.depth += 1;
+        match self.clock {
+            Some(clock) if self.depth == 1 => Some(clock()),
+            _ => None,
+        }
+    }
+
+    /// Finishes timing an operation and records its duration if it was sampled
+    ///
+    /// # Arguments
+    /// * start - The value returned by the matching begin
+    pub(crate) fn end(&mut self, start: Option<u64>) {
+        self.depth -= 1;
+        if let (Some(start), Some(clock)) = (start, self.clock) {
+            self.samples[self.next] = clock().wrapping_sub(start);
+            self.next = (self.next + 1) % LATENCY_SAMPLES;
+            self.len = (self.len + 1).min(LATENCY_SAMPLES);
+        }
+    }
+
+    /// Returns the recorded samples in ring order
+    ///
+    /// # Returns
+    /// the valid part of the ring buffer
+    pub(crate) fn samples(&self) -> &[u64] {
+        &self.samples[..self.len]
+    }
+}
@@ -1,12 +1,68 @@
 //! # Buddy Memory Allocator
 #![no_std]
+#![cfg_attr(feature = "allocator_api", feature(allocator_api))]
+// The crate name is fixed by the course build (libP3.so)
+#![allow(non_snake_case)]
+// The public API mirrors the C malloc family and takes raw pointers in safe functions by design
+#![allow(clippy::not_unsafe_ptr_arg_deref)]
+#[cfg(feature = "std")]
+extern crate std;
+
+#[cfg(feature = "async_pool")]
+mod async_pool;
 mod buddy_error;
+mod builder;
+#[cfg(feature = "fragmentation_trend")]
+mod fragmentation;
+mod global;
+#[cfg(feature = "latency_sampling")]
+mod latency;
+mod list;
+#[cfg(feature = "maintainer")]
+mod maintainer;
+mod metadata;
+mod multi_pool;
+#[cfg(feature = "numa")]
+mod numa;
+mod region;
+#[cfg(feature = "replay")]
+mod replay;
+mod slab;
+mod sync_pool;
 mod tests;
+#[cfg(feature = "std")]
+mod thread_safe;
 
+#[cfg(feature = "async_pool")]
+pub use crate::async_pool::{AsyncBuddyPool, Lock};
 use crate::buddy_error::BuddyError;
-use core::{array, ptr};
-use errno::*;
+pub use crate::builder::{BuddyPoolBuilder, GrowthFactor, PoolConfig};
+#[cfg(feature = "fragmentation_trend")]
+use crate::fragmentation::FragmentationHistory;
+pub use crate::global::GlobalBuddy;
+#[cfg(feature = "latency_sampling")]
+use crate::latency::LatencySampler;
+use crate::list::{head_link, UNLINKED};
+#[cfg(feature = "maintainer")]
+pub use crate::maintainer::{spawn_maintainer, MaintainerHandle};
+use crate::metadata::{Metadata, MetadataTable};
+pub use crate::multi_pool::MultiBuddyPool;
+use crate::region::Region;
+#[cfg(feature = "replay")]
+pub use crate::replay::{Event, Location, Player, Recorder};
+pub use crate::slab::SlabCache;
+pub use crate::sync_pool::SyncBuddyPool;
+#[cfg(feature = "std")]
+pub use crate::thread_safe::{PoolGuard, ThreadSafeBuddyPool};
+use core::ptr::NonNull;
+use core::{array, fmt, ptr};
 use memmap2::MmapMut;
+#[cfg(feature = "std")]
+use memmap2::MmapOptions;
+#[cfg(feature = "std")]
+use std::fs::File;
+#[cfg(feature = "std")]
+use std::vec::Vec;
 
 /// The default amount of memory that this memory manger will manage unless explicitly set. The
 /// number of bytes is calculated as 2^DEFAULT_K
@@ -16,25 +72,122 @@ const DEFAULT_K: usize = 30;
 const MIN_K: usize = 20;
 
 /// The maximum size of the buddy memory pool. This is 1 larger than needed to allow indexes 1-N
-/// instead of 0-N. Internally the maximum amount of memory is MAX_K-1
-const MAX_K: usize = 48;
+/// instead of 0-N. Internally the maximum amount of memory is MAX_K-1. Where usize is narrower
+/// than 64 bits the pool is capped below half the address space, as no object can be larger than
+/// isize::MAX bytes.
+const MAX_K: usize = if (usize::BITS as usize) - 1 < 48 {
+    usize::BITS as usize - 1
+} else {
+    48
+};
+
+// A target whose address space can not hold the minimum pool, such as a 16 bit one, is rejected
+// here rather than failing every shift of MIN_K at run time
+const _: () = assert!(
+    MIN_K < MAX_K,
+    "usize is too narrow to hold the minimum pool size"
+);
 
 const BLOCK_AVAIL: u8 = 1; // Block is available to allocate
 const BLOCK_RESERVED: u8 = 0; // Block has been handed to user
+const BLOCK_ALIGNED: u8 = 2; // Marker in front of an aligned pointer, kval is the offset to the header
 const BLOCK_UNUSED: u8 = 3; // Block is not used at all
 
+/// The bits of a tag that hold one of the BLOCK_ states above. The rest of the byte holds flags.
+const TAG_STATE_MASK: u8 = 0b11;
+
+/// Tag flag on the header of a block whose pointer malloc_aligned moved past the natural user
+/// pointer, so that a BLOCK_ALIGNED marker sits in front of the pointer
+const TAG_ALIGNED: u8 = 1 << 2;
+
+/// Tag flag on a header kept at the end of its block, see BuddyPoolBuilder::trailing_headers
+const TAG_TRAILING: u8 = 1 << 3;
+
+/// Tag flag on the header of a block from malloc_with_hint, which realloc does not shrink so that
+/// the block can grow back up to its hint in place
+const TAG_HINTED: u8 = 1 << 4;
+
+/// The number of headerless blocks from malloc_block_aligned that can be live at once
+const BLOCK_ALIGNED_SLOTS: usize = 16;
+
+/// The number of recently shrunk blocks the hold cache remembers for each kval
+const HELD_SLOTS: usize = 4;
+
+/// Byte written over the body and into the header of freed blocks to detect use after free
+#[cfg(feature = "uaf_detect")]
+const FREED_MARKER: u8 = 0xFD;
+
+/// Byte written over the body of freed blocks, so that reads through dangling pointers stand out.
+/// With uaf_detect the freed marker is written instead.
+#[cfg(all(feature = "debug_poison", not(feature = "uaf_detect")))]
+const POISON_FREED: u8 = 0xDE;
+
+/// Byte written over the body of newly allocated blocks, so that reads of memory the caller never
+/// wrote stand out
+#[cfg(feature = "debug_poison")]
+const POISON_ALLOCATED: u8 = 0xAB;
+
 /// The error code for ENOMEM as defined in the POSIX standard
-const ENOMEM: Errno = Errno(12);
+const ENOMEM: i32 = 12;
+
+/// The error code for EINVAL as defined in the POSIX standard
+const EINVAL: i32 = 22;
+
+/// The pointer realloc returns when it frees a block because the new size is 0. Like the dangling
+/// pointer used for zero sized types it is non-null and aligned but must never be dereferenced. It
+/// is never inside a pool, so passing it to free does nothing, and realloc treats it like null.
+pub const ZERO_SIZE_PTR: *mut u8 = ptr::NonNull::<Avail>::dangling().as_ptr() as *mut u8;
 
 /// Struct to represent the table of all available blocks
 struct Avail {
-    tag: u8,          // Tag for block status BLOCK_AVAIL, BLOCK_RESERVED
-    kval: usize,      // The kval of this block
-    next: *mut Avail, // next memory block
-    prev: *mut Avail, // prev memory block
+    tag: u8,       // Block state BLOCK_AVAIL, BLOCK_RESERVED... in the low bits, TAG_ flags above
+    req_size: u32, // Bytes in use past the header of a reserved block, saturated at u32::MAX
+    generation: u16, // The allocation count at the time this block was allocated
+    kval: usize,   // The kval of this block
+    next: usize,   // Offset of the next block from the pool base, or the link of the list head
+    prev: usize,   // Offset of the prev block from the pool base, or the link of the list head
+    #[cfg(feature = "uaf_detect")]
+    marker: u8, // FREED_MARKER while the body holds the freed marker
 }
 
 impl Avail {
+    /// Returns the state of the block, without its flags.
+    ///
+    /// # Returns
+    /// one of the BLOCK_ states
+    fn state(&self) -> u8 {
+        self.tag & TAG_STATE_MASK
+    }
+
+    /// Sets the state of the block, keeping its flags.
+    ///
+    /// # Arguments
+    /// * state - One of the BLOCK_ states
+    fn set_state(&mut self, state: u8) {
+        self.tag = (self.tag & !TAG_STATE_MASK) | state;
+    }
+
+    /// Returns whether a flag is set in the block's tag.
+    ///
+    /// # Arguments
+    /// * flag - One of the TAG_ flags
+    fn has_flag(&self, flag: u8) -> bool {
+        self.tag & flag != 0
+    }
+
+    /// Sets or clears a flag in the block's tag, keeping its state.
+    ///
+    /// # Arguments
+    /// * flag - One of the TAG_ flags
+    /// * enabled - Whether to set the flag
+    fn set_flag(&mut self, flag: u8, enabled: bool) {
+        if enabled {
+            self.tag |= flag;
+        } else {
+            self.tag &= !flag;
+        }
+    }
+
     /// Create a new Avail struct with default values
     ///
     /// # Returns
@@ -42,21 +195,127 @@ impl Avail {
     fn new() -> Avail {
         Avail {
             tag: BLOCK_UNUSED,
+            req_size: 0,
+            generation: 0,
             kval: 0,
-            next: ptr::null_mut(),
-            prev: ptr::null_mut(),
+            next: UNLINKED,
+            prev: UNLINKED,
+            #[cfg(feature = "uaf_detect")]
+            marker: 0,
         }
     }
 }
 
-/// The buddy memory pool.
-pub struct BuddyPool {
-    kval_m: usize,         // The max kval of this pool
-    base: MmapMut,         // Base address used to scale memory for buddy calculations
-    avail: [Avail; MAX_K], // The array of available memory blocks
+/// A reference to an allocation that stays valid when the pool moves. It holds the allocation's
+/// offset from the pool base and the generation it was allocated in, so that a handle to an
+/// allocation that has since been freed can be told apart from a newer allocation at the same
+/// place.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Handle {
+    offset: usize,   // The offset of the user pointer from the pool base
+    generation: u16, // The generation of the allocation
+}
+
+/// The outcome of realloc_detailed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ReallocResult {
+    pub new_ptr: *mut u8,         // The pointer to the resized memory block
+    pub moved: bool,              // Whether the memory block was moved to a new location
+    pub old_ptr: Option<*mut u8>, // The old pointer, which has been freed, if the block moved
+}
+
+/// An operation reported to the observer installed with BuddyPool::set_observer.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AllocEvent {
+    Malloc {
+        ptr: *mut u8, // The returned pointer
+        size: usize,  // The requested size
+        kval: usize,  // The kval of the block handed out
+    },
+    Free {
+        ptr: *mut u8, // The freed pointer
+        kval: usize,  // The kval of the block before it was coalesced
+    },
+    Realloc {
+        old_ptr: *mut u8, // The pointer passed in
+        new_ptr: *mut u8, // The returned pointer
+        new_size: usize,  // The requested size
+    },
+}
+
+/// A snapshot of the pool's statistics, as returned by BuddyPool::stats.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BuddyStats {
+    pub total_bytes: usize, // The bytes the buddy system manages, see BuddyPool::capacity
+    pub used_bytes: usize,  // Bytes in reserved blocks, headers included
+    pub free_bytes: usize,  // Bytes in free blocks, headers included
+    pub largest_free_block: usize, // The size of the largest free block including its header
+    pub alignment_padding_bytes: usize, // Bytes skipped in front of live aligned allocations
+}
+
+/// The state of a block, as listed by BuddyPool::allocation_table.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BlockTag {
+    /// The block is free and on an avail list
+    Avail,
+    /// The block has been handed to the user
+    Reserved,
+}
+
+/// The buddy memory pool. N is the number of avail list heads, one per kval, so the pool can never
+/// reach a kval of N. The default covers every supported pool size; a smaller N, made with
+/// with_max_k, shrinks the struct for pools that are known to stay small.
+pub struct BuddyPool<const N: usize = MAX_K> {
+    kval_m: usize,                                   // The max kval of this pool
+    min_kval: usize, // The kval of the smallest block malloc hands out, splitting stops there
+    base: Region,    // Base address used to scale memory for buddy calculations
+    base_ptr: *mut u8, // Pointer to the mapping taken once, so writes through it keep their provenance
+    avail: [Avail; N], // The array of available memory blocks
+    auto_grow: bool,   // Whether realloc may grow the pool when it runs out of memory
+    grow_in_place: bool, // Whether realloc may grow a block by merging it with lower free buddies
+    trailing_headers: bool, // Whether malloc keeps the header at the end of the block
+    epoch: usize,      // Bumped by init, so trailing headers from before it are not taken as live
+    free_counts: [usize; N], // The number of blocks in each avail list
+    held: [[Option<(usize, usize)>; HELD_SLOTS]; N], // Offset and former kval of shrunk blocks, by kval
+    no_coalesce: Option<(usize, usize)>,             // The kval range that free does not coalesce
+    growth_factor: GrowthFactor, // How far the pool grows when it runs out of memory
+    block_aligned: [Option<(usize, usize)>; BLOCK_ALIGNED_SLOTS], // Offset and kval of headerless blocks
+    generation: u16, // The generation given to the next allocated block
+    scratch: Option<(usize, usize, usize)>, // Offset, size and high-water mark of the scratch block
+    bump_size: usize, // The size of the bump region in bytes, 0 if bump_alloc is off
+    header_bytes: usize, // The size of the pool-level header mapped after capacity, 0 if none
+    bump: Option<(usize, usize, usize)>, // Start, next free and end offsets of the bump region
+    capacity_limit: Option<usize>, // The number of bytes malloc may reserve, below the real size
+    reserve_floor: usize, // The free memory malloc leaves for malloc_emergency
+    alignment_padding: usize, // Bytes skipped in front of live aligned allocations
+    peak_used: usize, // The most bytes reserved at once since the last take_peak_used_bytes
+    live_allocations: usize, // The number of allocations that have not been freed
+    max_single_alloc_kval: usize, // The kval of the largest block ever handed out, 0 if none
+    max_live_allocations: Option<usize>, // The most allocations that may be live at once
+    metadata: Option<MetadataTable>, // Out of band metadata of blocks malloc hands out headerless
+    numa_node: Option<u32>, // The NUMA node the pool's pages are bound to, if binding took effect
+    oom_handler: Option<fn(&mut BuddyPool<N>, usize) -> bool>, // Frees memory when malloc runs out
+    oom_invocations: u64, // The number of times malloc called the OOM handler
+    alloc_failures: u64, // The number of out of memory errors from malloc, calloc and realloc
+    oom_successes: u64, // The number of those calls after which malloc's retry succeeded
+    observer: Option<fn(AllocEvent)>, // Told about every successful malloc, free and realloc
+    #[cfg(feature = "chaos")]
+    chaos: Option<(u32, u64)>, // The failure threshold out of 2^24 and the PRNG state
+    #[cfg(feature = "latency_sampling")]
+    latency: LatencySampler, // Recent malloc, free and realloc durations
+    #[cfg(feature = "fragmentation_trend")]
+    fragmentation: FragmentationHistory, // Recent fragmentation samples
 }
 
 impl BuddyPool {
+    /// Create a builder for a memory pool with non-default options.
+    ///
+    /// # Returns
+    /// a new BuddyPoolBuilder with the default options
+    pub fn builder() -> BuddyPoolBuilder {
+        BuddyPoolBuilder::new()
+    }
+
     /// Create a new memory pool using the buddy algorithm. Internally, this function uses memmap2
     /// to get a block of memory to manage so should be portable to any system that implements mmap
     /// as well as Windows systems. This function will round up to the nearest power of two. So if
@@ -70,309 +329,3721 @@ impl BuddyPool {
     /// internal data structures.
     ///
     /// NOTE: Memory pools returned by this function can not be intermingled. Calling buddy_malloc
-    /// with pool A and then calling buddy_free with pool B will result in undefined behavior.
+    /// with pool A and then calling buddy_free with pool B is ignored by pool B, see same_pool.
     ///
     /// # Arguments
     /// * size - The size of the pool in bytes
     pub fn new(size: usize) -> Result<BuddyPool, BuddyError> {
-        let mut kval: usize;
-        if size == 0 {
-            kval = DEFAULT_K;
-        } else {
-            kval = b_to_k(size);
+        let numbytes = 1usize << pool_kval(size);
+        //Memory map a block of raw memory to manage
+        let base = MmapMut::map_anon(numbytes).map_err(|_| {
+            set_errno(ENOMEM);
+            BuddyError::NoMemory
+        })?;
+        BuddyPool::from_mmap(base)
+    }
+
+    /// Create a new memory pool like new, but refuse sizes that new would clamp. The size is still
+    /// rounded up to a power of two, which capacity reports, but a size that rounds to less than
+    /// the minimum pool size or more than the maximum is an error rather than a different pool,
+    /// and 0 does not stand for the default size.
+    ///
+    /// # Arguments
+    /// * size - The size of the pool in bytes
+    ///
+    /// # Returns
+    /// the new memory pool, InvalidSize if the rounded size is outside the supported pool sizes,
+    /// or NoMemory if the memory could not be mapped
+    pub fn new_exact(size: usize) -> Result<BuddyPool, BuddyError> {
+        if !(MIN_K..MAX_K).contains(&b_to_k(size)) {
+            set_errno(EINVAL);
+            return Err(BuddyError::InvalidSize);
+        }
+        BuddyPool::new(size)
+    }
+
+    /// Create a new memory pool backed by a file, so that the blocks it hands out survive the
+    /// process. The file is resized to the pool size, rounded as in new, and mapped shared. As
+    /// with new, the caller must call the init function, which makes the whole file one free
+    /// block. Use reopen_file to attach to the file again later.
+    ///
+    /// # Arguments
+    /// * file - The file to keep the pool in, opened for reading and writing
+    /// * size - The size of the pool in bytes
+    ///
+    /// # Returns
+    /// the new memory pool, or NoMemory if the file could not be resized or mapped
+    #[cfg(feature = "std")]
+    pub fn new_file(file: &File, size: usize) -> Result<BuddyPool, BuddyError> {
+        let numbytes = 1usize << pool_kval(size);
+        if file.set_len(numbytes as u64).is_err() {
+            set_errno(ENOMEM);
+            return Err(BuddyError::NoMemory);
+        }
+        BuddyPool::map_file(file, numbytes)
+    }
+
+    /// Attaches to a pool that new_file left in a file, keeping the blocks allocated in it. The
+    /// blocks in the file link to each other by offset, but the list heads live in the pool struct
+    /// rather than the file, so the caller must call repair instead of init. repair rebuilds the
+    /// lists from the block headers in the file. Pointers from the earlier mapping are stale, and
+    /// must be kept as offsets from the base or as handles and turned back into pointers against
+    /// the new base.
+    ///
+    /// Only pools without out of band metadata or trailing headers can be reopened, as those
+    /// options are not stored in the file.
+    ///
+    /// # Safety
+    /// The file must hold a pool written by new_file with the given kval_m, and nothing else may
+    /// change the file while the pool is mapped, or the block headers can send the pool outside
+    /// its mapping.
+    ///
+    /// # Arguments
+    /// * file - The file holding the pool, opened for reading and writing
+    /// * kval_m - The kval of the pool, whose size is 2^kval_m bytes
+    ///
+    /// # Returns
+    /// the pool, InvalidSize if the file is shorter than the pool or kval_m is not supported,
+    /// CorruptedMemoryPool if the block headers do not cover the pool, or NoMemory if the file
+    /// could not be mapped
+    #[cfg(feature = "std")]
+    pub unsafe fn reopen_file(file: &File, kval_m: usize) -> Result<BuddyPool, BuddyError> {
+        let len = file.metadata().map_or(0, |metadata| metadata.len());
+        if !(MIN_K..MAX_K).contains(&kval_m) || len < 1u64 << kval_m {
+            set_errno(EINVAL);
+            return Err(BuddyError::InvalidSize);
         }
-        if kval < MIN_K {
-            kval = MIN_K;
+        let mut pool = BuddyPool::map_file(file, 1 << kval_m)?;
+        // The pool is not linked yet, but the headers can already be walked through the base
+        let mut end = 0;
+        let mut live = 0;
+        for (offset, kval, tag) in pool.blocks() {
+            end = offset + (1 << kval);
+            live += (tag == BLOCK_RESERVED) as usize;
         }
-        if kval >= MAX_K {
-            kval = MAX_K - 1;
+        if end != pool.capacity() {
+            return Err(BuddyError::CorruptedMemoryPool);
         }
+        pool.live_allocations = live;
+        Ok(pool)
+    }
+
+    /// Maps the first len bytes of a file shared and builds an uninitialized pool over them.
+    ///
+    /// # Arguments
+    /// * file - The file to map
+    /// * len - The length of the mapping, a valid pool size
+    ///
+    /// # Returns
+    /// the pool, or NoMemory if the file could not be mapped
+    #[cfg(feature = "std")]
+    fn map_file(file: &File, len: usize) -> Result<BuddyPool, BuddyError> {
+        // SAFETY: the pool owns the mapping, and reopen_file leaves keeping the file unchanged to
+        // its caller
+        let map = unsafe { MmapOptions::new().len(len).map_mut(file) }.map_err(|_| {
+            set_errno(ENOMEM);
+            BuddyError::NoMemory
+        })?;
+        BuddyPool::from_mmap(map)
+    }
 
-        let kval_m = kval;
+    /// Create a new memory pool like new, whose smallest block is 2^min_k bytes instead of the
+    /// smallest block that can hold a header. Requests are rounded up to blocks of at least that
+    /// size and blocks are never split below it, which keeps the avail lists short for workloads
+    /// whose objects are all about the same size.
+    ///
+    /// # Arguments
+    /// * size - The size of the pool in bytes, as for new
+    /// * min_k - The kval of the smallest block, from the kval of a header up to the pool's kval
+    ///
+    /// # Returns
+    /// the new memory pool, InvalidSize if min_k is out of range, or NoMemory as in new
+    pub fn new_with_limits(size: usize, min_k: usize) -> Result<BuddyPool, BuddyError> {
+        let mut pool = BuddyPool::new(size)?;
+        if !(b_to_k(size_of::<Avail>())..=pool.kval_m).contains(&min_k) {
+            set_errno(EINVAL);
+            return Err(BuddyError::InvalidSize);
+        }
+        pool.min_kval = min_k;
+        Ok(pool)
+    }
 
-        let numbytes = (1u64 << kval) as usize;
-        //Memory map a block of raw memory to manage
-        let base = MmapMut::map_anon(numbytes).or_else(|_| {
+    /// Create a new memory pool that manages a mapping made by the caller, for example a shared
+    /// mapping or one made with flags that new does not use. The pool takes ownership of the
+    /// mapping. Its length must be a power of two between the minimum and maximum pool sizes, and
+    /// it must start on a multiple of the header alignment, which a mapping made at a file offset
+    /// that is not page aligned may not.
+    ///
+    /// As with new, the caller must call the init function before using the pool. Growing the pool
+    /// remaps it, so auto grow should be left off unless the mapping can be
+    /// extended.
+    ///
+    /// # Arguments
+    /// * map - The mapping to manage
+    ///
+    /// # Returns
+    /// the new memory pool, InvalidSize if the length of the mapping is not supported or
+    /// InvalidAlignment if headers at its start would not be aligned
+    pub fn from_mmap(map: MmapMut) -> Result<BuddyPool, BuddyError> {
+        BuddyPool::from_region(Region::Mapped(map))
+    }
+
+    /// Create a new memory pool that manages a region of memory given by the caller, for targets
+    /// without mmap. The region must be a power of two between the minimum and maximum pool sizes
+    /// long, and start on a multiple of the header alignment. The pool can not grow, and it
+    /// leaves the region as it is when dropped.
+    ///
+    /// As with new, the caller must call the init function before using the pool.
+    ///
+    /// # Safety
+    /// The region must be valid for reads and writes for size bytes, and must not be used by
+    /// anything but the pool until the pool is dropped.
+    ///
+    /// # Arguments
+    /// * base - Pointer to the start of the region
+    /// * size - The length of the region in bytes
+    ///
+    /// # Returns
+    /// the new memory pool, InvalidSize if the length of the region is not supported or
+    /// InvalidAlignment if headers at its start would not be aligned
+    pub unsafe fn from_raw(base: *mut u8, size: usize) -> Result<BuddyPool, BuddyError> {
+        BuddyPool::from_region(Region::Raw(base, size))
+    }
+}
+
+impl<const N: usize> BuddyPool<N> {
+    /// Create a new memory pool with N avail list heads, for pools that never need the largest
+    /// sizes. The size is rounded as in new, and the pool can not grow to a kval of N or more.
+    /// The pool is used like one from new, and must be initialized before it is used.
+    ///
+    /// # Arguments
+    /// * size - The size of the pool in bytes
+    ///
+    /// # Returns
+    /// the new memory pool, InvalidSize if the rounded size needs a kval of N or more, or NoMemory
+    /// if the memory could not be mapped
+    pub fn with_max_k(size: usize) -> Result<BuddyPool<N>, BuddyError> {
+        let kval = pool_kval(size);
+        if kval >= N {
+            set_errno(EINVAL);
+            return Err(BuddyError::InvalidSize);
+        }
+        let base = MmapMut::map_anon(1usize << kval).map_err(|_| {
             set_errno(ENOMEM);
-            return Err(BuddyError::NoMemory);
+            BuddyError::NoMemory
         })?;
+        BuddyPool::from_region(Region::Mapped(base))
+    }
+
+    /// Implements from_mmap and from_raw.
+    ///
+    /// # Arguments
+    /// * region - The memory to manage
+    ///
+    /// # Returns
+    /// the new memory pool, or InvalidSize or InvalidAlignment as in from_mmap
+    fn from_region(mut region: Region) -> Result<BuddyPool<N>, BuddyError> {
+        // Every pool size from MIN_K must fit, and the heads must stay clear of UNLINKED
+        const { assert!(N > MIN_K && N <= MAX_K) };
+        let len = region.len();
+        let kval_m = b_to_k(len);
+        if !len.is_power_of_two() || !(MIN_K..N).contains(&kval_m) {
+            set_errno(EINVAL);
+            return Err(BuddyError::InvalidSize);
+        }
+        if !region.as_ptr().addr().is_multiple_of(align_of::<Avail>()) {
+            set_errno(EINVAL);
+            return Err(BuddyError::InvalidAlignment);
+        }
 
         let pool = BuddyPool {
             kval_m,
-            base,
-            avail: array::from_fn::<_, MAX_K, _>(|_| Avail::new()),
+            min_kval: b_to_k(size_of::<Avail>()),
+            base_ptr: region.as_mut_ptr(),
+            base: region,
+            avail: array::from_fn::<_, N, _>(|_| Avail::new()),
+            auto_grow: false,
+            grow_in_place: false,
+            trailing_headers: false,
+            epoch: 0,
+            free_counts: [0; N],
+            held: [[None; HELD_SLOTS]; N],
+            no_coalesce: None,
+            growth_factor: GrowthFactor::Fit,
+            block_aligned: [None; BLOCK_ALIGNED_SLOTS],
+            generation: 0,
+            scratch: None,
+            bump_size: 0,
+            header_bytes: 0,
+            bump: None,
+            capacity_limit: None,
+            reserve_floor: 0,
+            alignment_padding: 0,
+            peak_used: 0,
+            live_allocations: 0,
+            max_single_alloc_kval: 0,
+            max_live_allocations: None,
+            metadata: None,
+            numa_node: None,
+            oom_handler: None,
+            oom_invocations: 0,
+            alloc_failures: 0,
+            oom_successes: 0,
+            observer: None,
+            #[cfg(feature = "chaos")]
+            chaos: None,
+            #[cfg(feature = "latency_sampling")]
+            latency: LatencySampler::new(),
+            #[cfg(feature = "fragmentation_trend")]
+            fragmentation: FragmentationHistory::new(),
         };
         Ok(pool)
     }
 
     /// Initialize the buddy memory pool. This function must be called before any other functions
-    /// for the pool to function. This was not handled in new because the avail array requires
-    /// memory locations to be fixed before initialization.
+    /// for the pool to function. The avail lists link blocks by their offset from the base and
+    /// name the list heads by kval, so the pool may be moved freely once it is initialized.
     pub fn init(&mut self) {
+        // Every block starts a multiple of the smallest block from the base, which is itself a
+        // multiple of the header size, so an aligned base keeps every header aligned
+        debug_assert!(self
+            .base
+            .as_ptr()
+            .addr()
+            .is_multiple_of(align_of::<Avail>()));
         // Initialize the avail list
         for i in 0..=self.kval_m {
-            self.avail[i].next = &mut self.avail[i] as *mut Avail;
-            self.avail[i].prev = &mut self.avail[i] as *mut Avail;
+            self.avail[i].next = head_link(i);
+            self.avail[i].prev = head_link(i);
             self.avail[i].kval = i;
-            self.avail[i].tag = BLOCK_UNUSED;
+            self.avail[i].set_state(BLOCK_UNUSED);
+            self.free_counts[i] = 0;
+        }
+        self.block_aligned = [None; BLOCK_ALIGNED_SLOTS];
+        self.held = [[None; HELD_SLOTS]; N];
+        self.scratch = None;
+        self.bump = None;
+        self.alignment_padding = 0;
+        self.peak_used = 0;
+        self.live_allocations = 0;
+        self.max_single_alloc_kval = 0;
+        self.oom_invocations = 0;
+        self.oom_successes = 0;
+        self.alloc_failures = 0;
+        self.epoch = self.epoch.wrapping_add(1);
+        if let Some(metadata) = self.metadata.as_mut() {
+            metadata.clear();
         }
 
         //Add in the first block
-        let base_ptr = self.base.as_ptr() as *mut Avail;
-        self.avail[self.kval_m].next = base_ptr;
-        self.avail[self.kval_m].prev = base_ptr;
+        self.avail[self.kval_m].next = 0;
+        self.avail[self.kval_m].prev = 0;
 
-        let m = unsafe { &mut *base_ptr };
-        m.tag = BLOCK_AVAIL;
+        let m = unsafe { &mut *(self.base_ptr as *mut Avail) };
+        m.set_state(BLOCK_AVAIL);
         m.kval = self.kval_m;
-        m.next = &mut self.avail[self.kval_m] as *mut Avail;
-        m.prev = &mut self.avail[self.kval_m] as *mut Avail;
+        m.next = head_link(self.kval_m);
+        m.prev = head_link(self.kval_m);
+        self.free_counts[self.kval_m] = 1;
+    }
+
+    /// Frees every allocation at once by putting the pool back in the state init leaves it in,
+    /// a single free block covering the whole pool. This is much cheaper than freeing the blocks
+    /// one by one, as nothing is merged.
+    ///
+    /// Every pointer and handle handed out before the reset is invalid afterwards, and passing
+    /// one to free or realloc corrupts the pool. The statistics are cleared too, while settings
+    /// such as the capacity limit or the OOM handler are kept.
+    pub fn reset(&mut self) {
+        self.init();
     }
 
     /// Allocates a block of size bytes of memory, returning a pointer to the beginning of the
     /// block. The content of the newly allocated block of memory is not initialized, remaining with
     /// indeterminate values.
     ///
+    /// A request that needs the whole pool, because it and its header do not fit in half of it,
+    /// can only be satisfied while nothing else is allocated. If part of the pool is in use such a
+    /// request fails with PoolNotEmpty rather than NoMemory, to tell it apart from the pool simply
+    /// being too small. A request that would leave less free memory than the reserve floor set
+    /// through the builder fails with ReserveFloor, and one that would take the number of live
+    /// allocations past the limit set through the builder fails with LiveAllocationLimit. With the
+    /// errno feature, all of these also set errno to ENOMEM.
+    ///
+    /// If the pool was built with out of band metadata, the block has no header and the pointer is
+    /// the start of the block itself. If it was built with trailing headers, the pointer is also
+    /// the start of the block and the header takes the last bytes of the block instead.
+    ///
+    /// If an OOM handler is installed, a request that fails with NoMemory or PoolNotEmpty calls it
+    /// and is retried once if the handler reports that it freed memory.
+    ///
     /// # Arguments
     /// * size - The size of the user requested memory block in bytes
     ///
     /// # Returns
     /// a pointer to the memory block
     pub fn malloc(&mut self, size: usize) -> Result<*mut u8, BuddyError> {
-        let avail_size = size_of::<Avail>();
-        let kval = b_to_k(size + avail_size);
-        unsafe { Ok((self.malloc_kval(kval)? as *mut u8).offset(avail_size as isize)) }
-    }
-
-    /// A block of memory previously allocated by a call to malloc, realloc is
-    /// deallocated, making it available again for further allocations.
-    ///
-    /// If ptr does not point to a block of memory allocated with the above functions, it causes
-    /// undefined behavior.
-    ///
-    /// If ptr is a null pointer, the function does nothing. Notice that this function does not
-    /// change the value of ptr itself, hence it still points to the same (now invalid) location.
-    ///
-    /// # Arguments
-    /// * ptr - Pointer to the memory block to free
-    pub fn free(&mut self, ptr: *mut u8) {
-        if ptr.is_null() {
-            return;
-        }
-        unsafe {
-            let avail = (ptr.offset(-(size_of::<Avail>() as isize)) as *mut Avail)
-                .as_mut()
-                .unwrap();
-            self.free_avail(avail);
+        let result = self.admit(size, |pool| pool.malloc_above(size, 0, pool.reserve_floor));
+        if let Ok(ptr) = result {
+            self.notify_malloc(ptr, size);
         }
+        result
     }
 
-    /// Changes the size of the memory block pointed to by ptr. The function may move the memory
-    /// block to a new location (whose address is returned by the function). The content of the
-    /// memory block is preserved up to the lesser of the new and old sizes, even if the block is
-    /// moved to a new location. If the new size is larger, the value of the newly allocated portion
-    /// is indeterminate.
-    ///
-    /// In case that ptr is a null pointer, the function behaves like malloc, assigning a new block
-    /// of size bytes and returning a pointer to its beginning.
-    ///
-    /// If size is equal to zero, and ptr is not NULL, then the call is equivalent to free(ptr)
+    /// Runs an allocation through the steps that malloc and its variants share: chaos failures,
+    /// latency sampling, the OOM handler with its single retry, and counting failures. A request
+    /// for the whole pool that fails with PoolNotEmpty is out of memory too, so it is handled the
+    /// same way as NoMemory.
     ///
     /// # Arguments
-    /// ptr - Pointer to a memory block
-    /// size - The new size of the memory block
+    /// * size - The number of bytes requested, as passed to the OOM handler
+    /// * alloc - Makes the allocation, called again if the OOM handler freed memory
     ///
     /// # Returns
-    /// a pointer to the new memory block
-    pub fn realloc(&mut self, ptr: *mut u8, size: usize) -> Result<*mut u8, BuddyError> {
-        if ptr.is_null() {
-            return self.malloc(size);
-        }
-        let target_kval = b_to_k(size + size_of::<Avail>());
-        // case - requested size too large
-        if target_kval > self.kval_m {
+    /// the result of the last call to alloc
+    fn admit(
+        &mut self,
+        size: usize,
+        mut alloc: impl FnMut(&mut Self) -> Result<*mut u8, BuddyError>,
+    ) -> Result<*mut u8, BuddyError> {
+        #[cfg(feature = "chaos")]
+        if self.chaos_fails() {
+            self.alloc_failures += 1;
             set_errno(ENOMEM);
             return Err(BuddyError::NoMemory);
         }
-        // case - current kval fits size
-        let mut old_avail = unsafe {
-            (ptr.offset(-(size_of::<Avail>() as isize)) as *mut Avail)
-                .as_mut()
-                .ok_or(BuddyError::CorruptedMemoryPool)?
+        let out_of_memory = |result: &Result<*mut u8, BuddyError>| {
+            matches!(result, Err(BuddyError::NoMemory | BuddyError::PoolNotEmpty))
         };
-        let old_kval = old_avail.kval;
-        if target_kval == old_kval {
-            return Ok(ptr);
-        }
-        // case - requested size is 0
-        if size == 0 {
-            self.free(ptr);
-            return Ok(ptr);
-        }
-        // case - reduce size
-        while target_kval < old_avail.kval {
-            old_avail = self.split(old_avail);
-        }
-        // case - increase size
-        let mut ptr = ptr;
-        if target_kval > old_avail.kval {
-            unsafe {
-                let new_avail = self.malloc_kval(target_kval)?;
-                let new_block = (new_avail as *mut u8).offset(size_of::<Avail>() as isize);
-                let old_size = (1u64 << old_avail.kval) as usize;
-                new_block.copy_from_nonoverlapping(ptr, old_size);
-                self.free(ptr);
-                ptr = new_block;
+        let result = self.sampled(|pool| {
+            let result = alloc(pool);
+            let Some(handler) = pool.oom_handler.filter(|_| out_of_memory(&result)) else {
+                return result;
+            };
+            pool.oom_invocations += 1;
+            if !handler(pool, size) {
+                return result;
             }
+            let retry = alloc(pool);
+            if retry.is_ok() {
+                pool.oom_successes += 1;
+            }
+            retry
+        });
+        if out_of_memory(&result) {
+            self.alloc_failures += 1;
         }
-        Ok(ptr)
+        result
     }
 
-    /// Adds a block of memory to the avail list and tags it as available.
+    /// Tells the observer, if one is installed, about a block that was handed out.
     ///
     /// # Arguments
-    /// * avail - The block of memory to add to the avail list
-    fn add_to_avail(&mut self, avail: &mut Avail) {
-        let kval = avail.kval;
-        avail.prev = self.avail[kval].prev;
-        avail.next = &mut self.avail[kval];
-        unsafe {
-            (*self.avail[kval].prev).next = avail;
+    /// * ptr - The user pointer of the block
+    /// * size - The size that was requested for it
+    fn notify_malloc(&self, ptr: *mut u8, size: usize) {
+        if let Some(observer) = self.observer {
+            let kval = self.headerless_kval(ptr);
+            let kval = kval.unwrap_or_else(|| unsafe { (*self.header_of(ptr)).kval });
+            observer(AllocEvent::Malloc { ptr, size, kval });
         }
-        self.avail[kval].prev = avail;
-        avail.tag = BLOCK_AVAIL;
     }
 
-    /// Removes a block of memory from the avail list and tags it as reserved.
+    /// Installs a handler that malloc calls when it runs out of memory, for example to evict
+    /// entries from a cache kept in the pool. The handler gets the pool and the requested size,
+    /// and returns whether it freed anything, in which case malloc tries again once.
     ///
     /// # Arguments
-    /// * avail - The block of memory to remove from the avail list
-    fn remove_from_avail(&mut self, avail: &mut Avail) {
-        unsafe {
-            (*avail.next).prev = avail.prev;
-            (*avail.prev).next = avail.next;
-        }
-        avail.tag = BLOCK_RESERVED;
-        avail.next = ptr::null_mut();
-        avail.prev = ptr::null_mut();
+    /// * handler - The function to call when malloc fails with NoMemory or PoolNotEmpty
+    pub fn set_oom_handler(&mut self, handler: fn(&mut BuddyPool<N>, usize) -> bool) {
+        self.oom_handler = Some(handler);
     }
 
-    /// Find the buddy of a given pointer and kval relative to the base address we got from memmap2
+    /// Installs an observer that is told about every successful malloc, free and realloc, for
+    /// profiling or custom accounting. A realloc is reported as a single event, even if it moves
+    /// the block with a malloc and a free, and malloc_contiguous reports each of its blocks. The
+    /// other allocation functions are not reported unless they call malloc, and without an
+    /// observer the cost is a single check per call.
     ///
     /// # Arguments
-    /// * buddy - The memory block that we want to find the buddy for
-    ///
-    /// # Returns
-    /// a pointer to the buddy
-    fn buddy_calc(&self, avail: &Avail) -> *mut Avail {
-        let mut addr = (avail as *const Avail).addr();
-        addr -= self.base.as_ptr().addr();
-        let mask = (1u64 << avail.kval) as usize;
-        unsafe { self.base.as_ptr().offset((addr ^ mask) as isize) as *mut Avail }
+    /// * observer - The function to call after each operation
+    pub fn set_observer(&mut self, observer: fn(AllocEvent)) {
+        self.observer = Some(observer);
     }
 
-    /// Splits a block of memory into two smaller blocks. This function will return a pointer to the
-    /// block with the lowest address, the other block will be added to the Avail list. The returned
-    /// block will be tagged as reserved and not added to the avail list.
+    /// Makes malloc fail with NoMemory at random, for testing how callers cope with running out of
+    /// memory. The failures come from a PRNG seeded with seed, so a run can be reproduced exactly.
+    /// A rate of 0 turns failure injection off again.
     ///
     /// # Arguments
-    /// * avail - The block of memory to split
+    /// * rate - The probability that a malloc fails, from 0 to 1
+    /// * seed - The seed of the PRNG
+    #[cfg(feature = "chaos")]
+    pub fn set_chaos_failure_rate(&mut self, rate: f32, seed: u64) {
+        let threshold = (rate.clamp(0.0, 1.0) * (1 << 24) as f32) as u32;
+        self.chaos = (threshold > 0).then_some((threshold, seed));
+    }
+
+    /// Draws the next number from the chaos PRNG and decides whether the allocation must fail.
     ///
     /// # Returns
-    /// a pointer to the block with the lowest address after the split
-    fn split<'a>(&mut self, avail: &'a mut Avail) -> &'a mut Avail {
-        let kval = avail.kval;
-        avail.kval -= 1;
-        avail.tag = BLOCK_RESERVED;
-        let buddy = self.buddy_calc(avail);
-        unsafe {
-            ptr::write(buddy, Avail::new());
-            let buddy = &mut *buddy;
-            buddy.kval = kval - 1;
-            buddy.tag = BLOCK_AVAIL;
-            self.add_to_avail(buddy);
-        }
-        avail
+    /// true if failure injection is on and this allocation must fail
+    #[cfg(feature = "chaos")]
+    fn chaos_fails(&mut self) -> bool {
+        let Some((threshold, state)) = self.chaos.as_mut() else {
+            return false;
+        };
+        // splitmix64: a Weyl sequence scrambled by mix
+        *state = state.wrapping_add(0x9e3779b97f4a7c15);
+        ((mix(*state) >> 40) as u32) < *threshold
     }
 
-    /// Gets the buddy of a block of memory. This function will return None if the buddy is not
-    /// tagged as available. This is most useful in coalescing blocks during a free operation.
+    /// Returns how often malloc called the OOM handler since init, and how many of those calls
+    /// were followed by a successful retry. A high call count shows memory pressure that the
+    /// handler is hiding.
     ///
-    /// # Arguments
-    /// * avail - The block of memory to get the buddy for
+    /// # Returns
+    /// the number of handler calls and the number of successful retries
+    pub fn oom_stats(&self) -> (u64, u64) {
+        (self.oom_invocations, self.oom_successes)
+    }
+
+    /// Returns the most bytes that have been reserved at once, an alias of peak_used_bytes kept
+    /// next to failure_count for the cumulative metrics that reset_metrics clears.
     ///
     /// # Returns
-    /// a reference to the buddy block if it is available, otherwise None
-    fn get_avail_buddy(&self, avail: &Avail) -> Option<&mut Avail> {
-        if avail.kval == self.kval_m {
-            return None;
-        }
-        let buddy = unsafe { self.buddy_calc(avail).as_mut().unwrap() };
-        if buddy.tag != BLOCK_AVAIL {
-            return None;
-        }
-        if buddy.kval != avail.kval {
-            return None;
-        }
-        Some(buddy)
+    /// the peak usage in bytes
+    pub fn peak_usage(&self) -> usize {
+        self.peak_used_bytes()
     }
 
-    /// Allocates a block of memory of size 2^k bytes, returning a pointer to the Avail struct at
-    /// the start of the block. This is in contrast to the malloc function which returns a pointer
-    /// to the start of usable user memory.
+    /// Returns how many times malloc, calloc or realloc failed with NoMemory, or with PoolNotEmpty
+    /// for a request that needs the whole pool, since init or the last reset_metrics. A realloc
+    /// whose inner malloc fails is counted once.
+    ///
+    /// # Returns
+    /// the number of failed allocations
+    pub fn failure_count(&self) -> u64 {
+        self.alloc_failures
+    }
+
+    /// Starts the cumulative metrics over without touching the pool's blocks. The failure count
+    /// and the peak usage are zeroed, and the peak picks up the memory still reserved with the
+    /// next allocation.
+    pub fn reset_metrics(&mut self) {
+        self.alloc_failures = 0;
+        self.peak_used = 0;
+    }
+
+    /// Allocates a zeroed block for an array of count elements of size bytes each, like calloc in
+    /// C. The whole usable region of the block is zeroed, but not its header.
     ///
     /// # Arguments
-    /// * kval - The size of the requested block in K values
+    /// * count - The number of elements
+    /// * size - The size of each element in bytes
     ///
     /// # Returns
-    /// a pointer to the Avail struct at the start of the block
-    unsafe fn malloc_kval(&mut self, kval: usize) -> Result<*mut Avail, BuddyError> {
-        if kval > self.kval_m {
+    /// a pointer to the zeroed memory block, or NoMemory if count * size overflows
+    pub fn calloc(&mut self, count: usize, size: usize) -> Result<*mut u8, BuddyError> {
+        let Some(total) = count.checked_mul(size) else {
+            self.alloc_failures += 1;
             set_errno(ENOMEM);
             return Err(BuddyError::NoMemory);
-        }
-        if self.avail[kval].next as *const Avail != &self.avail[kval] {
-            let block = self.avail[kval].next;
-            self.remove_from_avail(&mut *block);
-            return Ok(block);
-        }
-        //No blocks available at this kval, try to split a larger block
-        let larger_block = self.malloc_kval(kval + 1)?;
-        Ok(self.split(&mut *larger_block))
-    }
+        };
+        let ptr = self.malloc(total)?;
+        unsafe { ptr.write_bytes(0, self.usable_size_aligned(ptr)) };
+        Ok(ptr)
+    }
+
+    /// Allocates a block like malloc but ignores the reserve floor, so that it can use the
+    /// emergency headroom that malloc leaves free. Everything else, from the OOM handler to the
+    /// observer, works as for malloc.
+    ///
+    /// # Arguments
+    /// * size - The size of the user requested memory block in bytes
+    ///
+    /// # Returns
+    /// a pointer to the memory block
+    pub fn malloc_emergency(&mut self, size: usize) -> Result<*mut u8, BuddyError> {
+        let result = self.admit(size, |pool| pool.malloc_above(size, 0, 0));
+        if let Ok(ptr) = result {
+            self.notify_malloc(ptr, size);
+        }
+        result
+    }
+
+    /// Allocates a block like malloc, but at least 2^min_kval bytes large whatever the size. The
+    /// extra room is headroom for the block to grow into, as realloc does not move a block that
+    /// grows within its current size.
+    ///
+    /// # Arguments
+    /// * size - The size of the user requested memory block in bytes
+    /// * min_kval - The kval of the smallest block to hand out
+    ///
+    /// # Returns
+    /// a pointer to the memory block
+    pub fn malloc_min_kval(&mut self, size: usize, min_kval: usize) -> Result<*mut u8, BuddyError> {
+        let result = self.admit(size, |pool| {
+            pool.malloc_above(size, min_kval, pool.reserve_floor)
+        });
+        if let Ok(ptr) = result {
+            self.notify_malloc(ptr, size);
+        }
+        result
+    }
+
+    /// Allocates a block like malloc, but large enough for a buffer that is expected to grow to
+    /// expected_max bytes. The block records size as the bytes in use while usable_size reports
+    /// the whole block, and realloc keeps the block as is for any size up to expected_max instead
+    /// of splitting it on a shrink, so the buffer can grow to its hint without moving.
+    ///
+    /// # Arguments
+    /// * size - The size of the user requested memory block in bytes
+    /// * expected_max - The size the block is expected to grow to
+    ///
+    /// # Returns
+    /// a pointer to the memory block
+    pub fn malloc_with_hint(
+        &mut self,
+        size: usize,
+        expected_max: usize,
+    ) -> Result<*mut u8, BuddyError> {
+        let Some(min_kval) = self.request_kval(size.max(expected_max)) else {
+            self.alloc_failures += 1;
+            set_errno(ENOMEM);
+            return Err(BuddyError::NoMemory);
+        };
+        let ptr = self.malloc_min_kval(size, min_kval)?;
+        if self.headerless_kval(ptr).is_none() {
+            unsafe { (*self.header_of(ptr)).set_flag(TAG_HINTED, true) };
+        }
+        Ok(ptr)
+    }
+
+    /// Returns the kval of the block malloc uses for a request.
+    ///
+    /// # Arguments
+    /// * size - The size of the user requested memory block in bytes
+    ///
+    /// # Returns
+    /// the kval of the block, or None if size and its header overflow a usize
+    fn request_kval(&self, size: usize) -> Option<usize> {
+        match self.metadata {
+            // The block must still be able to hold a header once it is freed
+            Some(_) => Some(b_to_k(size.max(size_of::<Avail>())).max(self.min_kval)),
+            None => size
+                .checked_add(size_of::<Avail>())
+                .map(|bytes| b_to_k(bytes).max(self.min_kval)),
+        }
+    }
+
+    /// Implements malloc with a given minimum block size and reserve floor.
+    ///
+    /// # Arguments
+    /// * size - The size of the user requested memory block in bytes
+    /// * min_kval - The kval of the smallest block to hand out, 0 for no minimum
+    /// * floor - The free memory that must be left after the allocation
+    ///
+    /// # Returns
+    /// a pointer to the memory block
+    fn malloc_above(
+        &mut self,
+        size: usize,
+        min_kval: usize,
+        floor: usize,
+    ) -> Result<*mut u8, BuddyError> {
+        let avail_size = size_of::<Avail>();
+        let Some(kval) = self.request_kval(size) else {
+            set_errno(ENOMEM);
+            return Err(BuddyError::NoMemory);
+        };
+        let kval = kval.max(min_kval);
+        if kval == self.kval_m && self.free_counts[kval] == 0 {
+            set_errno(ENOMEM);
+            return Err(BuddyError::PoolNotEmpty);
+        }
+        if kval <= self.kval_m && self.free_bytes().saturating_sub(1 << kval) < floor {
+            set_errno(ENOMEM);
+            return Err(BuddyError::ReserveFloor);
+        }
+        unsafe {
+            let block = self.malloc_kval(kval)?;
+            let offset = self.offset_of(block as *mut u8);
+            if let Some(metadata) = self.metadata.as_mut() {
+                let entry = Metadata {
+                    kval: kval as u8,
+                    generation: (*block).generation,
+                };
+                metadata.set(offset, Some(entry));
+                return Ok(block as *mut u8);
+            }
+            if self.trailing_headers {
+                let generation = (*block).generation;
+                let trailer = (block as *mut u8).add((1 << kval) - avail_size) as *mut Avail;
+                ptr::write(trailer, Avail::new());
+                (*trailer).set_state(BLOCK_RESERVED);
+                (*trailer).set_flag(TAG_TRAILING, true);
+                (*trailer).kval = kval;
+                (*trailer).generation = generation;
+                (*trailer).req_size = u32::try_from(size).unwrap_or(u32::MAX);
+                // The offset and epoch tell the trailer apart from user data that looks like one
+                (*trailer).next = offset;
+                (*trailer).prev = self.epoch;
+                return Ok(block as *mut u8);
+            }
+            let ptr = (block as *mut u8).add(avail_size);
+            self.set_req_size(ptr, size);
+            Ok(ptr)
+        }
+    }
+
+    /// Allocates the smallest free block that fits size bytes without splitting it. This trades
+    /// internal fragmentation for predictable latency, since the whole block is handed out even if
+    /// it is much larger than the request. The call fails if no free block is large enough.
+    ///
+    /// Apart from the block it picks, the call behaves like malloc: the reserve floor, limits,
+    /// chaos failures, OOM handler, failure count and observer all apply, and the block is laid out
+    /// for out of band metadata or trailing headers if the pool uses them.
+    ///
+    /// # Arguments
+    /// * size - The size of the user requested memory block in bytes
+    ///
+    /// # Returns
+    /// a pointer to the memory block
+    pub fn malloc_no_split(&mut self, size: usize) -> Result<*mut u8, BuddyError> {
+        let result = self.admit(size, |pool| {
+            let Some(kval) = pool.request_kval(size) else {
+                set_errno(ENOMEM);
+                return Err(BuddyError::NoMemory);
+            };
+            // A block of the smallest free kval that fits is taken whole, without a split
+            match (kval..=pool.kval_m).find(|&k| pool.free_counts[k] > 0) {
+                Some(k) => pool.malloc_above(size, k, pool.reserve_floor),
+                None => {
+                    set_errno(ENOMEM);
+                    Err(BuddyError::NoMemory)
+                }
+            }
+        });
+        if let Ok(ptr) = result {
+            self.notify_malloc(ptr, size);
+        }
+        result
+    }
+
+    /// A block of memory previously allocated by a call to malloc, realloc is
+    /// deallocated, making it available again for further allocations.
+    ///
+    /// If ptr does not point to a block of memory allocated with the above functions, it causes
+    /// undefined behavior.
+    ///
+    /// If ptr is a null pointer, the function does nothing. Notice that this function does not
+    /// change the value of ptr itself, hence it still points to the same (now invalid) location.
+    ///
+    /// If ptr does not point into this pool, for example because it was allocated from another
+    /// pool, the function does nothing rather than corrupting this pool. A block that has already
+    /// been freed is left alone in the same way, see try_free.
+    ///
+    /// # Arguments
+    /// * ptr - Pointer to the memory block to free
+    pub fn free(&mut self, ptr: *mut u8) {
+        // The errors only tell the caller that the pointer was ignored
+        let _ = self.try_free(ptr);
+    }
+
+    /// Frees a block like free, but reports a pointer that free would ignore. A block is known to
+    /// be freed already when its header is on an avail list, or was left unused when the block
+    /// merged into the free buddy below it. This catches a double free as long as the memory has
+    /// not been handed out again in the meantime.
+    ///
+    /// # Arguments
+    /// * ptr - Pointer to the memory block to free
+    ///
+    /// # Returns
+    /// Ok if the block was freed or ptr is null, or CorruptedMemoryPool if ptr does not point into
+    /// this pool or its block has already been freed
+    pub fn try_free(&mut self, ptr: *mut u8) -> Result<(), BuddyError> {
+        if ptr.is_null() || ptr == ZERO_SIZE_PTR {
+            return Ok(());
+        }
+        if !self.contains(ptr) {
+            return Err(BuddyError::CorruptedMemoryPool);
+        }
+        self.sampled(|pool| unsafe {
+            let avail = match pool.take_headerless(ptr) {
+                Some(avail) => &mut *avail,
+                None => match pool.checked_header_of(ptr) {
+                    Some(avail) => &mut *avail,
+                    None => return Err(BuddyError::CorruptedMemoryPool),
+                },
+            };
+            // Already freed, relinking it would corrupt the avail lists
+            if matches!(avail.state(), BLOCK_AVAIL | BLOCK_UNUSED) {
+                return Err(BuddyError::CorruptedMemoryPool);
+            }
+            #[cfg(feature = "uaf_detect")]
+            {
+                if avail.marker == FREED_MARKER {
+                    return Err(BuddyError::CorruptedMemoryPool);
+                }
+                pool.mark_freed(avail);
+            }
+            if avail.has_flag(TAG_ALIGNED) {
+                let offset = ptr.addr() - (avail as *mut Avail).addr();
+                pool.alignment_padding -= offset - size_of::<Avail>();
+            }
+            pool.live_allocations -= 1;
+            // If the block merges into its lower buddy this header is left behind as unused
+            avail.set_state(BLOCK_UNUSED);
+            let kval = avail.kval;
+            pool.free_avail(avail);
+            if let Some(observer) = pool.observer {
+                observer(AllocEvent::Free { ptr, kval });
+            }
+            Ok(())
+        })
+    }
+
+    /// Frees every pointer in ptrs as free does, skipping nulls. A pointer that appears twice is
+    /// only freed once, as the second free finds the block already on an avail list or merged
+    /// away. The blocks are freed in the order given; each free coalesces as far as it can, so the
+    /// pool ends up the same whatever the order.
+    ///
+    /// # Arguments
+    /// * ptrs - Pointers to the memory blocks to free
+    pub fn free_many(&mut self, ptrs: &[*mut u8]) {
+        for &ptr in ptrs {
+            self.free(ptr);
+        }
+    }
+
+    /// Allocates a block of size bytes whose address is a multiple of align. The block is made
+    /// large enough to carry the alignment slack, and if the pointer has to be moved past the
+    /// natural user pointer, a marker is written directly in front of it holding the offset back to
+    /// the block's header. This keeps the pointer compatible with free and realloc.
+    ///
+    /// # Arguments
+    /// * size - The size of the user requested memory block in bytes
+    /// * align - The required alignment in bytes, which must be a power of two
+    ///
+    /// # Returns
+    /// a pointer to the aligned memory block
+    pub fn malloc_aligned(&mut self, size: usize, align: usize) -> Result<*mut u8, BuddyError> {
+        if !align.is_power_of_two() {
+            set_errno(EINVAL);
+            return Err(BuddyError::InvalidAlignment);
+        }
+        let avail_size = size_of::<Avail>();
+        if align <= avail_size && self.base_ptr.addr().is_multiple_of(align) {
+            // Blocks start on multiples of the header size from the base, so with an aligned base
+            // the natural pointer is aligned
+            return self.malloc(size);
+        }
+        let kval = b_to_k(size.saturating_add(align + 2 * avail_size)).max(self.min_kval);
+        unsafe {
+            let block = self.malloc_kval(kval)? as *mut u8;
+            let natural = block.add(avail_size);
+            if natural.addr().is_multiple_of(align) {
+                self.set_req_size(natural, size);
+                return Ok(natural);
+            }
+            let marked = block.add(2 * avail_size);
+            let aligned = marked.add((align - marked.addr() % align) % align);
+            let marker = aligned.sub(avail_size) as *mut Avail;
+            ptr::write(marker, Avail::new());
+            (*marker).set_state(BLOCK_ALIGNED);
+            (*marker).kval = aligned.offset_from(block) as usize;
+            (*(block as *mut Avail)).set_flag(TAG_ALIGNED, true);
+            self.alignment_padding += aligned.offset_from(natural) as usize;
+            self.set_req_size(aligned, size);
+            Ok(aligned)
+        }
+    }
+
+    /// Allocates room for one value of type T, aligned for T through malloc_aligned. The memory is
+    /// not initialized. A zero sized T takes no memory and gets a dangling pointer, which
+    /// dealloc_one accepts.
+    ///
+    /// # Returns
+    /// a pointer to the uninitialized value
+    pub fn alloc_one<T>(&mut self) -> Result<NonNull<T>, BuddyError> {
+        if size_of::<T>() == 0 {
+            return Ok(NonNull::dangling());
+        }
+        let ptr = self.malloc_aligned(size_of::<T>(), align_of::<T>())?;
+        Ok(unsafe { NonNull::new_unchecked(ptr as *mut T) })
+    }
+
+    /// Frees the memory of a value allocated with alloc_one. The value is not dropped.
+    ///
+    /// # Safety
+    /// ptr must come from alloc_one on this pool with the same T and must not have been freed
+    /// already, and it must not be used afterwards.
+    ///
+    /// # Arguments
+    /// * ptr - Pointer to the value
+    pub unsafe fn dealloc_one<T>(&mut self, ptr: NonNull<T>) {
+        if size_of::<T>() != 0 {
+            self.free(ptr.as_ptr() as *mut u8);
+        }
+    }
+
+    /// Allocates room for an array of len values of type T, aligned for T through malloc_aligned.
+    /// The memory is not initialized. An array that takes no memory, because len is 0 or T is zero
+    /// sized, gets a dangling pointer, which dealloc_array accepts.
+    ///
+    /// # Arguments
+    /// * len - The number of elements
+    ///
+    /// # Returns
+    /// a pointer to the uninitialized array, or NoMemory if its size in bytes overflows
+    pub fn alloc_array<T>(&mut self, len: usize) -> Result<NonNull<[T]>, BuddyError> {
+        let Some(size) = len.checked_mul(size_of::<T>()) else {
+            set_errno(ENOMEM);
+            return Err(BuddyError::NoMemory);
+        };
+        if size == 0 {
+            return Ok(NonNull::slice_from_raw_parts(NonNull::dangling(), len));
+        }
+        let ptr = self.malloc_aligned(size, align_of::<T>())?;
+        Ok(NonNull::slice_from_raw_parts(
+            unsafe { NonNull::new_unchecked(ptr as *mut T) },
+            len,
+        ))
+    }
+
+    /// Frees the memory of an array allocated with alloc_array. The elements are not dropped.
+    ///
+    /// # Safety
+    /// ptr must come from alloc_array on this pool with the same T and length and must not have
+    /// been freed already, and it must not be used afterwards.
+    ///
+    /// # Arguments
+    /// * ptr - Pointer to the array
+    pub unsafe fn dealloc_array<T>(&mut self, ptr: NonNull<[T]>) {
+        if !ptr.is_empty() && size_of::<T>() != 0 {
+            self.free(ptr.as_ptr() as *mut u8);
+        }
+    }
+
+    /// Allocates a block whose address is the block itself rather than the memory after its header,
+    /// so the pointer is a multiple of the block size from the pool base. This suits hardware that
+    /// indexes memory by block. The header that would normally sit in front of the memory is kept
+    /// in a small side table keyed by the block's offset instead, which limits how many of these
+    /// blocks can be live at once. The pointer can be passed to free and realloc as usual.
+    ///
+    /// # Arguments
+    /// * size - The size of the user requested memory block in bytes
+    ///
+    /// # Returns
+    /// a pointer to the start of the block, or NoMemory if the side table is full
+    pub fn malloc_block_aligned(&mut self, size: usize) -> Result<*mut u8, BuddyError> {
+        let Some(slot) = self.block_aligned.iter().position(Option::is_none) else {
+            set_errno(ENOMEM);
+            return Err(BuddyError::NoMemory);
+        };
+        // The block must still be able to hold a header once it is freed
+        let kval = b_to_k(size).max(self.min_kval);
+        self.sampled(|pool| unsafe {
+            let block = pool.malloc_kval(kval)? as *mut u8;
+            let offset = block.offset_from(pool.base.as_ptr()) as usize;
+            pool.block_aligned[slot] = Some((offset, kval));
+            Ok(block)
+        })
+    }
+
+    /// Allocates count blocks that follow each other in memory, for hardware such as DMA rings that
+    /// needs several buffers at contiguous addresses. One block large enough for all of them is
+    /// allocated and carved into reserved blocks of the size malloc would pick for size bytes, and
+    /// any of it left over past the last block goes back to the avail lists. Each block is its own
+    /// allocation and is freed separately.
+    ///
+    /// The blocks are admitted like a malloc of all of them at once: the reserve floor, chaos
+    /// failures, the OOM handler and the failure count apply, and the observer is told about each
+    /// block.
+    ///
+    /// # Arguments
+    /// * size - The size of each user requested memory block in bytes
+    /// * count - The number of blocks, which must be at least 1
+    ///
+    /// # Returns
+    /// a pointer to the first memory block, block i starts i times the block size after it, or
+    /// NoMemory if the blocks together do not fit in the pool
+    pub fn malloc_contiguous(&mut self, size: usize, count: usize) -> Result<*mut u8, BuddyError> {
+        if count == 0 {
+            set_errno(EINVAL);
+            return Err(BuddyError::InvalidSize);
+        }
+        if self
+            .max_live_allocations
+            .is_some_and(|max| self.live_allocations.saturating_add(count) > max)
+        {
+            set_errno(ENOMEM);
+            return Err(BuddyError::LiveAllocationLimit);
+        }
+        let avail_size = size_of::<Avail>();
+        let kval = b_to_k(size.saturating_add(avail_size)).max(self.min_kval);
+        let Some(total_kval) = count
+            .checked_next_power_of_two()
+            .map(|blocks| kval + blocks.trailing_zeros() as usize)
+            .filter(|&total_kval| total_kval <= self.kval_m)
+        else {
+            self.alloc_failures += 1;
+            set_errno(ENOMEM);
+            return Err(BuddyError::NoMemory);
+        };
+        // The blocks fit in the pool, so their total size can not overflow
+        let total = count << kval;
+        let result = self.admit(total, |pool| unsafe {
+            if pool.free_bytes().saturating_sub(total) < pool.reserve_floor {
+                set_errno(ENOMEM);
+                return Err(BuddyError::ReserveFloor);
+            }
+            let max_single_alloc_kval = pool.max_single_alloc_kval;
+            let mut block = &mut *pool.malloc_kval(total_kval)?;
+            while block.kval > kval {
+                block = pool.split(block);
+            }
+            pool.max_single_alloc_kval = max_single_alloc_kval.max(kval);
+            let start = pool.offset_of(block as *mut Avail as *mut u8);
+            let ptr = pool.ptr_at(start + avail_size);
+            pool.set_req_size(ptr, size);
+            // Everything past the blocks carved so far is still free, so each block starts a free
+            // block that only has to be split down
+            for i in 1..count {
+                let mut block = &mut *(pool.ptr_at(start + (i << kval)) as *mut Avail);
+                pool.remove_from_avail(block);
+                while block.kval > kval {
+                    block = pool.split(block);
+                }
+                pool.stamp_generation(block);
+                pool.live_allocations += 1;
+                pool.set_req_size(pool.ptr_at(start + (i << kval) + avail_size), size);
+            }
+            pool.update_peak();
+            Ok(ptr)
+        });
+        if let Ok(ptr) = result {
+            for i in 0..count {
+                self.notify_malloc(unsafe { ptr.add(i << kval) }, size);
+            }
+        }
+        result
+    }
+
+    /// Returns the number of bytes usable in the block of ptr, the block size minus its header,
+    /// like glibc's malloc_usable_size. The block may be written up to this size without calling
+    /// realloc. For pointers from malloc_aligned, see usable_size_aligned instead.
+    ///
+    /// # Arguments
+    /// * ptr - Pointer to a memory block
+    ///
+    /// # Returns
+    /// the number of usable bytes in the block, 0 for a null pointer
+    pub fn usable_size(&self, ptr: *mut u8) -> usize {
+        if ptr.is_null() {
+            return 0;
+        }
+        if let Some(usable) = self.headerless_usable_size(ptr) {
+            return usable;
+        }
+        let kval = unsafe { (*self.header_of(ptr)).kval };
+        (1usize << kval) - size_of::<Avail>()
+    }
+
+    /// Returns the number of bytes the caller may use from ptr to the end of its block. Unlike
+    /// usable_size, this accounts for the padding in front of pointers returned by
+    /// malloc_aligned.
+    ///
+    /// # Arguments
+    /// * ptr - Pointer to a memory block
+    ///
+    /// # Returns
+    /// the number of usable bytes from ptr to the end of the block, 0 for a null pointer
+    pub fn usable_size_aligned(&self, ptr: *mut u8) -> usize {
+        if ptr.is_null() {
+            return 0;
+        }
+        if let Some(usable) = self.headerless_usable_size(ptr) {
+            return usable;
+        }
+        unsafe {
+            let avail = self.header_of(ptr);
+            let end = (avail as *mut u8).add(1usize << (*avail).kval);
+            end.offset_from(ptr) as usize
+        }
+    }
+
+    /// Changes the size of the memory block pointed to by ptr. The function may move the memory
+    /// block to a new location (whose address is returned by the function). The content of the
+    /// memory block is preserved up to the lesser of the new and old sizes, even if the block is
+    /// moved to a new location. If the new size is larger, the value of the newly allocated portion
+    /// is indeterminate.
+    ///
+    /// In case that ptr is a null pointer, the function behaves like malloc, assigning a new block
+    /// of size bytes and returning a pointer to its beginning. A pointer that does not point into
+    /// this pool is rejected with CorruptedMemoryPool.
+    ///
+    /// If size is equal to zero, and ptr is not NULL, then the call is equivalent to free(ptr) and
+    /// returns ZERO_SIZE_PTR rather than the freed pointer, so that the result can not be used to
+    /// reach freed memory by accident.
+    ///
+    /// If auto grow has been enabled with set_auto_grow and the pool can not hold the larger block,
+    /// the pool is grown to make room for it. This may move the whole pool, in which case every
+    /// other outstanding pointer into the pool is invalidated. Only the returned pointer is rebased.
+    ///
+    /// # Arguments
+    /// ptr - Pointer to a memory block
+    /// size - The new size of the memory block
+    ///
+    /// # Returns
+    /// a pointer to the new memory block
+    pub fn realloc(&mut self, ptr: *mut u8, size: usize) -> Result<*mut u8, BuddyError> {
+        self.realloc_copy(ptr, size, usize::MAX)
+    }
+
+    /// Changes the size of the memory block pointed to by ptr like realloc, but zeroes the bytes
+    /// the block gains instead of leaving them indeterminate. Everything from the old usable size
+    /// to the new usable size is zeroed, whether the block grew in place or moved, and a block
+    /// that still fits in its current size gains nothing to zero. A null pointer gets a new block
+    /// that is zeroed whole, like calloc.
+    ///
+    /// # Arguments
+    /// ptr - Pointer to a memory block
+    /// size - The new size of the memory block
+    ///
+    /// # Returns
+    /// a pointer to the new memory block
+    pub fn realloc_zeroed(&mut self, ptr: *mut u8, size: usize) -> Result<*mut u8, BuddyError> {
+        // Null and ZERO_SIZE_PTR allocate a new block, which is zeroed whole
+        let old_usable = if self.contains(ptr) {
+            self.usable_size_aligned(ptr)
+        } else {
+            0
+        };
+        let new_ptr = self.realloc(ptr, size)?;
+        if size == 0 {
+            return Ok(new_ptr);
+        }
+        let new_usable = self.usable_size_aligned(new_ptr);
+        if new_usable > old_usable {
+            unsafe {
+                new_ptr
+                    .add(old_usable)
+                    .write_bytes(0, new_usable - old_usable)
+            };
+        }
+        Ok(new_ptr)
+    }
+
+    /// Shrinks a block to the smallest block that holds new_size bytes, without moving it. The
+    /// block is split until it reaches that size, keeping the lower half each time and returning
+    /// the upper half to its avail list, so ptr stays valid and its contents up to new_size are
+    /// kept. Blocks from malloc_aligned and blocks without a header keep their size, as their
+    /// pointer is not at the start of the lower half.
+    ///
+    /// # Arguments
+    /// * ptr - Pointer to a memory block
+    /// * new_size - The number of bytes the block must still hold
+    ///
+    /// # Returns
+    /// Ok, InvalidSize if new_size is larger than the block's usable size, or CorruptedMemoryPool
+    /// if ptr is not an allocated block of this pool
+    pub fn shrink(&mut self, ptr: *mut u8, new_size: usize) -> Result<(), BuddyError> {
+        if !self.contains(ptr) {
+            return Err(BuddyError::CorruptedMemoryPool);
+        }
+        let header = match self.headerless_kval(ptr) {
+            Some(_) => None,
+            None => match unsafe { self.checked_header_of(ptr) } {
+                Some(avail) if unsafe { (*avail).state() } == BLOCK_RESERVED => Some(avail),
+                _ => return Err(BuddyError::CorruptedMemoryPool),
+            },
+        };
+        if new_size > self.usable_size_aligned(ptr) {
+            set_errno(EINVAL);
+            return Err(BuddyError::InvalidSize);
+        }
+        let Some(header) = header else {
+            return Ok(());
+        };
+        unsafe {
+            let mut avail = &mut *header;
+            if !avail.has_flag(TAG_ALIGNED) {
+                // A smaller size than the block's usable size can not overflow with its header
+                let target_kval = self.request_kval(new_size).unwrap();
+                while target_kval < avail.kval {
+                    avail = self.split(avail);
+                }
+            }
+            self.set_req_size(ptr, new_size);
+        }
+        Ok(())
+    }
+
+    /// Changes the size of the memory block pointed to by ptr like realloc, but if the block has to
+    /// move only the first copy_len bytes are copied to the new location. This speeds up growing
+    /// buffers where only a prefix is meaningful. The copy is clamped to the old usable size and
+    /// the rest of the new block is indeterminate.
+    ///
+    /// # Arguments
+    /// ptr - Pointer to a memory block
+    /// size - The new size of the memory block
+    /// copy_len - The number of bytes to preserve if the block moves
+    ///
+    /// # Returns
+    /// a pointer to the new memory block
+    pub fn realloc_copy(
+        &mut self,
+        ptr: *mut u8,
+        size: usize,
+        copy_len: usize,
+    ) -> Result<*mut u8, BuddyError> {
+        let failures = self.alloc_failures;
+        let observer = self.observer.take();
+        // The malloc and free of a moving realloc are not reported on their own
+        let result = self.sampled(|pool| pool.realloc_inner(ptr, size, copy_len));
+        self.observer = observer;
+        // A failed malloc inside realloc has counted the failure already
+        if result == Err(BuddyError::NoMemory) && self.alloc_failures == failures {
+            self.alloc_failures += 1;
+        }
+        if let (Some(observer), &Ok(new_ptr)) = (observer, &result) {
+            observer(AllocEvent::Realloc {
+                old_ptr: ptr,
+                new_ptr,
+                new_size: size,
+            });
+        }
+        result
+    }
+
+    /// Changes the size of the memory block pointed to by ptr like realloc, and also reports
+    /// whether the block moved. If it did, the old pointer is returned as well so that callers
+    /// that keep aliases to the block can find and clear them, as the old block has been freed.
+    ///
+    /// # Arguments
+    /// ptr - Pointer to a memory block
+    /// size - The new size of the memory block
+    ///
+    /// # Returns
+    /// the new pointer, whether the block moved and the freed old pointer if it did
+    pub fn realloc_detailed(
+        &mut self,
+        ptr: *mut u8,
+        size: usize,
+    ) -> Result<ReallocResult, BuddyError> {
+        let new_ptr = self.realloc(ptr, size)?;
+        let moved = !ptr.is_null() && size != 0 && new_ptr != ptr;
+        Ok(ReallocResult {
+            new_ptr,
+            moved,
+            old_ptr: moved.then_some(ptr),
+        })
+    }
+
+    /// Estimates the work a realloc of ptr to size would do without doing it, so that real-time
+    /// code can decide whether to realloc now or later. A shrink costs one operation per split, and
+    /// so does growing in place into the free buddies above the block. Any other grow, or a resize
+    /// of an aligned block that no longer fits, moves the block and costs the splits needed to
+    /// allocate the new block plus one for the copy. Freeing with a size of 0 costs one operation.
+    ///
+    /// # Arguments
+    /// ptr - Pointer to a memory block
+    /// size - The new size of the memory block
+    ///
+    /// # Returns
+    /// the number of operations and whether the block would move, or None if ptr is not from this
+    /// pool or the realloc would fail without growing the pool
+    pub fn realloc_cost(&self, ptr: *mut u8, size: usize) -> Option<(usize, bool)> {
+        if ptr.is_null() || !self.contains(ptr) {
+            return None;
+        }
+        if size == 0 {
+            return Some((1, false));
+        }
+        let avail_size = size_of::<Avail>();
+        let target_kval = b_to_k(size.saturating_add(avail_size)).max(self.min_kval);
+        let header = self
+            .headerless_kval(ptr)
+            .is_none()
+            .then(|| unsafe { self.header_of(ptr) })
+            .filter(|&header| unsafe { !(*header).has_flag(TAG_ALIGNED) });
+        match header {
+            // case - plain block, which is split in place when shrinking
+            Some(header) => {
+                let old_kval = unsafe { (*header).kval };
+                if target_kval <= old_kval && unsafe { (*header).has_flag(TAG_HINTED) } {
+                    return Some((0, false));
+                }
+                if target_kval <= old_kval {
+                    return Some((old_kval - target_kval, false));
+                }
+                let offset = self.offset_of(header as *mut u8);
+                if self.merged_offset(unsafe { &*header }, target_kval) == Some(offset) {
+                    return Some((target_kval - old_kval, false));
+                }
+            }
+            // case - aligned block, which is kept as is if the new size fits
+            None if size <= self.usable_size_aligned(ptr) => return Some((0, false)),
+            None => {}
+        }
+        // case - the block moves to a newly allocated one
+        let k = (target_kval..=self.kval_m).find(|&k| self.free_counts[k] > 0)?;
+        Some((k - target_kval + 1, true))
+    }
+
+    /// Predicts how many merges a free of ptr would do, without freeing it, by following the chain
+    /// of free buddies the same way free would. This lets real-time code predict free latency the
+    /// way realloc_cost predicts allocation latency.
+    ///
+    /// # Arguments
+    /// * ptr - Pointer to a memory block
+    ///
+    /// # Returns
+    /// the number of merges, from 0 if the buddy is reserved up to the pool's kval less the block's
+    /// if it would merge into the whole pool, and 0 for a pointer that is not from this pool
+    pub fn free_coalesce_cost(&self, ptr: *mut u8) -> usize {
+        if ptr.is_null() || !self.contains(ptr) {
+            return 0;
+        }
+        let (mut offset, mut kval) = match self.headerless_kval(ptr) {
+            Some(kval) => (self.offset_of(ptr), kval),
+            None => unsafe {
+                let avail = self.header_of(ptr);
+                (self.offset_of(avail as *mut u8), (*avail).kval)
+            },
+        };
+        let mut merges = 0;
+        while kval < self.kval_m {
+            if matches!(self.no_coalesce, Some((min, max)) if (min..=max).contains(&kval)) {
+                break;
+            }
+            let buddy = self.ptr_at(offset ^ (1 << kval));
+            let free = self.headerless_kval(buddy).is_none()
+                && unsafe {
+                    let buddy = &*(buddy as *const Avail);
+                    buddy.state() == BLOCK_AVAIL && buddy.kval == kval
+                };
+            if !free {
+                break;
+            }
+            merges += 1;
+            offset &= !(1 << kval);
+            kval += 1;
+        }
+        merges
+    }
+
+    /// Implements realloc_copy without recording a latency sample, so that the allocations and
+    /// frees it makes are not sampled separately.
+    ///
+    /// # Arguments
+    /// ptr - Pointer to a memory block
+    /// size - The new size of the memory block
+    /// copy_len - The number of bytes to preserve if the block moves
+    ///
+    /// # Returns
+    /// a pointer to the new memory block
+    fn realloc_inner(
+        &mut self,
+        ptr: *mut u8,
+        size: usize,
+        copy_len: usize,
+    ) -> Result<*mut u8, BuddyError> {
+        if ptr.is_null() || ptr == ZERO_SIZE_PTR {
+            return self.malloc(size);
+        }
+        if !self.contains(ptr) {
+            return Err(BuddyError::CorruptedMemoryPool);
+        }
+        // case - requested size is 0
+        if size == 0 {
+            self.free(ptr);
+            return Ok(ZERO_SIZE_PTR);
+        }
+        // case - headerless block, keep it if it still fits and otherwise move it to a new block
+        if self.headerless_kval(ptr).is_some() {
+            let usable = self.usable_size_aligned(ptr);
+            if size <= usable {
+                return Ok(ptr);
+            }
+            let new_block = self.malloc(size)?;
+            unsafe {
+                new_block.copy_from_nonoverlapping(ptr, usable.min(copy_len));
+            }
+            self.free(ptr);
+            return Ok(new_block);
+        }
+        #[cfg(feature = "uaf_detect")]
+        if unsafe { (*self.header_of(ptr)).marker } == FREED_MARKER {
+            return Err(BuddyError::CorruptedMemoryPool);
+        }
+        // case - requested size too large, including sizes that overflow once the header is added
+        let Some(target_kval) = self.request_kval(size) else {
+            set_errno(ENOMEM);
+            return Err(BuddyError::NoMemory);
+        };
+        if target_kval > self.kval_m && !self.auto_grow {
+            set_errno(ENOMEM);
+            return Err(BuddyError::NoMemory);
+        }
+        // case - current kval fits size
+        let mut old_avail = unsafe {
+            (ptr.sub(size_of::<Avail>()) as *mut Avail)
+                .as_mut()
+                .ok_or(BuddyError::CorruptedMemoryPool)?
+        };
+        // case - aligned allocation, keep it if it still fits and otherwise move it to a plain block
+        if unsafe { (*self.header_of(ptr)).has_flag(TAG_ALIGNED) } {
+            let usable = self.usable_size_aligned(ptr);
+            if size <= usable {
+                unsafe { self.set_req_size(ptr, size) };
+                return Ok(ptr);
+            }
+            let new_block = self.malloc(size)?;
+            unsafe {
+                new_block.copy_from_nonoverlapping(ptr, usable.min(copy_len));
+            }
+            self.free(ptr);
+            return Ok(new_block);
+        }
+        let old_kval = old_avail.kval;
+        // case - the block fits size, or it was allocated with a hint and is kept for growing
+        if target_kval == old_kval || (target_kval < old_kval && old_avail.has_flag(TAG_HINTED)) {
+            unsafe { self.set_req_size(ptr, size) };
+            return Ok(ptr);
+        }
+        // case - reduce size, each split hands the upper half straight to its avail list. The half
+        // can not coalesce while its buddy is still reserved, and only its header is written, so
+        // nothing below the new end of the block is touched. The block goes in the hold cache so
+        // that growing it again takes the halves back in place if they are still free
+        let offset = self.offset_of(ptr) - size_of::<Avail>();
+        if target_kval < old_kval {
+            self.hold(offset, target_kval, old_kval);
+        }
+        while target_kval < old_avail.kval {
+            old_avail = self.split(old_avail);
+        }
+        // case - increase size, back into the halves split off by a shrink if they are still free
+        let mut ptr = ptr;
+        if target_kval > old_avail.kval && unsafe { self.reclaim_held(old_avail, target_kval) } {
+            unsafe { self.set_req_size(ptr, size) };
+            return Ok(ptr);
+        }
+        // case - increase size in place, by absorbing the buddies above the block if they are free
+        // all the way up to the target kval, so the block keeps its pointer and nothing is copied
+        if target_kval > old_avail.kval
+            && self.merged_offset(old_avail, target_kval) == Some(offset)
+        {
+            unsafe {
+                self.merge_up_to(old_avail, target_kval);
+                self.set_req_size(ptr, size);
+            }
+            return Ok(ptr);
+        }
+        if self.grow_in_place && target_kval > old_avail.kval {
+            if let Some(grown) = unsafe { self.grow_into_buddies(old_avail, target_kval, copy_len) }
+            {
+                unsafe { self.set_req_size(grown, size) };
+                return Ok(grown);
+            }
+        }
+        if target_kval > old_avail.kval {
+            unsafe {
+                let new_avail = match self.malloc_kval(target_kval) {
+                    Err(BuddyError::NoMemory) if self.auto_grow => {
+                        // case - pool exhausted, grow it and rebase the pointer we were given
+                        let offset = ptr.offset_from(self.base.as_ptr()) as usize;
+                        self.grow_for(target_kval)?;
+                        ptr = self.base_ptr.add(offset);
+                        old_avail = &mut *(ptr.sub(size_of::<Avail>()) as *mut Avail);
+                        self.malloc_kval(target_kval)?
+                    }
+                    result => result?,
+                };
+                let new_block = (new_avail as *mut u8).add(size_of::<Avail>());
+                let old_size = (1usize << old_avail.kval) - size_of::<Avail>();
+                new_block.copy_from_nonoverlapping(ptr, old_size.min(copy_len));
+                self.free(ptr);
+                ptr = new_block;
+            }
+        }
+        unsafe { self.set_req_size(ptr, size) };
+        Ok(ptr)
+    }
+
+    /// Grows the block at ptr to hold size bytes by merging it with the free buddies above it, and
+    /// fails rather than moving or copying anything if they are not free. Unlike realloc this
+    /// never touches errno, so a container can try it cheaply before allocating a new buffer and
+    /// copying into it itself. A size that already fits the block just updates its request size.
+    ///
+    /// # Arguments
+    /// * ptr - Pointer to a memory block from malloc
+    /// * size - The new size of the memory block
+    ///
+    /// # Returns
+    /// Ok if the block now holds size bytes at ptr, NoMemory if it can not grow in place, which
+    /// includes aligned and headerless blocks, and CorruptedMemoryPool if ptr is not from this pool
+    pub fn try_grow_in_place(&mut self, ptr: *mut u8, size: usize) -> Result<(), BuddyError> {
+        if ptr.is_null() || !self.contains(ptr) {
+            return Err(BuddyError::CorruptedMemoryPool);
+        }
+        if self.headerless_kval(ptr).is_some() {
+            return Err(BuddyError::NoMemory);
+        }
+        let avail = unsafe { &mut *self.header_of(ptr) };
+        if avail.has_flag(TAG_ALIGNED) {
+            return Err(BuddyError::NoMemory);
+        }
+        let kval = b_to_k(size.saturating_add(size_of::<Avail>()));
+        if kval > avail.kval {
+            let offset = self.offset_of(avail as *mut Avail as *mut u8);
+            // A lower buddy would move the start of the block
+            if self.merged_offset(avail, kval) != Some(offset) {
+                return Err(BuddyError::NoMemory);
+            }
+            unsafe { self.merge_up_to(avail, kval) };
+        }
+        unsafe { self.set_req_size(ptr, size) };
+        Ok(())
+    }
+
+    /// Allocates a new block of size bytes to replace the block at old without freeing old. This
+    /// is useful for double buffering: the new allocation is guaranteed to have succeeded before
+    /// anything happens to the old block, and the caller copies what it needs and then frees old.
+    ///
+    /// # Arguments
+    /// old - Pointer to the memory block being replaced
+    /// size - The size of the new memory block
+    ///
+    /// # Returns
+    /// a pair of the new pointer and old, or an error if old is not from this pool or the new block
+    /// can not be allocated, in which case old is untouched
+    pub fn swap_alloc(
+        &mut self,
+        old: *mut u8,
+        size: usize,
+    ) -> Result<(*mut u8, *mut u8), BuddyError> {
+        if !old.is_null() && !self.contains(old) {
+            return Err(BuddyError::CorruptedMemoryPool);
+        }
+        let new = self.malloc(size)?;
+        Ok((new, old))
+    }
+
+    /// Runs f with a zeroed scratch buffer of size bytes. The buffer is cached between calls, so a
+    /// repeated call with the same size reuses the same block and only re-zeroes the part below
+    /// the last non-zero byte the previous call left behind. A call with a different size frees
+    /// the cached block and allocates a new one. The cached block stays allocated until
+    /// release_scratch is called.
+    ///
+    /// # Arguments
+    /// * size - The size of the scratch buffer in bytes
+    /// * f - The function to run with the buffer
+    ///
+    /// # Returns
+    /// the result of f
+    pub fn with_zeroed_scratch<R>(
+        &mut self,
+        size: usize,
+        f: impl FnOnce(&mut [u8]) -> R,
+    ) -> Result<R, BuddyError> {
+        let (ptr, dirty) = match self.scratch {
+            Some((offset, cached, dirty)) if cached == size => (self.ptr_at(offset), dirty),
+            _ => {
+                self.release_scratch();
+                (self.malloc(size)?, size)
+            }
+        };
+        let buffer = unsafe { core::slice::from_raw_parts_mut(ptr, size) };
+        buffer[..dirty].fill(0);
+        let result = f(buffer);
+        let dirty = buffer.iter().rposition(|&b| b != 0).map_or(0, |i| i + 1);
+        self.scratch = Some((self.offset_of(ptr), size, dirty));
+        Ok(result)
+    }
+
+    /// Frees the block cached by with_zeroed_scratch, if there is one.
+    pub fn release_scratch(&mut self) {
+        if let Some((offset, _, _)) = self.scratch.take() {
+            self.free(self.ptr_at(offset));
+        }
+    }
+
+    /// Allocates size bytes from the bump region, a single block reserved the first time this is
+    /// called whose size is set with the builder's bump_region_bytes. Allocations are carved one
+    /// after another with no header in front of them, and are not freed one at a time: bump_reset
+    /// reclaims all of them at once while keeping the region reserved. This suits data that lives
+    /// for a single frame or request.
+    ///
+    /// # Arguments
+    /// * size - The size of the requested memory in bytes
+    /// * align - The required alignment in bytes, which must be a power of two
+    ///
+    /// # Returns
+    /// a pointer to the memory, or None if the pool has no bump region, align is not a power of two
+    /// or the region has no room left
+    pub fn bump_alloc(&mut self, size: usize, align: usize) -> Option<*mut u8> {
+        if self.bump_size == 0 || !align.is_power_of_two() {
+            return None;
+        }
+        let (start, next, end) = match self.bump {
+            Some(bump) => bump,
+            None => {
+                let region = self.malloc(self.bump_size).ok()?;
+                let start = self.offset_of(region);
+                (start, start, start + self.bump_size)
+            }
+        };
+        let addr = self.ptr_at(next).addr();
+        let offset = next + (align - addr % align) % align;
+        if offset.checked_add(size)? > end {
+            self.bump = Some((start, next, end));
+            return None;
+        }
+        self.bump = Some((start, offset + size, end));
+        Some(self.ptr_at(offset))
+    }
+
+    /// Reclaims everything bump_alloc has handed out, so the next call starts again at the front
+    /// of the bump region. Pointers from earlier bump_alloc calls must no longer be used.
+    pub fn bump_reset(&mut self) {
+        if let Some((start, _, end)) = self.bump {
+            self.bump = Some((start, start, end));
+        }
+    }
+
+    /// Runs f with the pool and frees every block f allocated once it returns, so transient work
+    /// can not leak. The pool's free state is snapshotted before f runs and restored afterward;
+    /// allocations are recognized by the generation they were stamped with, so f should make fewer
+    /// than 65536 allocations.
+    ///
+    /// Pointers allocated inside f are dangling once with_scope returns and must not escape it.
+    /// f should also only free or reallocate what it allocated, as blocks allocated before the
+    /// scope that f frees are not brought back.
+    ///
+    /// # Arguments
+    /// * f - The function to run with the pool
+    ///
+    /// # Returns
+    /// the result of f
+    pub fn with_scope<R>(&mut self, f: impl FnOnce(&mut BuddyPool<N>) -> R) -> R {
+        let generation = self.generation;
+        let block_aligned = self.block_aligned;
+        let scratch = self.scratch;
+        let bump = self.bump;
+        let alignment_padding = self.alignment_padding;
+        let result = f(self);
+        let allocated = self.generation.wrapping_sub(generation);
+        for slot in 0..BLOCK_ALIGNED_SLOTS {
+            if let Some(entry) = self.block_aligned[slot] {
+                if !block_aligned.contains(&Some(entry)) {
+                    self.free(self.ptr_at(entry.0));
+                }
+            }
+        }
+        let mut offset = 0;
+        while offset < self.capacity() {
+            let block = self.ptr_at(offset);
+            let in_scope = |g: u16| g.wrapping_sub(generation) < allocated;
+            if let Some(entry) = self.metadata.as_ref().and_then(|m| m.get(offset)) {
+                let kval = entry.kval as usize;
+                offset = if in_scope(entry.generation) {
+                    self.free(block);
+                    self.free_block_end(offset, kval)
+                } else {
+                    offset + (1 << kval)
+                };
+                continue;
+            }
+            let (kval, header) = match self.block_aligned_slot(block) {
+                Some(slot) => (self.block_aligned[slot].map_or(0, |(_, kval)| kval), None),
+                None => unsafe { ((*(block as *mut Avail)).kval, Some(block as *mut Avail)) },
+            };
+            match header {
+                Some(avail)
+                    if unsafe {
+                        (*avail).state() == BLOCK_RESERVED && in_scope((*avail).generation)
+                    } =>
+                unsafe {
+                    #[cfg(feature = "uaf_detect")]
+                    self.mark_freed(&mut *avail);
+                    self.live_allocations -= 1;
+                    self.free_avail(&mut *avail);
+                    offset = self.free_block_end(offset, kval);
+                },
+                _ => offset += 1 << kval,
+            }
+        }
+        if self.scratch != scratch {
+            // The scratch block only changes after the old one is released
+            self.scratch = None;
+        }
+        if bump.is_none() {
+            // A bump region first allocated inside the scope was freed with the rest
+            self.bump = None;
+        }
+        self.alignment_padding = alignment_padding;
+        result
+    }
+
+    /// Checks whether the usable region of an allocation, from ptr to the end of its block, lies
+    /// within a single OS page. Such a buffer can be protected or handed to a device page by page
+    /// without affecting any other allocation's page.
+    ///
+    /// # Arguments
+    /// * ptr - Pointer to a memory block
+    ///
+    /// # Returns
+    /// true if the usable region does not cross a page boundary, false for a null pointer
+    pub fn is_page_contained(&self, ptr: *mut u8) -> bool {
+        let usable = self.usable_size_aligned(ptr);
+        if usable == 0 {
+            return false;
+        }
+        let page = page_size();
+        ptr.addr() / page == (ptr.addr() + usable - 1) / page
+    }
+
+    /// Returns a byte slice over the usable region of an allocation, from ptr to the end of its
+    /// block. This bounds accesses to the block so safe code can not overrun it.
+    ///
+    /// The slice borrows the pool mutably, so the pool can not be used to free or reallocate, or to
+    /// hand out a second slice, while it is alive.
+    ///
+    /// # Safety
+    /// ptr must be null or a live allocation from this pool, and no reference or raw pointer access
+    /// to the same allocation may be used while the slice is in use, as the pool can not track
+    /// aliasing through raw pointers.
+    ///
+    /// # Arguments
+    /// * ptr - Pointer to a memory block
+    ///
+    /// # Returns
+    /// a mutable slice over the usable bytes of the block, empty for a null pointer
+    pub unsafe fn as_slice_mut(&mut self, ptr: *mut u8) -> &mut [u8] {
+        if ptr.is_null() {
+            return &mut [];
+        }
+        // SAFETY: the caller guarantees ptr is a live allocation that nothing else refers to, and
+        // the length stops at the end of its block
+        unsafe { core::slice::from_raw_parts_mut(ptr, self.usable_size_aligned(ptr)) }
+    }
+
+    /// Checks whether a pointer lies within the memory managed by this pool. This is useful for
+    /// dispatching free and realloc when several pools are in use.
+    ///
+    /// # Arguments
+    /// * ptr - The pointer to check
+    ///
+    /// # Returns
+    /// true if ptr points into this pool's memory
+    pub fn contains(&self, ptr: *mut u8) -> bool {
+        let base = self.base.as_ptr().addr();
+        let addr = ptr.addr();
+        addr >= base && addr < base + self.capacity()
+    }
+
+    /// Returns the number of bytes the buddy system manages, which is the size of the largest
+    /// block the pool could hand out including its header.
+    ///
+    /// # Returns
+    /// the logically managed size of the pool in bytes
+    pub fn capacity(&self) -> usize {
+        1usize << self.kval_m
+    }
+
+    /// Returns the size the pool was actually given, which is the size asked of new rounded up to
+    /// a power of two and clamped to the sizes the pool supports, see new_exact.
+    ///
+    /// # Returns
+    /// the granted size of the pool in bytes, the same as capacity
+    pub fn granted_size(&self) -> usize {
+        self.capacity()
+    }
+
+    /// Returns the largest size that malloc can allocate, which succeeds only while the pool is
+    /// empty.
+    ///
+    /// # Returns
+    /// the capacity of the pool less one header
+    pub fn max_single_allocation(&self) -> usize {
+        self.capacity() - size_of::<Avail>()
+    }
+
+    /// Returns the usable size of the smallest block malloc hands out, so requests up to this size
+    /// all take the same amount of the pool. A block of the minimum kval can be too small to hold
+    /// anything past its header, in which case the smallest block is the next one up.
+    ///
+    /// # Returns
+    /// the size of the smallest block with room for a byte, less one header
+    pub fn min_alloc_size(&self) -> usize {
+        let kval = b_to_k(size_of::<Avail>() + 1).max(self.min_kval);
+        (1usize << kval) - size_of::<Avail>()
+    }
+
+    /// Suggests how many bytes to add to an object so that it and its header exactly fill the block
+    /// it is allocated in. Padding a fixed-size struct by this much uses the space that would
+    /// otherwise be lost to internal fragmentation.
+    ///
+    /// # Arguments
+    /// * size - The size of the object in bytes
+    ///
+    /// # Returns
+    /// the number of bytes to add to size, 0 if it already fills its block or no block can hold it
+    pub fn suggest_padding(&self, size: usize) -> usize {
+        let kval = b_to_k(size.saturating_add(size_of::<Avail>())).max(self.min_kval);
+        1usize
+            .checked_shl(kval as u32)
+            .map_or(0, |block| block - size_of::<Avail>() - size)
+    }
+
+    /// Returns the length of the memory mapping behind the pool. This is larger than capacity by
+    /// the size of the pool-level header set with BuddyPoolBuilder::reserved_header_bytes, and
+    /// equal to it for a pool without one.
+    ///
+    /// # Returns
+    /// the length of the mapping in bytes
+    pub fn mapped_bytes(&self) -> usize {
+        self.base.len()
+    }
+
+    /// Returns the pool-level header mapped after the memory the buddy system manages, which is
+    /// empty unless the pool was built with BuddyPoolBuilder::reserved_header_bytes. The pool
+    /// never reads or writes it, and growing the pool moves its contents along.
+    ///
+    /// # Returns
+    /// the header's bytes
+    pub fn reserved_header(&mut self) -> &mut [u8] {
+        unsafe {
+            core::slice::from_raw_parts_mut(self.base_ptr.add(self.capacity()), self.header_bytes)
+        }
+    }
+
+    /// Returns the address the pool's memory starts at, so that debuggers and FFI code can turn
+    /// pointers into offsets and check that they point into the pool. The address changes if the
+    /// pool is grown and moves.
+    ///
+    /// # Returns
+    /// the base address of the pool
+    pub fn base_addr(&self) -> usize {
+        self.base.as_ptr().addr()
+    }
+
+    /// Returns the length of the pool's memory from base_addr, the same as mapped_bytes.
+    ///
+    /// # Returns
+    /// the length of the pool's memory in bytes
+    pub fn base_len(&self) -> usize {
+        self.base.len()
+    }
+
+    /// Finds the block size with the most free blocks, which identifies the dominant small object
+    /// size in a fragmented pool. Ties go to the smaller kval. This is O(MAX_K) as the avail lists
+    /// keep a count of their blocks.
+    ///
+    /// # Returns
+    /// the kval of the longest avail list, or None if nothing has been allocated from the pool
+    pub fn hottest_kval(&self) -> Option<usize> {
+        if self.free_counts[self.kval_m] == 1 {
+            return None;
+        }
+        let mut hottest = None;
+        let mut max_count = 0;
+        for k in 0..=self.kval_m {
+            if self.free_counts[k] > max_count {
+                hottest = Some(k);
+                max_count = self.free_counts[k];
+            }
+        }
+        hottest
+    }
+
+    /// Checks whether two pools are the same pool, by comparing the memory they manage. Pointers may
+    /// only be passed between pools for which this returns true.
+    ///
+    /// # Arguments
+    /// * a - The first pool
+    /// * b - The second pool
+    ///
+    /// # Returns
+    /// true if both pools manage the same memory
+    pub fn same_pool(a: &BuddyPool<N>, b: &BuddyPool<N>) -> bool {
+        a.base.as_ptr() == b.base.as_ptr() && a.kval_m == b.kval_m
+    }
+
+    /// Returns the pool's effective configuration, including options set through the builder and
+    /// the setters.
+    ///
+    /// # Returns
+    /// the current configuration of the pool
+    pub fn config(&self) -> PoolConfig {
+        PoolConfig {
+            min_kval: self.min_kval,
+            max_kval: N - 1,
+            kval_m: self.kval_m,
+            auto_grow: self.auto_grow,
+            grow_in_place: self.grow_in_place,
+            growth_factor: self.growth_factor,
+            no_coalesce: self.no_coalesce,
+            reserve_floor_bytes: self.reserve_floor,
+            max_live_allocations: self.max_live_allocations,
+            out_of_band_metadata: self.metadata.is_some(),
+            trailing_headers: self.trailing_headers,
+            bump_region_bytes: self.bump_size,
+            numa_node: self.numa_node,
+            reserved_header_bytes: self.header_bytes,
+        }
+    }
+
+    /// Hashes the pool's configuration as returned by config: its size, the kval limits and every
+    /// option set through the builder. Two pools built with the same parameters have the same
+    /// fingerprint, which does not depend on what is allocated. Growing the pool changes it.
+    ///
+    /// # Returns
+    /// a 64 bit fingerprint of the configuration
+    pub fn config_fingerprint(&self) -> u64 {
+        let config = self.config();
+        let no_coalesce = config
+            .no_coalesce
+            .map_or(0, |(min, max)| 1 | min << 8 | max << 16);
+        [
+            config.min_kval,
+            config.max_kval,
+            config.kval_m,
+            config.auto_grow as usize,
+            config.grow_in_place as usize,
+            config.growth_factor as usize,
+            no_coalesce,
+            config.reserve_floor_bytes,
+            config
+                .max_live_allocations
+                .map_or(0, |max| max.wrapping_add(1)),
+            config.out_of_band_metadata as usize,
+            config.trailing_headers as usize,
+            config.numa_node.map_or(0, |node| node as usize + 1),
+            config.bump_region_bytes,
+            config.reserved_header_bytes,
+        ]
+        .into_iter()
+        .fold(0, |hash, value| mix(hash ^ value as u64))
+    }
+
+    /// Checks that the pool was built as expected, to catch a pool sized or configured differently
+    /// than the deployment intended.
+    ///
+    /// # Arguments
+    /// * expected - A fingerprint from config_fingerprint of a correctly built pool
+    ///
+    /// # Returns
+    /// Ok if the fingerprints match, otherwise ConfigMismatch
+    pub fn assert_config(&self, expected: u64) -> Result<(), BuddyError> {
+        if self.config_fingerprint() != expected {
+            return Err(BuddyError::ConfigMismatch);
+        }
+        Ok(())
+    }
+
+    /// Creates a handle for an allocation that can be resolved back to a pointer after the pool
+    /// has moved, for example because it was grown. Blocks from malloc_block_aligned have no header
+    /// to hold a generation, so their handles resolve for as long as any such block is at that
+    /// offset. Blocks with out of band metadata keep their generation in the metadata table.
+    ///
+    /// # Arguments
+    /// * ptr - Pointer to a memory block
+    ///
+    /// # Returns
+    /// a handle to the allocation
+    pub fn handle_of(&self, ptr: *mut u8) -> Handle {
+        let offset = self.offset_of(ptr);
+        let generation = match self.metadata.as_ref().and_then(|m| m.get(offset)) {
+            Some(entry) => entry.generation,
+            None if let Some(trailer) = self.trailer_of(ptr) => unsafe { (*trailer).generation },
+            None if self.contains(ptr) && self.block_aligned_slot(ptr).is_none() => unsafe {
+                (*self.header_of(ptr)).generation
+            },
+            None => 0,
+        };
+        Handle { offset, generation }
+    }
+
+    /// Resolves a handle to a pointer at the pool's current location.
+    ///
+    /// # Arguments
+    /// * handle - A handle from handle_of
+    ///
+    /// # Returns
+    /// the pointer to the allocation, or None if it has been freed
+    pub fn resolve(&self, handle: Handle) -> Option<*mut u8> {
+        let ptr = self.ptr_at(handle.offset);
+        if let Some(entry) = self.metadata.as_ref().and_then(|m| m.get(handle.offset)) {
+            return (entry.generation == handle.generation).then_some(ptr);
+        }
+        if let Some(trailer) = self.trailer_of(ptr) {
+            return unsafe { (*trailer).generation == handle.generation }.then_some(ptr);
+        }
+        if handle.offset < size_of::<Avail>() || handle.offset >= self.capacity() {
+            return None;
+        }
+        if self.block_aligned_slot(ptr).is_some() {
+            return Some(ptr);
+        }
+        let avail = unsafe { &*self.header_of(ptr) };
+        (avail.state() == BLOCK_RESERVED && avail.generation == handle.generation).then_some(ptr)
+    }
+
+    /// Merges every pair of free buddies left unmerged, for example by set_no_coalesce_range, and
+    /// reports the largest block that is free afterwards. The pass ignores the no coalesce range
+    /// and works from the smallest blocks up, so merged blocks go on to merge with their own
+    /// buddies.
+    ///
+    /// # Returns
+    /// the usable size of the largest free block after coalescing, 0 if no block is free
+    pub fn coalesce_and_report(&mut self) -> usize {
+        // The halves held for shrunk blocks are fair game for merging from here on
+        self.held = [[None; HELD_SLOTS]; N];
+        for k in 0..self.kval_m {
+            self.merge_pairs(k);
+        }
+        (0..=self.kval_m)
+            .rev()
+            .find(|&k| self.free_counts[k] > 0)
+            .map_or(0, |k| (1usize << k) - size_of::<Avail>())
+    }
+
+    /// Merges the free blocks of one size whose buddies are free too, for example because
+    /// set_no_coalesce_range kept free from merging them, without touching any other size. Free
+    /// blocks can not be moved, so blocks whose buddies are in use stay where they are. The merged
+    /// blocks are not merged any further, even if their own buddies are free.
+    ///
+    /// # Arguments
+    /// * kval - The kval of the free blocks to merge
+    ///
+    /// # Returns
+    /// the number of pairs merged into blocks of kval + 1
+    pub fn gather_free(&mut self, kval: usize) -> usize {
+        if kval >= self.kval_m {
+            return 0;
+        }
+        self.merge_pairs(kval)
+    }
+
+    /// Returns the memory of free blocks to the operating system, so that a pool which saw a spike
+    /// and went quiet again does not keep it resident. Free buddies are merged first, see
+    /// coalesce_and_report, and then the kernel is told it may drop the pages of every free block.
+    /// The header at the start of each block holds the avail list links, so only the whole pages
+    /// after it are dropped. The pages fault back in, zeroed or reread from the backing file, when
+    /// the blocks are used again.
+    ///
+    /// Regions handed over with from_raw are left alone, as their memory belongs to the caller.
+    #[cfg(unix)]
+    pub fn trim(&mut self) {
+        self.coalesce_and_report();
+        let page = page_size();
+        for kval in 0..=self.kval_m {
+            let mut current = self.avail[kval].next;
+            while current != head_link(kval) {
+                let node = self.ptr_at(current) as *mut Avail;
+                unsafe {
+                    let start = (current + size_of::<Avail>()).next_multiple_of(page);
+                    let end = (current + (1usize << kval)) / page * page;
+                    if start < end {
+                        // miri can not call madvise, and the pages would only be dropped anyway
+                        #[cfg(not(miri))]
+                        self.base.discard(start, end - start);
+                        // The dropped pages no longer hold the freed marker
+                        #[cfg(feature = "uaf_detect")]
+                        {
+                            (*node).marker = 0;
+                        }
+                    }
+                    current = (*node).next;
+                }
+            }
+        }
+    }
+
+    /// Runs the housekeeping that can wait for a quiet moment: frees the block cached by
+    /// with_zeroed_scratch, merges the free buddies left unmerged, see coalesce_and_report, and on
+    /// unix returns the pages of free blocks to the operating system, see trim. spawn_maintainer
+    /// runs this on a background thread.
+    ///
+    /// # Returns
+    /// the usable size of the largest free block afterwards, 0 if no block is free
+    pub fn auto_maintain(&mut self) -> usize {
+        self.release_scratch();
+        #[cfg(unix)]
+        self.trim();
+        // trim has merged the buddies already, this only finds the largest block
+        self.coalesce_and_report()
+    }
+
+    /// Checks whether a block for size bytes would be free once every pair of free buddies was
+    /// merged, without merging them. Buddies left unmerged by set_no_coalesce_range make the avail
+    /// lists understate what the pool can hold, and this looks past them: a block of the needed
+    /// kval can be formed wherever all the blocks within an aligned range of its size are free.
+    ///
+    /// # Arguments
+    /// * size - The size of the user requested memory block in bytes
+    ///
+    /// # Returns
+    /// true if a block for size would be free after coalescing
+    pub fn could_allocate(&self, size: usize) -> bool {
+        let Some(kval) = self.request_kval(size).filter(|&kval| kval <= self.kval_m) else {
+            return false;
+        };
+        if (kval..=self.kval_m).any(|k| self.free_counts[k] > 0) {
+            return true;
+        }
+        let mut range_free = true;
+        for (offset, block_kval, tag) in self.blocks() {
+            // Smaller blocks tile the range of their kval exactly, so a range is complete once a
+            // block ends on its boundary
+            if offset.is_multiple_of(1 << kval) {
+                range_free = true;
+            }
+            range_free &= tag == BLOCK_AVAIL;
+            if range_free && (offset + (1 << block_kval)).is_multiple_of(1 << kval) {
+                return true;
+            }
+        }
+        false
+    }
+
+    /// Returns the number of bytes currently reserved, counting whole blocks including their
+    /// headers.
+    ///
+    /// # Returns
+    /// the capacity of the pool less the size of all free blocks
+    pub fn used_bytes(&self) -> usize {
+        self.capacity() - self.free_bytes()
+    }
+
+    /// Returns the number of allocations that have not been freed. A block that realloc moves
+    /// counts as one allocation.
+    ///
+    /// # Returns
+    /// the number of live allocations
+    pub fn live_allocations(&self) -> usize {
+        self.live_allocations
+    }
+
+    /// Returns the usable size of the largest block handed out since the pool was initialized,
+    /// which shows the biggest single demand on the pool rather than the total.
+    ///
+    /// # Returns
+    /// the usable size of the largest block in bytes, 0 if nothing has been allocated
+    pub fn largest_allocation_ever(&self) -> usize {
+        match self.max_single_alloc_kval {
+            0 => 0,
+            k if self.metadata.is_some() => 1 << k,
+            k => (1 << k) - size_of::<Avail>(),
+        }
+    }
+
+    /// Returns the number of live allocations per MiB of pool capacity, which shows whether the
+    /// workload is made of many small allocations or a few large ones. Targets without floating
+    /// point support can compute the same figure in fixed point as
+    /// `live_allocations() * 1024 * 1024 / capacity()`, which gives whole allocations per MiB.
+    ///
+    /// # Returns
+    /// the live allocations per MiB
+    pub fn allocation_density(&self) -> f64 {
+        self.live_allocations as f64 / (self.capacity() as f64 / (1024.0 * 1024.0))
+    }
+
+    /// Returns the share of the reserved memory taken up by block headers, counting one header for
+    /// every live allocation. Many small allocations give a high ratio, as each pays for a whole
+    /// header, which suggests serving them from a SlabCache instead.
+    ///
+    /// # Returns
+    /// the header bytes of live allocations divided by used_bytes, 0 if nothing is reserved
+    pub fn overhead_ratio(&self) -> f64 {
+        let used = self.used_bytes();
+        if used == 0 {
+            return 0.0;
+        }
+        (self.live_allocations * size_of::<Avail>()) as f64 / used as f64
+    }
+
+    /// Returns the most bytes that have been reserved at once since the pool was initialized or
+    /// take_peak_used_bytes or reset_metrics was last called, counted like used_bytes.
+    ///
+    /// # Returns
+    /// the peak usage in bytes
+    pub fn peak_used_bytes(&self) -> usize {
+        self.peak_used
+    }
+
+    /// Returns the peak usage and starts a new interval with the current usage as its baseline,
+    /// so periodic callers see the peak of each interval rather than of the pool's lifetime.
+    ///
+    /// # Returns
+    /// the peak usage in bytes since the last call
+    pub fn take_peak_used_bytes(&mut self) -> usize {
+        let peak = self.peak_used;
+        self.peak_used = self.used_bytes();
+        peak
+    }
+
+    /// Returns a snapshot of the pool's statistics. Sizes are whole blocks, so the bytes of a
+    /// reserved block count as used even where they are header or unused tail, and used and free
+    /// bytes always add up to the total. Nothing in the pool is changed.
+    ///
+    /// # Returns
+    /// the current statistics of the pool
+    pub fn stats(&self) -> BuddyStats {
+        let free_bytes = self.free_bytes();
+        BuddyStats {
+            total_bytes: self.capacity(),
+            used_bytes: self.capacity() - free_bytes,
+            free_bytes,
+            largest_free_block: (0..=self.kval_m)
+                .rev()
+                .find(|&k| self.free_counts[k] > 0)
+                .map_or(0, |k| 1 << k),
+            alignment_padding_bytes: self.alignment_padding,
+        }
+    }
+
+    /// Measures fragmentation as the share of free memory that lies outside the largest free
+    /// block, from the same numbers as stats. A value near 1 means much memory is free but no
+    /// single large request fits it.
+    ///
+    /// # Returns
+    /// the fragmentation between 0 and 1, 0 if nothing is allocated or no memory is free
+    pub fn fragmentation(&self) -> f64 {
+        let stats = self.stats();
+        if stats.used_bytes == 0 || stats.free_bytes == 0 {
+            return 0.0;
+        }
+        1.0 - stats.largest_free_block as f64 / stats.free_bytes as f64
+    }
+
+    /// Runs a quick self-test of an empty pool: it is filled completely with blocks, which are then
+    /// freed in reverse and in shuffled orders, and each round must coalesce back into a single
+    /// free block. No block is smaller than the pool's minimum kval, so a pool whose minimum block
+    /// is larger than a sixteenth of the pool is only tested in halves. The capacity limit, live
+    /// allocation limit and no-coalesce range are lifted while the test runs, and the pool's
+    /// generation, peak usage and largest allocation are put back afterward, so a pool that passes
+    /// is left as it was.
+    ///
+    /// # Returns
+    /// Ok if every round coalesced fully, PoolNotEmpty if part of the pool is in use and
+    /// CorruptedMemoryPool if a round did not fill or coalesce the pool as expected
+    pub fn self_test(&mut self) -> Result<(), BuddyError> {
+        if self.free_counts[self.kval_m] != 1 {
+            return Err(BuddyError::PoolNotEmpty);
+        }
+        let generation = self.generation;
+        let peak_used = self.peak_used;
+        let max_single_alloc_kval = self.max_single_alloc_kval;
+        let capacity_limit = self.capacity_limit.take();
+        let max_live_allocations = self.max_live_allocations.take();
+        let no_coalesce = self.no_coalesce.take();
+
+        let min_kval = self.min_kval.max(b_to_k(size_of::<Avail>()));
+        let sixteenths = [self.kval_m - 4; 16];
+        let mut halves = [min_kval; MAX_K];
+        for (i, kval) in (min_kval..self.kval_m).rev().enumerate() {
+            halves[i] = kval;
+        }
+        let halves = &halves[..=self.kval_m - min_kval];
+        let odd_then_even = (0..halves.len())
+            .filter(|i| i % 2 == 1)
+            .chain((0..halves.len()).filter(|i| i % 2 == 0));
+        let mut result = Ok(());
+        if self.kval_m - 4 >= min_kval {
+            result = unsafe {
+                self.self_test_round(&sixteenths, (0..16).rev())
+                    .and_then(|_| self.self_test_round(&sixteenths, (0..16).map(|i| i * 7 % 16)))
+            };
+        }
+        let result = result.and_then(|_| unsafe { self.self_test_round(halves, odd_then_even) });
+
+        self.generation = generation;
+        self.peak_used = peak_used;
+        self.max_single_alloc_kval = max_single_alloc_kval;
+        self.capacity_limit = capacity_limit;
+        self.max_live_allocations = max_live_allocations;
+        self.no_coalesce = no_coalesce;
+        result
+    }
+
+    /// Checks the pool's invariants that can be checked without walking the avail lists. Unlike
+    /// the list checks in debug builds, this can be run in release builds and reports problems
+    /// instead of panicking.
+    ///
+    /// # Returns
+    /// Ok if the pool is consistent, otherwise CorruptedMemoryPool
+    pub fn verify(&self) -> Result<(), BuddyError> {
+        if !self.base_consistent() {
+            return Err(BuddyError::CorruptedMemoryPool);
+        }
+        self.check_usable_sizes()
+    }
+
+    /// Checks that the blocks of the pool account for every byte of it exactly once. The pool is
+    /// walked in address order, which must end exactly at the end of the pool, and the reserved
+    /// blocks met on the way plus the free blocks on the avail lists must add up to the capacity.
+    /// A header whose kval grew over its neighbour, or a free block that is on a list but was
+    /// swallowed by another block, counts bytes twice, and a lost block counts them not at all.
+    ///
+    /// # Returns
+    /// true if the blocks add up to the capacity of the pool
+    pub fn conservation_ok(&self) -> bool {
+        let mut walked = 0;
+        let mut reserved = 0;
+        for (offset, kval, tag) in self.blocks() {
+            walked = offset + (1 << kval);
+            if tag != BLOCK_AVAIL {
+                reserved += 1 << kval;
+            }
+        }
+        let mut free = 0;
+        for k in 0..=self.kval_m {
+            let head = head_link(k);
+            let mut current = self.avail[k].next;
+            // A list corrupted into a cycle that misses the head still ends after its count
+            for _ in 0..self.free_counts[k] {
+                if current == head || current >= self.capacity() {
+                    return false;
+                }
+                let block = unsafe { &*(self.ptr_at(current) as *const Avail) };
+                if block.kval > self.kval_m {
+                    return false;
+                }
+                free += 1 << block.kval;
+                current = block.next;
+            }
+            if current != head {
+                return false;
+            }
+        }
+        walked == self.capacity() && reserved + free == self.capacity()
+    }
+
+    /// Walks every avail list forward and backward and checks that it is a proper circular list,
+    /// like the checks run after every list change in debug builds but without panicking. Each
+    /// node must be a block inside the pool at an offset its size divides, be tagged as available,
+    /// carry the list's kval and link back to its neighbours, and each list must return to its
+    /// head both ways within the number of blocks its count says it holds.
+    ///
+    /// # Returns
+    /// Ok if every list is consistent, otherwise CorruptedMemoryPool
+    pub fn validate(&self) -> Result<(), BuddyError> {
+        for kval in 0..=self.kval_m {
+            let head = head_link(kval);
+            if self.avail[kval].kval != kval || self.avail[kval].state() != BLOCK_UNUSED {
+                return Err(BuddyError::CorruptedMemoryPool);
+            }
+            let count = self.free_counts[kval];
+            // Forward, checking each node before following its links
+            let mut prev = head;
+            let mut current = self.avail[kval].next;
+            for _ in 0..count {
+                if current == head || current >= self.capacity() {
+                    return Err(BuddyError::CorruptedMemoryPool);
+                }
+                let node = unsafe { &*(self.ptr_at(current) as *const Avail) };
+                if !current.is_multiple_of(1 << kval)
+                    || node.state() != BLOCK_AVAIL
+                    || node.kval != kval
+                    || node.prev != prev
+                {
+                    return Err(BuddyError::CorruptedMemoryPool);
+                }
+                prev = current;
+                current = node.next;
+            }
+            if current != head || self.avail[kval].prev != prev {
+                return Err(BuddyError::CorruptedMemoryPool);
+            }
+            // Backward, over the nodes the forward walk already checked
+            let mut next = head;
+            let mut current = self.avail[kval].prev;
+            for _ in 0..count {
+                let node = unsafe { &*(self.ptr_at(current) as *const Avail) };
+                if node.next != next {
+                    return Err(BuddyError::CorruptedMemoryPool);
+                }
+                next = current;
+                current = node.prev;
+            }
+            if current != head {
+                return Err(BuddyError::CorruptedMemoryPool);
+            }
+        }
+        Ok(())
+    }
+
+    /// Computes a fingerprint of the free list topology: the offset and header of every block on
+    /// each avail list, plus the pool's kval and base. The blocks are combined without regard to
+    /// their order within a list, so allocating and freeing a block leaves the checksum as it was
+    /// once the pool has coalesced back. A checksum that changes between two points with no
+    /// allocation in between means the lists were corrupted.
+    ///
+    /// # Returns
+    /// a 64 bit checksum of the free list state
+    pub fn state_checksum(&self) -> u64 {
+        let mut checksum = mix(self.kval_m as u64)
+            .wrapping_add(mix(self.base.as_ptr().addr() as u64))
+            .wrapping_add(self.base_consistent() as u64);
+        for k in 0..=self.kval_m {
+            let mut current = self.avail[k].next;
+            // A list corrupted into a cycle that misses the head still ends after its count
+            for _ in 0..=self.free_counts[k] {
+                if current == head_link(k) {
+                    break;
+                }
+                let offset = current as u64;
+                checksum = checksum.wrapping_add(mix(offset ^ ((k as u64) << 56)));
+                if current >= self.capacity() {
+                    break;
+                }
+                let block = unsafe { &*(self.ptr_at(current) as *const Avail) };
+                checksum = checksum
+                    .wrapping_add(mix(offset ^ ((block.kval as u64) << 48) ^ block.tag as u64));
+                current = block.next;
+            }
+        }
+        checksum
+    }
+
+    /// Resets dest and carves it into the same blocks as this pool, so that both start from the
+    /// same fragmented state. Only the shape is copied: each reserved block here becomes a reserved
+    /// block at the same offset in dest, but no data is copied, and blocks that have no header here
+    /// get one in dest.
+    ///
+    /// # Arguments
+    /// * dest - The pool to reset, which must be initialized and have the same kval
+    ///
+    /// # Returns
+    /// Ok, InvalidSize if the pools differ in size or CorruptedMemoryPool if this pool's blocks can
+    /// not be walked
+    pub fn clone_topology_into(&self, dest: &mut BuddyPool<N>) -> Result<(), BuddyError> {
+        if dest.kval_m != self.kval_m {
+            set_errno(EINVAL);
+            return Err(BuddyError::InvalidSize);
+        }
+        self.check_usable_sizes()?;
+        dest.init();
+        // Everything past the blocks carved so far is still free, so each block starts a free
+        // block in dest that only has to be split down
+        for (offset, kval, tag) in self.blocks() {
+            let mut block = unsafe { &mut *(dest.ptr_at(offset) as *mut Avail) };
+            dest.remove_from_avail(block);
+            while block.kval > kval {
+                block = dest.split(block);
+            }
+            if tag == BLOCK_AVAIL {
+                dest.add_to_avail(block);
+            } else {
+                block.req_size = 0;
+                dest.stamp_generation(block);
+                dest.live_allocations += 1;
+            }
+        }
+        dest.update_peak();
+        Ok(())
+    }
+
+    /// Rebuilds the avail lists from scratch by walking the pool block by block and linking every
+    /// block whose header is tagged as available into the list for its kval. This recovers from
+    /// corrupted list pointers as long as the block headers themselves are intact, which is checked
+    /// with check_usable_sizes before anything is changed.
+    ///
+    /// # Returns
+    /// the number of free blocks linked back into the lists, or CorruptedMemoryPool if the headers
+    /// can not be walked
+    pub fn repair(&mut self) -> Result<usize, BuddyError> {
+        self.check_usable_sizes()?;
+        for k in 0..=self.kval_m {
+            self.avail[k].next = head_link(k);
+            self.avail[k].prev = head_link(k);
+            self.avail[k].kval = k;
+            self.avail[k].set_state(BLOCK_UNUSED);
+            self.free_counts[k] = 0;
+        }
+        let mut relinked = 0;
+        let mut offset = 0;
+        while offset < self.capacity() {
+            let block = self.ptr_at(offset);
+            let kval = match self.headerless_kval(block) {
+                Some(kval) => kval,
+                None => {
+                    let avail = unsafe { &mut *(block as *mut Avail) };
+                    if avail.state() == BLOCK_AVAIL {
+                        self.add_to_avail(avail);
+                        relinked += 1;
+                    }
+                    avail.kval
+                }
+            };
+            offset += 1 << kval;
+        }
+        Ok(relinked)
+    }
+
+    /// Checks that the list for the whole pool agrees with the pool base. While nothing is
+    /// allocated the list must hold exactly one block, sitting at the base and spanning the whole
+    /// pool, and otherwise the list must be empty. A failure means the base pointer or the list
+    /// head has been corrupted.
+    ///
+    /// # Returns
+    /// true if the list for the whole pool is consistent with the base
+    pub fn base_consistent(&self) -> bool {
+        let head = &self.avail[self.kval_m];
+        let base = self.base_ptr as *const Avail;
+        match self.free_counts[self.kval_m] {
+            0 => head.next == head_link(self.kval_m) && head.prev == head_link(self.kval_m),
+            1 => unsafe {
+                head.next == 0
+                    && head.prev == 0
+                    && (*base).state() == BLOCK_AVAIL
+                    && (*base).kval == self.kval_m
+            },
+            _ => false,
+        }
+    }
+
+    /// Walks every block in the pool and checks that the bytes in use in each reserved block,
+    /// plus its header, fit within the block. A failure means a request was placed in a block
+    /// that is too small for it, which would let the caller write into the next block.
+    ///
+    /// # Returns
+    /// Ok if every reserved block fits its request, otherwise CorruptedMemoryPool
+    pub fn check_usable_sizes(&self) -> Result<(), BuddyError> {
+        let mut covered = 0;
+        for (offset, kval, tag) in self.blocks() {
+            if tag != BLOCK_RESERVED && tag != BLOCK_AVAIL {
+                return Err(BuddyError::CorruptedMemoryPool);
+            }
+            let block = self.ptr_at(offset);
+            if tag == BLOCK_RESERVED && self.headerless_kval(block).is_none() {
+                let avail = unsafe { &*(block as *const Avail) };
+                if avail.req_size as u64 + size_of::<Avail>() as u64 > 1u64 << kval {
+                    return Err(BuddyError::CorruptedMemoryPool);
+                }
+            }
+            covered = offset + (1 << kval);
+        }
+        // The walk stops early at a block with a bad kval
+        if covered != self.capacity() {
+            return Err(BuddyError::CorruptedMemoryPool);
+        }
+        Ok(())
+    }
+
+    /// Caps the number of bytes that can be reserved at once below the real size of the pool. This
+    /// lets tests exercise running out of memory and fragmentation in a small effective pool while
+    /// still using a normal mapping. An allocation fails if its block would take the reserved
+    /// memory past the limit, so no block larger than the limit can be allocated. Passing a limit
+    /// of at least capacity removes the cap.
+    ///
+    /// # Arguments
+    /// * bytes - The most memory that may be reserved, including headers
+    pub fn set_capacity_limit(&mut self, bytes: usize) {
+        self.capacity_limit = (bytes < self.capacity()).then_some(bytes);
+    }
+
+    /// Lists every free block in address order, unlike the avail lists which are in the order the
+    /// blocks were freed. Adjacent entries show runs of contiguous free space, which helps with
+    /// analysing external fragmentation and planning compaction.
+    ///
+    /// # Returns
+    /// the offset from the pool base and size in bytes of each free block, sorted by offset
+    #[cfg(feature = "std")]
+    pub fn free_blocks_sorted(&self) -> Vec<(usize, usize)> {
+        self.blocks()
+            .filter(|&(_, _, tag)| tag == BLOCK_AVAIL)
+            .map(|(offset, kval, _)| (offset, 1usize << kval))
+            .collect()
+    }
+
+    /// Counts the blocks of each kval in the pool, free or reserved, by walking the whole pool from
+    /// block to block. Unlike free_counts this shows how far the whole pool is subdivided, and as
+    /// the blocks tile the pool the counts times their block sizes add up to the capacity.
+    ///
+    /// # Returns
+    /// the number of blocks of each kval, indexed by kval
+    pub fn depth_histogram(&self) -> [usize; MAX_K] {
+        let mut histogram = [0; MAX_K];
+        for (_, kval, _) in self.blocks() {
+            histogram[kval] += 1;
+        }
+        histogram
+    }
+
+    /// Lists every outstanding allocation in address order, for tracking down leaks. The pool
+    /// keeps no list of reserved blocks, so this walks the pool from block to block and skips the
+    /// free ones. Pointers from malloc_aligned are reported as the start of their usable region,
+    /// before the alignment padding, and the blocks held by the scratch buffer and the bump region
+    /// are reported like any other allocation.
+    ///
+    /// # Returns
+    /// an iterator over the user pointer and usable size of each reserved block
+    pub fn allocated_blocks(&self) -> impl Iterator<Item = (*mut u8, usize)> + '_ {
+        self.blocks()
+            .filter(|&(_, _, tag)| tag == BLOCK_RESERVED)
+            .map(|(offset, _, _)| {
+                let block = self.ptr_at(offset);
+                let ptr = match self.headerless_kval(block) {
+                    Some(_) => block,
+                    None => self.ptr_at(offset + size_of::<Avail>()),
+                };
+                (ptr, self.usable_size(ptr))
+            })
+    }
+
+    /// Lists every block in the pool in address order, free or reserved, by walking the whole pool
+    /// from block to block. The blocks tile the pool, so this is the complete map of its state for
+    /// debuggers and other tooling.
+    ///
+    /// # Returns
+    /// the offset from the pool base, size in bytes and state of each block, sorted by offset
+    #[cfg(feature = "std")]
+    pub fn allocation_table(&self) -> Vec<(usize, usize, BlockTag)> {
+        self.blocks()
+            .map(|(offset, kval, tag)| {
+                let tag = match tag {
+                    BLOCK_AVAIL => BlockTag::Avail,
+                    _ => BlockTag::Reserved,
+                };
+                (offset, 1usize << kval, tag)
+            })
+            .collect()
+    }
+
+    /// Stops free from coalescing blocks whose kval lies within min_kval..=max_kval. Blocks in the
+    /// range go straight back to their avail list so they stay ready for immediate reuse, which
+    /// reduces churn for a hot object size. Blocks outside the range still coalesce normally.
+    /// Passing a min_kval greater than max_kval turns this off again.
+    ///
+    /// # Arguments
+    /// * min_kval - The smallest kval that is not coalesced
+    /// * max_kval - The largest kval that is not coalesced
+    pub fn set_no_coalesce_range(&mut self, min_kval: usize, max_kval: usize) {
+        self.no_coalesce = (min_kval <= max_kval).then_some((min_kval, max_kval));
+    }
+
+    /// Installs the clock used to time malloc, free and realloc. The clock can count in any unit,
+    /// it only has to be monotonic. Sampling stays off until a clock is installed, which keeps the
+    /// pool independent of any particular time source.
+    ///
+    /// # Arguments
+    /// * clock - A function returning the current time
+    #[cfg(feature = "latency_sampling")]
+    pub fn set_clock(&mut self, clock: fn() -> u64) {
+        self.latency.set_clock(clock);
+    }
+
+    /// Returns the most recent operation latencies in the units of the installed clock. Once more
+    /// samples have been taken than fit in the ring buffer, the oldest are overwritten in place,
+    /// so the slice is in ring order rather than time order.
+    ///
+    /// # Returns
+    /// the recorded malloc, free and realloc durations
+    #[cfg(feature = "latency_sampling")]
+    pub fn latency_samples(&self) -> &[u64] {
+        self.latency.samples()
+    }
+
+    /// Tells whether fragmentation is getting worse or better, from the slope of the fragmentation
+    /// sampled after each recent malloc and free. Fragmentation is the share of free memory that
+    /// lies outside the largest free block. A background maintainer can use this to decide when to
+    /// compact.
+    ///
+    /// # Returns
+    /// a positive value if fragmentation is worsening, a negative value if it is improving and 0
+    /// if it is stable, in per mille per sample
+    #[cfg(feature = "fragmentation_trend")]
+    pub fn fragmentation_trend(&self) -> i32 {
+        self.fragmentation.trend()
+    }
+
+    /// Enables or disables growing the pool from realloc. When enabled, a realloc that can not be
+    /// satisfied within the current pool calls grow to map a larger region instead of failing. This
+    /// is off by default because growing may move the pool and invalidate outstanding pointers.
+    ///
+    /// # Arguments
+    /// * enabled - Whether realloc may grow the pool
+    pub fn set_auto_grow(&mut self, enabled: bool) {
+        self.auto_grow = enabled;
+    }
+
+    /// Enables or disables growing blocks into their lower buddies from realloc. A realloc that
+    /// grows a block always absorbs the free buddies above it when they reach the new size, which
+    /// keeps the pointer. When enabled, it also tries to merge the block with free buddies on
+    /// either side. If a lower buddy is merged the block starts lower down, so the data is moved
+    /// back to the start of the merged block and the returned pointer is lower than the old one,
+    /// but no new block is allocated. Only when the buddies are not free all the way up does the
+    /// block move to a newly allocated one. This is off by default so that a growing realloc does
+    /// not take up the memory below a block.
+    ///
+    /// # Arguments
+    /// * enabled - Whether realloc may merge blocks with their free buddies
+    pub fn set_grow_in_place(&mut self, enabled: bool) {
+        self.grow_in_place = enabled;
+    }
+
+    /// Grows the pool so that it manages at least new_size bytes. The size is rounded up to the
+    /// next power of two and is always at least double the current size. The new upper half of
+    /// the region is added to the avail lists and coalesced with any free blocks below it.
+    ///
+    /// On Linux the mapping is resized with mremap, which keeps the pool in place when the address
+    /// space allows it. Otherwise the pool is moved to a new mapping, which invalidates every
+    /// outstanding pointer into the pool. The avail lists link blocks by offset, so they stay
+    /// valid wherever the mapping ends up.
+    ///
+    /// # Arguments
+    /// * new_size - The minimum size of the pool in bytes after growing
+    pub fn grow(&mut self, new_size: usize) -> Result<(), BuddyError> {
+        let kval = b_to_k(new_size).max(self.kval_m + 1);
+        if kval >= N {
+            set_errno(ENOMEM);
+            return Err(BuddyError::NoMemory);
+        }
+        let old_kval = self.kval_m;
+        if let Some(metadata) = self.metadata.as_mut() {
+            metadata.resize(1usize << kval)?;
+        }
+        self.remap((1usize << kval) + self.header_bytes)?;
+        // The header follows the end of the pool, which the new blocks below are written over
+        unsafe {
+            ptr::copy(
+                self.base_ptr.add(1usize << old_kval),
+                self.base_ptr.add(1usize << kval),
+                self.header_bytes,
+            );
+        }
+        if let Some(node) = self.numa_node {
+            self.bind_numa(node);
+        }
+
+        for i in old_kval + 1..=kval {
+            self.avail[i].next = head_link(i);
+            self.avail[i].prev = head_link(i);
+            self.avail[i].kval = i;
+            self.avail[i].set_state(BLOCK_UNUSED);
+        }
+        self.kval_m = kval;
+
+        // Each new block is the buddy of everything below it, so freeing them in order coalesces
+        // the old pool upwards as far as it is free
+        for i in old_kval..kval {
+            unsafe {
+                let block = self.base_ptr.add(1usize << i) as *mut Avail;
+                ptr::write(block, Avail::new());
+                (*block).kval = i;
+                (*block).set_state(BLOCK_RESERVED);
+                self.free_avail(&mut *block);
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the NUMA node the pool's pages are bound to. This is None unless the pool was built
+    /// with a NUMA node and the numa feature, and binding took effect, which needs Linux with NUMA
+    /// support and an existing node.
+    ///
+    /// # Returns
+    /// the node the pool is bound to, or None if it is not bound
+    pub fn numa_node(&self) -> Option<u32> {
+        self.numa_node
+    }
+
+    /// Binds the pool's pages to a NUMA node, recording whether it took effect. Without the numa
+    /// feature the pool stays unbound.
+    ///
+    /// # Arguments
+    /// * node - The NUMA node to bind to
+    pub(crate) fn bind_numa(&mut self, node: u32) {
+        #[cfg(feature = "numa")]
+        let bound = numa::bind(self.base_ptr, self.base.len(), node);
+        #[cfg(not(feature = "numa"))]
+        let bound = false;
+        self.numa_node = bound.then_some(node);
+    }
+
+    /// Grows the pool far enough that a block of kval is available regardless of how much of the
+    /// current pool is in use. The pool's growth factor decides whether it grows just that far or
+    /// further to leave room for later requests.
+    ///
+    /// # Arguments
+    /// * kval - The kval of the block that must fit after growing
+    fn grow_for(&mut self, kval: usize) -> Result<(), BuddyError> {
+        let fit = (kval + 1).max(self.kval_m + 1);
+        if fit >= N {
+            set_errno(ENOMEM);
+            return Err(BuddyError::NoMemory);
+        }
+        let kval = match self.growth_factor {
+            GrowthFactor::Fit => fit,
+            GrowthFactor::Double => (fit + 1).min(N - 1),
+        };
+        self.grow(1usize << kval)
+    }
+
+    /// Resizes the backing mapping to len bytes, preserving its contents. The mapping may move,
+    /// and a region from from_raw can not be resized at all.
+    ///
+    /// # Arguments
+    /// * len - The new length of the mapping in bytes
+    pub(crate) fn remap(&mut self, len: usize) -> Result<(), BuddyError> {
+        self.base.remap(len)?;
+        self.base_ptr = self.base.as_mut_ptr();
+        Ok(())
+    }
+
+    /// Finds the Avail header of the block holding a user pointer. For pointers returned by
+    /// malloc_aligned the header is found through the marker written in front of the pointer.
+    ///
+    /// # Arguments
+    /// * ptr - Pointer to user memory
+    ///
+    /// # Returns
+    /// a pointer to the Avail struct at the start of the block
+    unsafe fn header_of(&self, ptr: *mut u8) -> *mut Avail {
+        let avail = ptr.sub(size_of::<Avail>()) as *mut Avail;
+        if (*avail).state() == BLOCK_ALIGNED {
+            return ptr.sub((*avail).kval) as *mut Avail;
+        }
+        avail
+    }
+
+    /// Finds the header of the block holding a user pointer like header_of, but only if the
+    /// pointer sits where malloc or malloc_aligned would have put it. The header must be aligned
+    /// and inside the pool, and start a block of a valid kval at a multiple of its size, so a
+    /// pointer into the middle of a block is turned away before its bytes are taken as a header.
+    ///
+    /// # Arguments
+    /// * ptr - Pointer into the pool
+    ///
+    /// # Returns
+    /// a pointer to the Avail struct at the start of the block, or None if ptr can not be a user
+    /// pointer of this pool
+    unsafe fn checked_header_of(&self, ptr: *mut u8) -> Option<*mut Avail> {
+        let avail_size = size_of::<Avail>();
+        let offset = self.offset_of(ptr);
+        if offset < avail_size
+            || offset >= self.capacity()
+            || !offset.is_multiple_of(align_of::<Avail>())
+        {
+            return None;
+        }
+        let marker = self.ptr_at(offset - avail_size) as *const Avail;
+        let header_offset = match (*marker).state() {
+            BLOCK_ALIGNED => offset.checked_sub((*marker).kval)?,
+            _ => offset - avail_size,
+        };
+        let header = self.ptr_at(header_offset) as *mut Avail;
+        if header.is_null() || !header_offset.is_multiple_of(align_of::<Avail>()) {
+            return None;
+        }
+        let kval = (*header).kval;
+        let valid = (b_to_k(avail_size)..=self.kval_m).contains(&kval);
+        (valid && header_offset.is_multiple_of(1 << kval)).then_some(header)
+    }
+
+    /// Walks every block in the pool in address order, stepping from each block to the next by its
+    /// kval. Headerless blocks are looked up in the side table or the metadata table instead. The
+    /// walk stops early at a block whose kval can not be right, so that a corrupted header can not
+    /// send it outside the pool.
+    ///
+    /// # Returns
+    /// an iterator over the offset, kval and tag of each block
+    fn blocks(&self) -> impl Iterator<Item = (usize, usize, u8)> + '_ {
+        let min_kval = b_to_k(size_of::<Avail>());
+        let end = self.capacity();
+        let mut offset = 0;
+        core::iter::from_fn(move || {
+            if offset >= end {
+                return None;
+            }
+            let block = self.ptr_at(offset);
+            let (kval, tag) = match self.headerless_kval(block) {
+                Some(kval) => (kval, BLOCK_RESERVED),
+                None => {
+                    let avail = unsafe { &*(block as *const Avail) };
+                    (avail.kval, avail.state())
+                }
+            };
+            if kval < min_kval || kval > self.kval_m || !offset.is_multiple_of(1 << kval) {
+                offset = end;
+                return None;
+            }
+            let block = (offset, kval, tag);
+            offset += 1 << kval;
+            Some(block)
+        })
+    }
+
+    /// Finds the free block that a just freed block ended up in after coalescing. Only the header
+    /// of the merged block is tagged as available, the headers it absorbed are left reserved.
+    ///
+    /// # Arguments
+    /// * offset - The offset of the freed block
+    /// * kval - The kval of the freed block
+    ///
+    /// # Returns
+    /// the offset just past the free block holding offset
+    fn free_block_end(&self, offset: usize, kval: usize) -> usize {
+        (kval..=self.kval_m)
+            .map(|k| (offset & !((1 << k) - 1), k))
+            .find(|&(start, k)| {
+                let avail = unsafe { &*(self.ptr_at(start) as *const Avail) };
+                avail.state() == BLOCK_AVAIL && avail.kval == k
+            })
+            .map_or(offset + (1 << kval), |(start, k)| start + (1 << k))
+    }
+
+    /// Records the number of bytes in use past the header of the block holding ptr, counting any
+    /// alignment padding in front of ptr.
+    ///
+    /// # Arguments
+    /// * ptr - Pointer to user memory
+    /// * size - The requested size in bytes
+    unsafe fn set_req_size(&self, ptr: *mut u8, size: usize) {
+        let avail = self.header_of(ptr);
+        let used = ptr.offset_from(avail as *mut u8) as usize - size_of::<Avail>() + size;
+        (*avail).req_size = u32::try_from(used).unwrap_or(u32::MAX);
+    }
+
+    /// Gives a block that is being allocated the next generation, so that handles to earlier
+    /// allocations of the same memory stop resolving.
+    ///
+    /// # Arguments
+    /// * avail - The block being allocated
+    fn stamp_generation(&mut self, avail: &mut Avail) {
+        avail.generation = self.generation;
+        self.generation = self.generation.wrapping_add(1);
+    }
+
+    /// Converts a pointer into its offset from the pool base, which stays the same if the pool moves.
+    /// A null pointer maps to an offset outside the pool.
+    ///
+    /// # Arguments
+    /// * ptr - Pointer into the pool
+    ///
+    /// # Returns
+    /// the offset of ptr from the pool base
+    pub(crate) fn offset_of(&self, ptr: *mut u8) -> usize {
+        ptr.addr().wrapping_sub(self.base.as_ptr().addr())
+    }
+
+    /// Converts an offset from offset_of back into a pointer at the pool's current location.
+    ///
+    /// # Arguments
+    /// * offset - The offset from the pool base
+    ///
+    /// # Returns
+    /// the pointer at offset, or null if offset is outside the pool
+    pub(crate) fn ptr_at(&self, offset: usize) -> *mut u8 {
+        if offset >= self.capacity() {
+            return ptr::null_mut();
+        }
+        unsafe { self.base_ptr.add(offset) }
+    }
+
+    /// Derives a pointer to the same place from the pool base. A pointer made from a reference to a
+    /// header may only be used to reach the header itself, so pointers to a block's body must be
+    /// derived from the base to keep the provenance of the whole mapping.
+    ///
+    /// # Arguments
+    /// * ptr - Pointer into the pool
+    ///
+    /// # Returns
+    /// a pointer to the same address with the provenance of the pool base
+    fn rederive<T>(&self, ptr: *const T) -> *mut T {
+        self.ptr_at(self.offset_of(ptr as *mut u8)) as *mut T
+    }
+
+    /// Finds the side table slot of a headerless block from malloc_block_aligned.
+    ///
+    /// # Arguments
+    /// * ptr - Pointer to the start of a block
+    ///
+    /// # Returns
+    /// the index of the block's slot, or None if ptr is not a headerless block
+    fn block_aligned_slot(&self, ptr: *const u8) -> Option<usize> {
+        let offset = self.offset_of(ptr as *mut u8);
+        self.block_aligned
+            .iter()
+            .position(|entry| matches!(entry, Some((o, _)) if *o == offset))
+    }
+
+    /// Finds the header at the end of a block from malloc with trailing headers. free only gets the
+    /// start of the block, so each kval the block could have is tried from the largest down. For a
+    /// kval larger than the block's own, the bytes where the trailer would be are the header at the
+    /// end of a block that starts higher, and so never hold a trailer for this offset.
+    ///
+    /// # Arguments
+    /// * ptr - Pointer to the start of a block
+    ///
+    /// # Returns
+    /// a pointer to the block's trailing header, or None if ptr is not such a block
+    fn trailer_of(&self, ptr: *const u8) -> Option<*mut Avail> {
+        if !self.trailing_headers || !self.contains(ptr as *mut u8) {
+            return None;
+        }
+        let offset = self.offset_of(ptr as *mut u8);
+        let min_kval = b_to_k(size_of::<Avail>());
+        (min_kval..=self.kval_m)
+            .rev()
+            .filter(|&k| offset.is_multiple_of(1 << k))
+            .map(|k| {
+                (
+                    k,
+                    self.ptr_at(offset + (1 << k) - size_of::<Avail>()) as *mut Avail,
+                )
+            })
+            .find(|&(k, trailer)| unsafe {
+                (*trailer).state() == BLOCK_RESERVED
+                    && (*trailer).has_flag(TAG_TRAILING)
+                    && (*trailer).kval == k
+                    && (*trailer).next == offset
+                    && (*trailer).prev == self.epoch
+            })
+            .map(|(_, trailer)| trailer)
+    }
+
+    /// Returns the usable size of a headerless block, which is the whole block unless its header
+    /// is kept at its end.
+    ///
+    /// # Arguments
+    /// * ptr - Pointer to the start of a block
+    ///
+    /// # Returns
+    /// the number of usable bytes in the block, or None if ptr is not a headerless block
+    fn headerless_usable_size(&self, ptr: *const u8) -> Option<usize> {
+        if let Some(trailer) = self.trailer_of(ptr) {
+            return Some((1usize << unsafe { (*trailer).kval }) - size_of::<Avail>());
+        }
+        self.headerless_kval(ptr).map(|kval| 1usize << kval)
+    }
+
+    /// Finds the kval of a headerless block, which is either from malloc_block_aligned, has its
+    /// header at its end or has its metadata out of band.
+    ///
+    /// # Arguments
+    /// * ptr - Pointer to the start of a block
+    ///
+    /// # Returns
+    /// the kval of the block, or None if ptr is not a headerless block
+    fn headerless_kval(&self, ptr: *const u8) -> Option<usize> {
+        if let Some(slot) = self.block_aligned_slot(ptr) {
+            return self.block_aligned[slot].map(|(_, kval)| kval);
+        }
+        if let Some(trailer) = self.trailer_of(ptr) {
+            return Some(unsafe { (*trailer).kval });
+        }
+        let entry = self
+            .metadata
+            .as_ref()?
+            .get(self.offset_of(ptr as *mut u8))?;
+        Some(entry.kval as usize)
+    }
+
+    /// Removes a headerless block from the side table or the metadata table, or clears its trailing
+    /// header, and writes a reserved header back into the block, so that it can be freed like any
+    /// other block.
+    ///
+    /// # Arguments
+    /// * ptr - Pointer to the start of a block
+    ///
+    /// # Returns
+    /// a pointer to the block's new header, or None if ptr is not a headerless block
+    unsafe fn take_headerless(&mut self, ptr: *mut u8) -> Option<*mut Avail> {
+        let kval = match self.block_aligned_slot(ptr) {
+            Some(slot) => self.block_aligned[slot].take()?.1,
+            None if let Some(trailer) = self.trailer_of(ptr) => {
+                (*trailer).set_flag(TAG_TRAILING, false);
+                (*trailer).kval
+            }
+            None => {
+                let offset = self.offset_of(ptr);
+                let metadata = self.metadata.as_mut()?;
+                let entry = metadata.get(offset)?;
+                metadata.set(offset, None);
+                entry.kval as usize
+            }
+        };
+        let avail = ptr as *mut Avail;
+        ptr::write(avail, Avail::new());
+        (*avail).set_state(BLOCK_RESERVED);
+        (*avail).kval = kval;
+        Some(avail)
+    }
+
+    /// Runs a public operation, timing it when latency sampling is enabled. Nested operations,
+    /// such as the malloc inside a moving realloc, are not timed on their own. When fragmentation
+    /// trend tracking is enabled, the fragmentation after the operation is recorded as well.
+    ///
+    /// # Arguments
+    /// * op - The operation to run
+    ///
+    /// # Returns
+    /// the result of op
+    #[inline]
+    fn sampled<R>(&mut self, op: impl FnOnce(&mut Self) -> R) -> R {
+        #[cfg(feature = "latency_sampling")]
+        let start = self.latency.begin();
+        let result = op(self);
+        #[cfg(feature = "latency_sampling")]
+        self.latency.end(start);
+        #[cfg(feature = "fragmentation_trend")]
+        self.fragmentation.record(self.fragmentation_per_mille());
+        result
+    }
+
+    /// Measures fragmentation as the share of free memory that lies outside the largest free
+    /// block. This is O(MAX_K) as the avail lists keep a count of their blocks.
+    ///
+    /// # Returns
+    /// the fragmentation in per mille, 0 if no memory is free
+    #[cfg(feature = "fragmentation_trend")]
+    fn fragmentation_per_mille(&self) -> u32 {
+        let mut total = 0u64;
+        let mut largest = 0u64;
+        for k in 0..=self.kval_m {
+            if self.free_counts[k] > 0 {
+                total += (self.free_counts[k] as u64) << k;
+                largest = 1u64 << k;
+            }
+        }
+        if total == 0 {
+            return 0;
+        }
+        (1000 - largest * 1000 / total) as u32
+    }
+
+    /// Adds a block of memory to the avail list and tags it as available.
+    ///
+    /// # Arguments
+    /// * avail - The block of memory to add to the avail list
+    fn add_to_avail(&mut self, avail: &mut Avail) {
+        let kval = avail.kval;
+        let link = self.offset_of(avail as *mut Avail as *mut u8);
+        unsafe { list::insert(&mut self.avail, self.base_ptr, avail) };
+        if self.trailing_headers {
+            // A free block has its header at the start, so a trailer left at its end by an earlier
+            // block must not be taken for a live one
+            let end = link + (1 << kval);
+            unsafe { (*(self.ptr_at(end - size_of::<Avail>()) as *mut Avail)).tag = BLOCK_UNUSED };
+        }
+        avail.set_state(BLOCK_AVAIL);
+        self.free_counts[kval] += 1;
+        #[cfg(debug_assertions)]
+        self.debug_check_lists();
+    }
+
+    /// Removes a block of memory from the avail list and tags it as reserved.
+    ///
+    /// # Arguments
+    /// * avail - The block of memory to remove from the avail list
+    fn remove_from_avail(&mut self, avail: &mut Avail) {
+        unsafe { list::remove(&mut self.avail, self.base_ptr, avail) };
+        // A block leaving the lists is handed out afresh, so the flags of its last use are cleared
+        avail.tag = BLOCK_RESERVED;
+        self.free_counts[avail.kval] -= 1;
+        #[cfg(debug_assertions)]
+        self.debug_check_lists();
+    }
+
+    /// Find the buddy of a given pointer and kval relative to the base address we got from memmap2
+    ///
+    /// # Arguments
+    /// * buddy - The memory block that we want to find the buddy for
+    ///
+    /// # Returns
+    /// a pointer to the buddy
+    fn buddy_calc(&self, avail: &Avail) -> *mut Avail {
+        let offset = self.offset_of(avail as *const Avail as *mut u8);
+        self.ptr_at(list::buddy_offset(offset, avail.kval)) as *mut Avail
+    }
+
+    /// Splits a block of memory into two smaller blocks. This function will return a pointer to the
+    /// block with the lowest address, the other block will be added to the Avail list. The returned
+    /// block will be tagged as reserved and not added to the avail list.
+    ///
+    /// # Arguments
+    /// * avail - The block of memory to split
+    ///
+    /// # Returns
+    /// a pointer to the block with the lowest address after the split
+    fn split<'a>(&mut self, avail: &'a mut Avail) -> &'a mut Avail {
+        let kval = avail.kval;
+        avail.kval -= 1;
+        avail.set_state(BLOCK_RESERVED);
+        let buddy = self.buddy_calc(avail);
+        debug_assert!(buddy.is_aligned());
+        unsafe {
+            ptr::write(buddy, Avail::new());
+            let buddy = &mut *buddy;
+            buddy.kval = kval - 1;
+            buddy.set_state(BLOCK_AVAIL);
+            self.add_to_avail(buddy);
+        }
+        avail
+    }
+
+    /// Gets the buddy of a block of memory. This function will return None if the buddy is not
+    /// tagged as available. This is most useful in coalescing blocks during a free operation.
+    ///
+    /// # Arguments
+    /// * avail - The block of memory to get the buddy for
+    ///
+    /// # Returns
+    /// a pointer to the buddy block if it is available, otherwise None
+    fn get_avail_buddy(&self, avail: &Avail) -> Option<*mut Avail> {
+        if avail.kval == self.kval_m {
+            return None;
+        }
+        let buddy = unsafe { self.buddy_calc(avail).as_ref().unwrap() };
+        if buddy.state() != BLOCK_AVAIL {
+            return None;
+        }
+        if buddy.kval != avail.kval {
+            return None;
+        }
+        // A headerless block is reserved no matter what its memory looks like
+        if self
+            .headerless_kval(buddy as *const Avail as *const u8)
+            .is_some()
+        {
+            return None;
+        }
+        Some(buddy as *const Avail as *mut Avail)
+    }
+
+    /// Allocates a block of memory of size 2^k bytes, returning a pointer to the Avail struct at
+    /// the start of the block. This is in contrast to the malloc function which returns a pointer
+    /// to the start of usable user memory.
+    ///
+    /// # Arguments
+    /// * kval - The size of the requested block in K values
+    ///
+    /// # Returns
+    /// a pointer to the Avail struct at the start of the block
+    unsafe fn malloc_kval(&mut self, kval: usize) -> Result<*mut Avail, BuddyError> {
+        // Find the smallest free block that fits, the range is empty if kval is too large
+        let found = (kval..=self.kval_m).find(|&k| self.free_counts[k] > 0);
+        let Some(k) = found.filter(|_| self.within_capacity_limit(kval)) else {
+            set_errno(ENOMEM);
+            return Err(BuddyError::NoMemory);
+        };
+        if self
+            .max_live_allocations
+            .is_some_and(|max| self.live_allocations >= max)
+        {
+            set_errno(ENOMEM);
+            return Err(BuddyError::LiveAllocationLimit);
+        }
+        debug_assert!(kval >= self.min_kval);
+        let mut block = &mut *(self.ptr_at(self.avail[k].next) as *mut Avail);
+        self.remove_from_avail(block);
+        #[cfg(feature = "uaf_detect")]
+        self.check_freed(block)?;
+        //Split the block down to the requested size
+        while block.kval > kval {
+            block = self.split(block);
+        }
+        #[cfg(feature = "debug_poison")]
+        self.poison(block, POISON_ALLOCATED);
+        self.stamp_generation(block);
+        self.live_allocations += 1;
+        self.max_single_alloc_kval = self.max_single_alloc_kval.max(kval);
+        self.update_peak();
+        Ok(self.rederive(block))
+    }
+
+    /// Runs one round of self_test: allocates a block of each kval, checks that nothing is left
+    /// free, then frees the blocks in the given order and checks that they coalesced into a single
+    /// free block.
+    ///
+    /// # Arguments
+    /// * kvals - The kvals of the blocks to allocate, which must add up to the whole pool
+    /// * order - The order to free the blocks in, as indices into kvals
+    ///
+    /// # Returns
+    /// Ok if the round filled and coalesced the pool, the allocation error or CorruptedMemoryPool
+    /// otherwise
+    unsafe fn self_test_round(
+        &mut self,
+        kvals: &[usize],
+        order: impl Iterator<Item = usize>,
+    ) -> Result<(), BuddyError> {
+        let mut blocks = [ptr::null_mut::<Avail>(); MAX_K];
+        for (i, &kval) in kvals.iter().enumerate() {
+            blocks[i] = self.malloc_kval(kval)?;
+        }
+        if self.free_bytes() != 0 {
+            return Err(BuddyError::CorruptedMemoryPool);
+        }
+        for i in order {
+            self.live_allocations -= 1;
+            self.free_avail(&mut *blocks[i]);
+        }
+        if self.free_counts[self.kval_m] != 1 || self.free_bytes() != self.capacity() {
+            return Err(BuddyError::CorruptedMemoryPool);
+        }
+        Ok(())
+    }
+
+    /// Raises the peak usage to the current usage if it is higher. This is called once an
+    /// allocation is complete, as usage briefly overshoots while a block is being split.
+    fn update_peak(&mut self) {
+        self.peak_used = self.peak_used.max(self.used_bytes());
+    }
+
+    /// Checks whether a block of kval can be allocated without the reserved memory exceeding the
+    /// limit set with set_capacity_limit.
+    ///
+    /// # Arguments
+    /// * kval - The kval of the block to allocate
+    ///
+    /// # Returns
+    /// true if there is no limit or the block fits within it
+    fn within_capacity_limit(&self, kval: usize) -> bool {
+        let Some(limit) = self.capacity_limit else {
+            return true;
+        };
+        let used = self.capacity() - self.free_bytes();
+        used as u64 + (1u64 << kval) <= limit as u64
+    }
+
+    /// Sums the sizes of all free blocks. This is O(MAX_K) as the avail lists keep a count of their
+    /// blocks.
+    ///
+    /// # Returns
+    /// the number of free bytes in the pool, including the headers of free blocks
+    fn free_bytes(&self) -> usize {
+        (0..=self.kval_m).map(|k| self.free_counts[k] << k).sum()
+    }
+
+    /// Grows a reserved block to kval by merging it with its free buddies, moving its data down to
+    /// the start of the merged block if a lower buddy was merged. The whole chain of buddies is
+    /// checked before anything is merged, so the block is left as it was if it can not grow.
+    ///
+    /// # Arguments
+    /// * avail - The reserved block to grow
+    /// * kval - The kval to grow it to
+    /// * copy_len - The number of bytes of data to preserve if the data moves
+    ///
+    /// # Returns
+    /// the user pointer of the merged block, or None if the buddies are not free up to kval
+    unsafe fn grow_into_buddies(
+        &mut self,
+        avail: &mut Avail,
+        kval: usize,
+        copy_len: usize,
+    ) -> Option<*mut u8> {
+        let old_offset = self.offset_of(avail as *mut Avail as *mut u8);
+        let offset = self.merged_offset(avail, kval)?;
+        let old_size = (1usize << avail.kval) - size_of::<Avail>();
+        self.merge_up_to(avail, kval);
+        let ptr = self.ptr_at(offset + size_of::<Avail>());
+        if offset != old_offset {
+            // The old data lies above the new header, and may overlap its new place
+            let old = self.ptr_at(old_offset + size_of::<Avail>());
+            ptr::copy(old, ptr, old_size.min(copy_len));
+        }
+        Some(ptr)
+    }
+
+    /// Remembers a block realloc is about to shrink, so that growing it again can take back the
+    /// halves it splits off. The newest block goes first in the slots for its new kval, and the
+    /// oldest one is forgotten once the slots are full.
+    ///
+    /// # Arguments
+    /// * offset - The offset of the block from the pool base
+    /// * kval - The kval the block is shrunk to
+    /// * former_kval - The kval of the block before the shrink
+    fn hold(&mut self, offset: usize, kval: usize, former_kval: usize) {
+        let slots = &mut self.held[kval];
+        let end = slots
+            .iter()
+            .position(|slot| slot.is_some_and(|(held, _)| held == offset))
+            .unwrap_or(HELD_SLOTS - 1);
+        slots[..=end].rotate_right(1);
+        slots[0] = Some((offset, former_kval));
+    }
+
+    /// Grows a reserved block in place to kval if realloc shrank it from at least kval and the
+    /// upper halves it split off are all still free. The block keeps its pointer, so nothing is
+    /// copied. Whatever the outcome the block is dropped from the hold cache, and it is held again
+    /// under its new kval if it is still smaller than before the shrink.
+    ///
+    /// # Arguments
+    /// * avail - The reserved block to grow
+    /// * kval - The kval to grow it to
+    ///
+    /// # Returns
+    /// true if the block was grown
+    unsafe fn reclaim_held(&mut self, avail: &mut Avail, kval: usize) -> bool {
+        let offset = self.offset_of(avail as *mut Avail as *mut u8);
+        let slots = &mut self.held[avail.kval];
+        let Some(slot) = slots
+            .iter()
+            .position(|slot| slot.is_some_and(|(held, _)| held == offset))
+        else {
+            return false;
+        };
+        let Some((_, former_kval)) = slots[slot].take() else {
+            return false;
+        };
+        slots[slot..].rotate_left(1);
+        if former_kval < kval || self.merged_offset(avail, kval) != Some(offset) {
+            return false;
+        }
+        self.merge_up_to(avail, kval);
+        if former_kval > kval {
+            self.hold(offset, kval, former_kval);
+        }
+        true
+    }
+
+    /// Follows the chain of buddies of a block up to kval without merging them.
+    ///
+    /// # Arguments
+    /// * avail - The block to grow
+    /// * kval - The kval to grow it to
+    ///
+    /// # Returns
+    /// the offset the merged block would start at, or None if the buddies are not free up to kval
+    fn merged_offset(&self, avail: &Avail, kval: usize) -> Option<usize> {
+        if kval > self.kval_m {
+            return None;
+        }
+        let mut offset = self.offset_of(avail as *const Avail as *mut u8);
+        for k in avail.kval..kval {
+            let buddy = self.ptr_at(offset ^ (1 << k));
+            if self.headerless_kval(buddy).is_some() {
+                return None;
+            }
+            let buddy = unsafe { &*(buddy as *const Avail) };
+            if buddy.state() != BLOCK_AVAIL || buddy.kval != k {
+                return None;
+            }
+            offset &= !(1 << k);
+        }
+        Some(offset)
+    }
+
+    /// Merges a reserved block with its free buddies up to kval, keeping it reserved and keeping
+    /// its generation. The buddies must have been checked with merged_offset. The data is not
+    /// moved, so if a lower buddy was merged it is left where it was.
+    ///
+    /// # Arguments
+    /// * avail - The reserved block to grow
+    /// * kval - The kval to grow it to
+    unsafe fn merge_up_to(&mut self, avail: &mut Avail, kval: usize) {
+        let generation = avail.generation;
+        let mut merged = avail;
+        while merged.kval < kval {
+            let buddy = self.buddy_calc(merged);
+            merged = self.merge_buddy(merged, buddy);
+        }
+        merged.generation = generation;
+        merged.set_state(BLOCK_RESERVED);
+        self.max_single_alloc_kval = self.max_single_alloc_kval.max(kval);
+        self.update_peak();
+    }
 
     /// Frees a block of memory previously allocated by a call to malloc, realloc. This function
     /// should only be used internally as it takes as an argument the reference to the Avail struct,
     /// not the pointer to user memory.
     unsafe fn free_avail(&mut self, avail: &mut Avail) {
+        // With uaf_detect the body already holds the freed marker, which must be left intact
+        #[cfg(all(feature = "debug_poison", not(feature = "uaf_detect")))]
+        self.poison(avail, POISON_FREED);
         let mut avail = avail;
         let mut buddy_o = self.get_avail_buddy(avail);
-        while buddy_o.is_some() {
-            let buddy = buddy_o.unwrap() as *mut Avail;
-            self.remove_from_avail(&mut *buddy);
-            if (avail as *mut Avail) < buddy {
-                avail.kval += 1;
-            } else {
-                (*buddy).kval += 1;
-                avail = &mut *buddy;
+        while let Some(buddy) = buddy_o {
+            if let Some((min, max)) = self.no_coalesce {
+                if (min..=max).contains(&avail.kval) {
+                    break;
+                }
             }
+            avail = self.merge_buddy(avail, buddy);
             buddy_o = self.get_avail_buddy(avail);
         }
         self.add_to_avail(avail);
+        #[cfg(debug_assertions)]
+        self.debug_check_lists();
+    }
+
+    /// Merges every free block of the given kval whose buddy is free too, one level up.
+    ///
+    /// # Arguments
+    /// * kval - The kval of the free blocks to merge, below kval_m
+    ///
+    /// # Returns
+    /// the number of pairs merged
+    fn merge_pairs(&mut self, kval: usize) -> usize {
+        let mut merges = 0;
+        unsafe {
+            let mut current = self.avail[kval].next;
+            while current != head_link(kval) {
+                let node = self.ptr_at(current) as *mut Avail;
+                let mut next = (*node).next;
+                if let Some(buddy) = self.get_avail_buddy(&*node) {
+                    if next == self.offset_of(buddy as *mut u8) {
+                        next = (*buddy).next;
+                    }
+                    self.remove_from_avail(&mut *node);
+                    let merged = self.merge_buddy(&mut *node, buddy);
+                    self.add_to_avail(merged);
+                    merges += 1;
+                }
+                current = next;
+            }
+        }
+        merges
+    }
+
+    /// Merges a block that is not on an avail list with its free buddy. The buddy is taken off its
+    /// avail list and the merged block is left off the lists for the caller to place.
+    ///
+    /// # Arguments
+    /// * avail - The block to merge, which must not be on an avail list
+    /// * buddy - The free buddy of avail
+    ///
+    /// # Returns
+    /// the merged block, which starts at the lower of the two
+    unsafe fn merge_buddy<'a>(&mut self, avail: &'a mut Avail, buddy: *mut Avail) -> &'a mut Avail {
+        #[cfg(feature = "uaf_detect")]
+        let marked = avail.marker == FREED_MARKER && (*buddy).marker == FREED_MARKER;
+        let absorbed = (avail as *mut Avail).max(buddy);
+        self.remove_from_avail(&mut *buddy);
+        // The upper header is now inside the merged block, and must not pass for a live one if its
+        // pointer is freed again
+        (*absorbed).set_state(BLOCK_UNUSED);
+        let merged = if (avail as *mut Avail) < buddy {
+            avail
+        } else {
+            &mut *buddy
+        };
+        merged.kval += 1;
+        #[cfg(feature = "uaf_detect")]
+        if marked {
+            // The upper header is now part of the merged body so it gets the marker as well
+            (absorbed as *mut u8).write_bytes(FREED_MARKER, size_of::<Avail>());
+        } else {
+            merged.marker = 0;
+        }
+        merged
+    }
+
+    /// Fills the body of a block that is being freed with the freed marker and marks its header so
+    /// that any later write through a stale pointer can be detected.
+    ///
+    /// # Arguments
+    /// * avail - The block being freed
+    #[cfg(feature = "uaf_detect")]
+    unsafe fn mark_freed(&self, avail: &mut Avail) {
+        let body = (self.rederive(avail) as *mut u8).add(size_of::<Avail>());
+        body.write_bytes(FREED_MARKER, (1usize << avail.kval) - size_of::<Avail>());
+        avail.marker = FREED_MARKER;
+    }
+
+    /// Fills the body of a block with a poison byte, leaving the header alone as the avail lists
+    /// live there.
+    ///
+    /// # Arguments
+    /// * avail - The block to poison
+    /// * byte - POISON_FREED or POISON_ALLOCATED
+    #[cfg(feature = "debug_poison")]
+    unsafe fn poison(&self, avail: &Avail, byte: u8) {
+        let body = (self.rederive(avail) as *mut u8).add(size_of::<Avail>());
+        body.write_bytes(byte, (1usize << avail.kval) - size_of::<Avail>());
+    }
+
+    /// Checks that a block taken from the avail lists has not been written to since it was freed,
+    /// and clears its marker so it can be handed out. A block that fails the check stays reserved
+    /// so the corrupted memory is not handed out again.
+    ///
+    /// Blocks only keep the marker until they are split or coalesce with a buddy that was never
+    /// freed, as the marker can not be kept up to date for memory that was never marked.
+    ///
+    /// # Arguments
+    /// * avail - The block taken from the avail lists
+    #[cfg(feature = "uaf_detect")]
+    unsafe fn check_freed(&self, avail: &mut Avail) -> Result<(), BuddyError> {
+        if avail.marker != FREED_MARKER {
+            return Ok(());
+        }
+        let body = (self.rederive(avail) as *const u8).add(size_of::<Avail>());
+        let body = core::slice::from_raw_parts(body, (1usize << avail.kval) - size_of::<Avail>());
+        if body.iter().any(|&b| b != FREED_MARKER) {
+            return Err(BuddyError::CorruptedMemoryPool);
+        }
+        avail.marker = 0;
+        Ok(())
+    }
+
+    /// Walks every avail list and panics if it is not a proper circular list. Each node must link
+    /// back to the node before it, carry the list's kval and be tagged as available, and the walk
+    /// must return to the list head within the number of blocks that could fit in the pool. This
+    /// is only compiled into debug builds and is run after every list mutation.
+    #[cfg(debug_assertions)]
+    fn debug_check_lists(&self) {
+        for kval in 0..=self.kval_m {
+            let head = head_link(kval);
+            let max_blocks = 1usize << (self.kval_m - kval);
+            let mut prev = head;
+            let mut current = self.avail[kval].next;
+            let mut count = 0;
+            while current != head {
+                count += 1;
+                assert!(
+                    count <= max_blocks,
+                    "avail list {kval} does not return to its head"
+                );
+                let a = unsafe { &*(self.ptr_at(current) as *const Avail) };
+                assert_eq!(a.prev, prev, "avail list {kval} has a broken back-link");
+                assert_eq!(
+                    a.state(),
+                    BLOCK_AVAIL,
+                    "avail list {kval} holds an unavailable block"
+                );
+                assert_eq!(
+                    a.kval, kval,
+                    "avail list {kval} holds a block of the wrong size"
+                );
+                prev = current;
+                current = a.next;
+            }
+            assert_eq!(
+                self.avail[kval].prev, prev,
+                "avail list {kval} has a broken tail"
+            );
+        }
+    }
+}
+
+impl<const N: usize> fmt::Debug for BuddyPool<N> {
+    /// Prints the pool's kval, its base address and the number of free blocks on each avail list
+    /// that is not empty. Each walk stops after the most blocks that fit at its level, or at a link
+    /// outside the pool, so a corrupted list is printed with a + after its count instead of being
+    /// followed forever.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "BuddyPool {{ kval_m: {}, base: {:p}, free: {{",
+            self.kval_m, self.base_ptr
+        )?;
+        let mut first = true;
+        for kval in 0..=self.kval_m {
+            let head = head_link(kval);
+            let mut current = self.avail[kval].next;
+            // A list that was never linked is empty rather than broken
+            if current == head || current == UNLINKED {
+                continue;
+            }
+            let max_blocks = 1usize << (self.kval_m - kval);
+            let mut count = 0;
+            let mut truncated = false;
+            while current != head {
+                if count == max_blocks
+                    || current > self.capacity() - size_of::<Avail>()
+                    || !current.is_multiple_of(align_of::<Avail>())
+                {
+                    truncated = true;
+                    break;
+                }
+                count += 1;
+                current = unsafe { (*(self.ptr_at(current) as *const Avail)).next };
+            }
+            let separator = if first { " " } else { ", " };
+            let mark = if truncated { "+" } else { "" };
+            write!(f, "{separator}{kval}: {count}{mark}")?;
+            first = false;
+        }
+        write!(f, " }} }}")
     }
 }
 
-impl Drop for BuddyPool {
+impl<const N: usize> Drop for BuddyPool<N> {
     /// Inverse of buddy_init. Releases all memory allocated by the buddy allocator. This function
     /// will be called automatically when the BuddyPool goes out of scope.
     ///
     /// Notice that this function does not change the value of pool itself, hence it still points to
     /// the same (now invalid) location.
     fn drop(&mut self) {
-        let _ = self.base.flush();
+        // miri can not call msync, and an anonymous mapping has nothing to flush anyway
+        #[cfg(not(miri))]
+        self.base.flush();
+    }
+}
+
+/// Returns the size of an OS page.
+///
+/// # Returns
+/// the page size in bytes
+#[cfg(unix)]
+fn page_size() -> usize {
+    unsafe { libc::sysconf(libc::_SC_PAGESIZE) as usize }
+}
+
+/// Returns the size of an OS page, assumed to be 4KiB where it can not be queried.
+///
+/// # Returns
+/// the page size in bytes
+#[cfg(not(unix))]
+fn page_size() -> usize {
+    4096
+}
+
+/// Returns the kval of the pool new makes for a requested size, rounding the size up to a power of
+/// two between the minimum and maximum pool sizes and using the default size for 0.
+///
+/// # Arguments
+/// * size - The requested size of the pool in bytes
+///
+/// # Returns
+/// the kval of the pool
+fn pool_kval(size: usize) -> usize {
+    match size {
+        0 => DEFAULT_K,
+        _ => b_to_k(size).clamp(MIN_K, MAX_K - 1),
     }
 }
 
+/// Sets the calling thread's errno, so that C callers see the POSIX error alongside the returned
+/// BuddyError.
+///
+/// # Arguments
+/// * code - The POSIX error code
+#[cfg(feature = "errno")]
+fn set_errno(code: i32) {
+    errno::set_errno(errno::Errno(code));
+}
+
+/// Leaves errno alone, as a Rust caller gets the error from the returned BuddyError and a global
+/// errno is of little use without the standard library's threads.
+///
+/// # Arguments
+/// * code - The POSIX error code, unused
+#[cfg(not(feature = "errno"))]
+fn set_errno(_code: i32) {}
+
+/// Scrambles a value so that each input bit affects every output bit, using the splitmix64
+/// finalizer. Sums of mixed values make an order independent hash of a set.
+///
+/// # Arguments
+/// * value - The value to scramble
+///
+/// # Returns
+/// the scrambled value
+fn mix(mut value: u64) -> u64 {
+    value = (value ^ (value >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    value = (value ^ (value >> 27)).wrapping_mul(0x94d049bb133111eb);
+    value ^ (value >> 31)
+}
+
 /// Converts bytes to its equivalent K value defined as bytes <= 2^K
 ///
 /// # Arguments
@@ -0,0 +1,86 @@
+use crate::{Avail, MAX_K};
+
+/// The link of a block that is not on an avail list. Links at or below it are offsets from the
+/// pool base, and the links above it name the list heads, see head_link.
+pub(crate) const UNLINKED: usize = usize::MAX - MAX_K;
+
+/// Returns the link that refers to the head of an avail list. The heads live in the pool struct
+/// rather than the mapping, so they are named by their kval instead of an offset.
+///
+/// # Arguments
+/// * kval - The kval of the list
+///
+/// # Returns
+/// the link of the list head
+pub(crate) const fn head_link(kval: usize) -> usize {
+    usize::MAX - kval
+}
+
+/// Finds the avail list node a link refers to, which is either a list head or the header of a
+/// free block.
+///
+/// # Safety
+/// An offset link must point at a header inside the mapping that starts at base.
+///
+/// # Arguments
+/// * heads - The list heads, indexed by kval
+/// * base - The start of the mapping that offset links are taken from
+/// * link - The link of the node, a list head or an offset from base
+///
+/// # Returns
+/// the node the link refers to
+pub(crate) unsafe fn node_mut(heads: &mut [Avail], base: *mut u8, link: usize) -> &mut Avail {
+    if link > UNLINKED {
+        &mut heads[usize::MAX - link]
+    } else {
+        &mut *(base.add(link) as *mut Avail)
+    }
+}
+
+/// Links a block in at the tail of the list for its kval. Only the links are written, the
+/// block's tag and the pool's counts are left to the caller.
+///
+/// # Safety
+/// avail must be a header inside the mapping that starts at base and not on any list, and every
+/// block on the list must be as well.
+///
+/// # Arguments
+/// * heads - The list heads, indexed by kval
+/// * base - The start of the mapping the block is in
+/// * avail - The block to link in
+pub(crate) unsafe fn insert(heads: &mut [Avail], base: *mut u8, avail: &mut Avail) {
+    let kval = avail.kval;
+    let link = (avail as *mut Avail).addr() - base.addr();
+    avail.prev = heads[kval].prev;
+    avail.next = head_link(kval);
+    node_mut(heads, base, avail.prev).next = link;
+    heads[kval].prev = link;
+}
+
+/// Unlinks a block from the list it is on and marks its links as unlinked.
+///
+/// # Safety
+/// avail must be on a list of heads whose blocks are all inside the mapping that starts at base.
+///
+/// # Arguments
+/// * heads - The list heads, indexed by kval
+/// * base - The start of the mapping the block is in
+/// * avail - The block to unlink
+pub(crate) unsafe fn remove(heads: &mut [Avail], base: *mut u8, avail: &mut Avail) {
+    node_mut(heads, base, avail.next).prev = avail.prev;
+    node_mut(heads, base, avail.prev).next = avail.next;
+    avail.next = UNLINKED;
+    avail.prev = UNLINKED;
+}
+
+/// Finds the offset of a block's buddy, the other half of the block of the next kval up.
+///
+/// # Arguments
+/// * offset - The offset of the block from the pool base
+/// * kval - The kval of the block
+///
+/// # Returns
+/// the offset of the buddy from the pool base
+pub(crate) const fn buddy_offset(offset: usize, kval: usize) -> usize {
+    offset ^ (1usize << kval)
+}
@@ -0,0 +1,66 @@
+use crate::thread_safe::ThreadSafeBuddyPool;
+use std::sync::{Arc, Condvar, Mutex, PoisonError};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+/// Starts a thread that runs auto_maintain on a shared pool every interval, so that coalescing and
+/// trimming happen off the allocation path. The pool is locked for each pass, like any other call
+/// on it.
+///
+/// # Arguments
+/// * pool - The pool to maintain
+/// * interval - The time to wait between two passes
+///
+/// # Returns
+/// a handle that stops the thread when it is stopped or dropped
+pub fn spawn_maintainer(pool: Arc<ThreadSafeBuddyPool>, interval: Duration) -> MaintainerHandle {
+    let stop = Arc::new((Mutex::new(false), Condvar::new()));
+    let signal = Arc::clone(&stop);
+    let thread = thread::spawn(move || {
+        let (stopped, wake) = &*signal;
+        let mut stopped = stopped.lock().unwrap_or_else(PoisonError::into_inner);
+        while !*stopped {
+            pool.lock().auto_maintain();
+            // Waiting on the condvar rather than sleeping lets stop end the wait right away
+            stopped = wake
+                .wait_timeout(stopped, interval)
+                .unwrap_or_else(PoisonError::into_inner)
+                .0;
+        }
+    });
+    MaintainerHandle {
+        stop,
+        thread: Some(thread),
+    }
+}
+
+/// The handle of a thread started by spawn_maintainer. Dropping the handle stops the thread.
+pub struct MaintainerHandle {
+    stop: Arc<(Mutex<bool>, Condvar)>, // The stop flag, and the condvar the thread waits on
+    thread: Option<JoinHandle<()>>,    // The maintainer thread, None once joined
+}
+
+impl MaintainerHandle {
+    /// Stops the maintainer thread and waits for it to finish its current pass.
+    pub fn stop(mut self) {
+        self.join();
+    }
+
+    /// Signals the thread to stop and joins it, if that was not done already.
+    fn join(&mut self) {
+        let Some(thread) = self.thread.take() else {
+            return;
+        };
+        let (stopped, wake) = &*self.stop;
+        *stopped.lock().unwrap_or_else(PoisonError::into_inner) = true;
+        wake.notify_one();
+        // A pass that panicked has already released the pool, so there is nothing to report
+        let _ = thread.join();
+    }
+}
+
+impl Drop for MaintainerHandle {
+    fn drop(&mut self) {
+        self.join();
+    }
+}
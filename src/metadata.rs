@@ -0,0 +1,114 @@
+use crate::buddy_error::BuddyError;
+use crate::{set_errno, ENOMEM};
+use core::slice;
+use memmap2::MmapMut;
+
+/// The metadata of a reserved block that is kept out of band instead of in a header.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) struct Metadata {
+    pub(crate) kval: u8, // The kval of the block, 0 for an entry without a block
+    pub(crate) generation: u16, // The allocation count at the time the block was allocated
+}
+
+/// A table of block metadata kept in its own mapping, away from the pool's memory. There is one
+/// entry for every granule of the pool, indexed by the offset of the block that starts there.
+pub(crate) struct MetadataTable {
+    entries: MmapMut, // The Metadata entries, one per granule
+    granule: usize,   // The size of the smallest block in bytes
+}
+
+impl MetadataTable {
+    /// Create an empty table for a pool of the given length
+    ///
+    /// # Arguments
+    /// * pool_len - The length of the pool in bytes
+    /// * granule - The size of the smallest block in bytes
+    ///
+    /// # Returns
+    /// a new MetadataTable, or NoMemory if the table could not be mapped
+    pub(crate) fn new(pool_len: usize, granule: usize) -> Result<MetadataTable, BuddyError> {
+        Ok(MetadataTable {
+            entries: Self::map(pool_len / granule)?,
+            granule,
+        })
+    }
+
+    /// Returns the metadata of the block starting at offset.
+    ///
+    /// # Arguments
+    /// * offset - The offset of the block from the pool base
+    ///
+    /// # Returns
+    /// the block's metadata, or None if no out of band block starts at offset
+    pub(crate) fn get(&self, offset: usize) -> Option<Metadata> {
+        if !offset.is_multiple_of(self.granule) {
+            return None;
+        }
+        self.entries()
+            .get(offset / self.granule)
+            .copied()
+            .filter(|entry| entry.kval != 0)
+    }
+
+    /// Records or clears the metadata of the block starting at offset.
+    ///
+    /// # Arguments
+    /// * offset - The offset of the block from the pool base, which must be a multiple of the
+    ///   granule
+    /// * entry - The block's metadata, or None to clear the entry
+    pub(crate) fn set(&mut self, offset: usize, entry: Option<Metadata>) {
+        let index = offset / self.granule;
+        self.entries_mut()[index] = entry.unwrap_or(Metadata {
+            kval: 0,
+            generation: 0,
+        });
+    }
+
+    /// Clears every entry in the table.
+    pub(crate) fn clear(&mut self) {
+        self.entries.fill(0);
+    }
+
+    /// Resizes the table for a pool of a new length, keeping the existing entries.
+    ///
+    /// # Arguments
+    /// * pool_len - The new length of the pool in bytes
+    ///
+    /// # Returns
+    /// Ok, or NoMemory if the new table could not be mapped
+    pub(crate) fn resize(&mut self, pool_len: usize) -> Result<(), BuddyError> {
+        let mut entries = Self::map(pool_len / self.granule)?;
+        let len = entries.len().min(self.entries.len());
+        entries[..len].copy_from_slice(&self.entries[..len]);
+        self.entries = entries;
+        Ok(())
+    }
+
+    /// Maps zeroed memory for a number of entries.
+    ///
+    /// # Arguments
+    /// * count - The number of entries
+    ///
+    /// # Returns
+    /// the mapping, or NoMemory if it could not be created
+    fn map(count: usize) -> Result<MmapMut, BuddyError> {
+        MmapMut::map_anon(count * size_of::<Metadata>()).map_err(|_| {
+            set_errno(ENOMEM);
+            BuddyError::NoMemory
+        })
+    }
+
+    /// Views the mapping as entries. The mapping is page aligned and zeroed, and all zeroes is the
+    /// empty entry.
+    fn entries(&self) -> &[Metadata] {
+        let count = self.entries.len() / size_of::<Metadata>();
+        unsafe { slice::from_raw_parts(self.entries.as_ptr() as *const Metadata, count) }
+    }
+
+    /// Views the mapping as mutable entries.
+    fn entries_mut(&mut self) -> &mut [Metadata] {
+        let count = self.entries.len() / size_of::<Metadata>();
+        unsafe { slice::from_raw_parts_mut(self.entries.as_mut_ptr() as *mut Metadata, count) }
+    }
+}
@@ -0,0 +1,145 @@
+use crate::buddy_error::BuddyError;
+use crate::{b_to_k, set_errno, BuddyPool, DEFAULT_K, ENOMEM, MAX_K, MIN_K, ZERO_SIZE_PTR};
+use core::array;
+
+/// The most segments a pool can be split into, one per power of two between MIN_K and MAX_K.
+const MAX_SEGMENTS: usize = MAX_K - MIN_K;
+
+/// A memory pool made of several power-of-two buddy pools so that the total capacity does not have
+/// to be a power of two. Allocations are routed to the first segment with room, largest first, and
+/// free and realloc are dispatched to the segment that contains the pointer.
+pub struct MultiBuddyPool {
+    pub(crate) segments: [Option<BuddyPool>; MAX_SEGMENTS], // The segments, largest first
+}
+
+impl MultiBuddyPool {
+    /// Create a new memory pool covering size bytes. The size is rounded up to a multiple of the
+    /// minimum pool size and split into one buddy pool per set bit, so 3 GiB becomes a 2 GiB
+    /// segment and a 1 GiB segment. If a 0 is passed as an argument then a single segment of the
+    /// default size is created.
+    ///
+    /// For the pool to be usable, the caller must call the init function to initialize every
+    /// segment.
+    ///
+    /// # Arguments
+    /// * size - The size of the pool in bytes
+    pub fn new(size: usize) -> Result<MultiBuddyPool, BuddyError> {
+        let min_size = 1usize << MIN_K;
+        let size = match size {
+            0 => 1usize << DEFAULT_K,
+            _ => size.div_ceil(min_size).saturating_mul(min_size),
+        };
+        if b_to_k(size.saturating_add(1)) > MAX_K {
+            set_errno(ENOMEM);
+            return Err(BuddyError::NoMemory);
+        }
+
+        let mut pool = MultiBuddyPool {
+            segments: array::from_fn(|_| None),
+        };
+        let mut segment = 0;
+        for k in (MIN_K..MAX_K).rev() {
+            if size & (1usize << k) != 0 {
+                pool.segments[segment] = Some(BuddyPool::new(1usize << k)?);
+                segment += 1;
+            }
+        }
+        Ok(pool)
+    }
+
+    /// Initialize every segment of the pool. This function must be called before any other
+    /// functions for the pool to function, for the same reason as BuddyPool::init.
+    pub fn init(&mut self) {
+        for segment in self.segments.iter_mut().flatten() {
+            segment.init();
+        }
+    }
+
+    /// Allocates a block of size bytes from the first segment that can satisfy it.
+    ///
+    /// # Arguments
+    /// * size - The size of the user requested memory block in bytes
+    ///
+    /// # Returns
+    /// a pointer to the memory block
+    pub fn malloc(&mut self, size: usize) -> Result<*mut u8, BuddyError> {
+        for segment in self.segments.iter_mut().flatten() {
+            if let Ok(ptr) = segment.malloc(size) {
+                return Ok(ptr);
+            }
+        }
+        set_errno(ENOMEM);
+        Err(BuddyError::NoMemory)
+    }
+
+    /// Frees a block of memory previously allocated from this pool by returning it to the segment
+    /// that contains it. Null pointers, ZERO_SIZE_PTR and pointers outside every segment are
+    /// ignored.
+    ///
+    /// # Arguments
+    /// * ptr - Pointer to the memory block to free
+    pub fn free(&mut self, ptr: *mut u8) {
+        if let Some(segment) = self.segment_of(ptr) {
+            segment.free(ptr);
+        }
+    }
+
+    /// Changes the size of the memory block pointed to by ptr with the same semantics as
+    /// BuddyPool::realloc, so null and ZERO_SIZE_PTR allocate a new block. If the segment holding
+    /// the block can not grow it, the block is moved to any other segment with room.
+    ///
+    /// # Arguments
+    /// ptr - Pointer to a memory block
+    /// size - The new size of the memory block
+    ///
+    /// # Returns
+    /// a pointer to the new memory block
+    pub fn realloc(&mut self, ptr: *mut u8, size: usize) -> Result<*mut u8, BuddyError> {
+        if ptr.is_null() || ptr == ZERO_SIZE_PTR {
+            return self.malloc(size);
+        }
+        let segment = self
+            .segment_of(ptr)
+            .ok_or(BuddyError::CorruptedMemoryPool)?;
+        match segment.realloc(ptr, size) {
+            Err(BuddyError::NoMemory | BuddyError::PoolNotEmpty) => {
+                let old_size = segment.usable_size_aligned(ptr);
+                let new_ptr = self.malloc(size)?;
+                unsafe {
+                    new_ptr.copy_from_nonoverlapping(ptr, old_size.min(size));
+                }
+                self.free(ptr);
+                Ok(new_ptr)
+            }
+            result => result,
+        }
+    }
+
+    /// Checks whether a pointer lies within any segment of this pool.
+    ///
+    /// # Arguments
+    /// * ptr - The pointer to check
+    ///
+    /// # Returns
+    /// true if ptr points into one of the segments
+    pub fn contains(&self, ptr: *mut u8) -> bool {
+        self.segments
+            .iter()
+            .flatten()
+            .any(|segment| segment.contains(ptr))
+    }
+
+    /// Finds the segment that contains a pointer.
+    ///
+    /// # Arguments
+    /// * ptr - The pointer to look up
+    ///
+    /// # Returns
+    /// the segment holding ptr, or None if ptr is null or outside every segment
+    fn segment_of(&mut self, ptr: *mut u8) -> Option<&mut BuddyPool> {
+        self.segments
+            .iter_mut()
+            .flatten()
+            .find(|segment| segment.contains(ptr))
+    }
+}
@@ -0,0 +1,59 @@
+#[cfg(target_os = "linux")]
+use libc::{c_int, c_uint, c_ulong};
+
+/// The mbind mode that allocates pages only from the nodes in the mask, from linux/mempolicy.h
+#[cfg(target_os = "linux")]
+const MPOL_BIND: c_int = 2;
+
+/// The mbind flag that moves pages already faulted in onto the bound node
+#[cfg(target_os = "linux")]
+const MPOL_MF_MOVE: c_uint = 1 << 1;
+
+/// The number of nodes the node mask passed to mbind can hold
+#[cfg(target_os = "linux")]
+const MAX_NODES: usize = 1024;
+
+/// Binds the pages of a mapping to a NUMA node, so that they are faulted in on that node and any
+/// already faulted in are moved there.
+///
+/// # Arguments
+/// * ptr - The start of the mapping, which must be page aligned
+/// * len - The length of the mapping in bytes
+/// * node - The NUMA node to bind to
+///
+/// # Returns
+/// true if the pages were bound, false if the node does not exist or the platform has no NUMA
+/// support
+#[cfg(target_os = "linux")]
+pub(crate) fn bind(ptr: *mut u8, len: usize, node: u32) -> bool {
+    let node = node as usize;
+    if node >= MAX_NODES {
+        return false;
+    }
+    let bits = c_ulong::BITS as usize;
+    let mut mask: [c_ulong; MAX_NODES / c_ulong::BITS as usize] =
+        [0; MAX_NODES / c_ulong::BITS as usize];
+    mask[node / bits] |= 1 << (node % bits);
+    // The kernel reads one bit less than maxnode
+    let result = unsafe {
+        libc::syscall(
+            libc::SYS_mbind,
+            ptr,
+            len,
+            MPOL_BIND,
+            mask.as_ptr(),
+            MAX_NODES + 1,
+            MPOL_MF_MOVE,
+        )
+    };
+    result == 0
+}
+
+/// Binding is only supported on Linux, elsewhere the pool stays unbound.
+///
+/// # Returns
+/// false
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn bind(_ptr: *mut u8, _len: usize, _node: u32) -> bool {
+    false
+}
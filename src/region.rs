@@ -0,0 +1,105 @@
+use crate::buddy_error::BuddyError;
+use crate::{set_errno, ENOMEM};
+use memmap2::MmapMut;
+#[cfg(target_os = "linux")]
+use memmap2::RemapOptions;
+#[cfg(unix)]
+use memmap2::UncheckedAdvice;
+
+/// The memory a pool manages. It is either a mapping that the pool owns, or a region that the
+/// caller handed over with BuddyPool::from_raw, which the pool must not flush, remap or unmap.
+pub(crate) enum Region {
+    Mapped(MmapMut),     // A mapping owned by the pool
+    Raw(*mut u8, usize), // The start and length of a region owned by the caller
+}
+
+impl Region {
+    /// Returns a pointer to the start of the region.
+    pub(crate) fn as_ptr(&self) -> *const u8 {
+        match self {
+            Region::Mapped(map) => map.as_ptr(),
+            Region::Raw(ptr, _) => *ptr,
+        }
+    }
+
+    /// Returns a mutable pointer to the start of the region.
+    pub(crate) fn as_mut_ptr(&mut self) -> *mut u8 {
+        match self {
+            Region::Mapped(map) => map.as_mut_ptr(),
+            Region::Raw(ptr, _) => *ptr,
+        }
+    }
+
+    /// Returns the length of the region in bytes.
+    pub(crate) fn len(&self) -> usize {
+        match self {
+            Region::Mapped(map) => map.len(),
+            Region::Raw(_, len) => *len,
+        }
+    }
+
+    /// Flushes a mapping to its backing file, if it has one. A raw region has nothing to flush.
+    pub(crate) fn flush(&self) {
+        if let Region::Mapped(map) = self {
+            let _ = map.flush();
+        }
+    }
+
+    /// Tells the kernel that a range of a mapping is no longer needed, so its pages can be dropped.
+    /// The range reads back as zeroes or as the backing file afterwards. A raw region is left
+    /// alone, as its memory belongs to the caller.
+    ///
+    /// # Arguments
+    /// * offset - The page aligned offset of the range from the start of the region
+    /// * len - The length of the range in bytes
+    #[cfg(unix)]
+    pub(crate) fn discard(&self, offset: usize, len: usize) {
+        if let Region::Mapped(map) = self {
+            // SAFETY: callers only pass ranges inside free blocks, so no live allocation overlaps
+            // the discarded range and nothing can observe its pages turning to zeroes
+            let _ = unsafe { map.unchecked_advise_range(UncheckedAdvice::DontNeed, offset, len) };
+        }
+    }
+
+    /// Resizes a mapping to len bytes, preserving its contents. The mapping may move.
+    ///
+    /// # Arguments
+    /// * len - The new length of the mapping in bytes
+    ///
+    /// # Returns
+    /// Ok, or NoMemory if the mapping could not be resized or the region is not a mapping
+    #[cfg(target_os = "linux")]
+    pub(crate) fn remap(&mut self, len: usize) -> Result<(), BuddyError> {
+        let Region::Mapped(map) = self else {
+            set_errno(ENOMEM);
+            return Err(BuddyError::NoMemory);
+        };
+        unsafe { map.remap(len, RemapOptions::new().may_move(true)) }.map_err(|_| {
+            set_errno(ENOMEM);
+            BuddyError::NoMemory
+        })
+    }
+
+    /// Resizes a mapping to len bytes, preserving its contents. The mapping always moves on
+    /// platforms without mremap.
+    ///
+    /// # Arguments
+    /// * len - The new length of the mapping in bytes
+    ///
+    /// # Returns
+    /// Ok, or NoMemory if the mapping could not be resized or the region is not a mapping
+    #[cfg(not(target_os = "linux"))]
+    pub(crate) fn remap(&mut self, len: usize) -> Result<(), BuddyError> {
+        let Region::Mapped(map) = self else {
+            set_errno(ENOMEM);
+            return Err(BuddyError::NoMemory);
+        };
+        let mut new_map = MmapMut::map_anon(len).map_err(|_| {
+            set_errno(ENOMEM);
+            BuddyError::NoMemory
+        })?;
+        new_map[..map.len()].copy_from_slice(map);
+        *map = new_map;
+        Ok(())
+    }
+}
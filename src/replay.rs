@@ -0,0 +1,189 @@
+use crate::buddy_error::BuddyError;
+use crate::{BuddyPool, ZERO_SIZE_PTR};
+
+/// A recorded pointer. Pointers into the pool are stored as offsets from its base, and null and
+/// ZERO_SIZE_PTR, which are not in any pool, are stored as themselves.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Location {
+    Null,          // A null pointer
+    ZeroSize,      // ZERO_SIZE_PTR, as returned by realloc to a size of 0
+    Offset(usize), // A pointer into the pool, as an offset from its base
+}
+
+impl Location {
+    /// Records where a pointer points in a pool
+    ///
+    /// # Arguments
+    /// * pool - The pool the pointer belongs to
+    /// * ptr - The pointer to record
+    ///
+    /// # Returns
+    /// the location of the pointer
+    fn of(pool: &BuddyPool, ptr: *mut u8) -> Location {
+        if ptr.is_null() {
+            Location::Null
+        } else if ptr == ZERO_SIZE_PTR {
+            Location::ZeroSize
+        } else {
+            Location::Offset(pool.offset_of(ptr))
+        }
+    }
+
+    /// Turns the location back into a pointer in a pool
+    ///
+    /// # Arguments
+    /// * pool - The pool the pointer is replayed against
+    ///
+    /// # Returns
+    /// the pointer the location refers to in the pool
+    fn ptr_in(self, pool: &BuddyPool) -> *mut u8 {
+        match self {
+            Location::Null => core::ptr::null_mut(),
+            Location::ZeroSize => ZERO_SIZE_PTR,
+            Location::Offset(offset) => pool.ptr_at(offset),
+        }
+    }
+}
+
+/// One operation in a recorded allocation pattern. Pointers are stored as locations relative to
+/// the pool so that the log can be replayed against a different pool. A result of None means that
+/// the operation failed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Event {
+    Malloc {
+        size: usize,           // The requested size
+        offset: Option<usize>, // The offset of the returned pointer
+    },
+    Realloc {
+        offset: Location,             // The location of the pointer passed in
+        size: usize,                  // The requested size
+        new_offset: Option<Location>, // The location of the returned pointer
+    },
+    Free {
+        offset: Location, // The location of the freed pointer
+    },
+}
+
+/// Wraps a pool and logs every malloc, realloc and free made through it to a sink, so that a
+/// pattern seen in production can be replayed later with a Player.
+pub struct Recorder<'a, F: FnMut(Event)> {
+    pool: &'a mut BuddyPool, // The pool the operations are made on
+    sink: F,                 // Receives each event as it happens
+}
+
+impl<'a, F: FnMut(Event)> Recorder<'a, F> {
+    /// Create a new recorder around an initialized pool
+    ///
+    /// # Arguments
+    /// * pool - The pool to make the operations on
+    /// * sink - Receives each event as it happens
+    ///
+    /// # Returns
+    /// a new Recorder struct
+    pub fn new(pool: &'a mut BuddyPool, sink: F) -> Recorder<'a, F> {
+        Recorder { pool, sink }
+    }
+
+    /// Calls malloc on the pool and records it
+    ///
+    /// # Arguments
+    /// * size - The size of the user requested memory block in bytes
+    ///
+    /// # Returns
+    /// the result of malloc
+    pub fn malloc(&mut self, size: usize) -> Result<*mut u8, BuddyError> {
+        let result = self.pool.malloc(size);
+        let offset = result.as_ref().ok().map(|&ptr| self.pool.offset_of(ptr));
+        (self.sink)(Event::Malloc { size, offset });
+        result
+    }
+
+    /// Calls realloc on the pool and records it
+    ///
+    /// # Arguments
+    /// * ptr - Pointer to a memory block
+    /// * size - The new size of the memory block
+    ///
+    /// # Returns
+    /// the result of realloc
+    pub fn realloc(&mut self, ptr: *mut u8, size: usize) -> Result<*mut u8, BuddyError> {
+        let offset = Location::of(self.pool, ptr);
+        let result = self.pool.realloc(ptr, size);
+        let new_offset = result
+            .as_ref()
+            .ok()
+            .map(|&ptr| Location::of(self.pool, ptr));
+        (self.sink)(Event::Realloc {
+            offset,
+            size,
+            new_offset,
+        });
+        result
+    }
+
+    /// Calls free on the pool and records it
+    ///
+    /// # Arguments
+    /// * ptr - Pointer to the memory block to free
+    pub fn free(&mut self, ptr: *mut u8) {
+        let offset = Location::of(self.pool, ptr);
+        self.pool.free(ptr);
+        (self.sink)(Event::Free { offset });
+    }
+}
+
+/// Replays a log from a Recorder against a pool and checks that it behaves the same way.
+pub struct Player<'a> {
+    pool: &'a mut BuddyPool, // The pool the log is replayed against
+}
+
+impl<'a> Player<'a> {
+    /// Create a new player around an initialized pool, which should be fresh and the same size as
+    /// the recorded pool for the offsets to match
+    ///
+    /// # Arguments
+    /// * pool - The pool to replay the log against
+    ///
+    /// # Returns
+    /// a new Player struct
+    pub fn new(pool: &'a mut BuddyPool) -> Player<'a> {
+        Player { pool }
+    }
+
+    /// Replays the events in order. After each event the result must match the recorded offset
+    /// and the pool must pass verify.
+    ///
+    /// # Arguments
+    /// * events - The recorded events
+    ///
+    /// # Returns
+    /// Ok if the log was reproduced, otherwise the index of the first event that was not
+    pub fn replay(&mut self, events: impl IntoIterator<Item = Event>) -> Result<(), usize> {
+        for (index, event) in events.into_iter().enumerate() {
+            let reproduced = match event {
+                Event::Malloc { size, offset } => {
+                    let result = self.pool.malloc(size);
+                    result.ok().map(|ptr| self.pool.offset_of(ptr)) == offset
+                }
+                Event::Realloc {
+                    offset,
+                    size,
+                    new_offset,
+                } => {
+                    let ptr = offset.ptr_in(self.pool);
+                    let result = self.pool.realloc(ptr, size);
+                    result.ok().map(|ptr| Location::of(self.pool, ptr)) == new_offset
+                }
+                Event::Free { offset } => {
+                    let ptr = offset.ptr_in(self.pool);
+                    self.pool.free(ptr);
+                    true
+                }
+            };
+            if !reproduced || self.pool.verify().is_err() {
+                return Err(index);
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,124 @@
+use crate::buddy_error::BuddyError;
+use crate::{Avail, BuddyPool};
+
+/// The size of the buddy block a slab is carved from, including its header
+const SLAB_BLOCK_BYTES: usize = 4096;
+
+/// The most cells a slab can have, which is the number of bits in the free bitmap
+const SLAB_MAX_CELLS: usize = 256;
+
+/// Cells are rounded up to a multiple of this, so every cell is as aligned as a malloc pointer
+const SLAB_CELL_ALIGN: usize = 16;
+
+/// Wraps a pool and serves small allocations from fixed-size cells carved out of one buddy
+/// block, so that each of them does not pay for a whole header and a power of two block. The
+/// free cells are tracked in a bitmap. Larger requests, and small ones while the slab is full,
+/// go to the pool as usual. Once every cell is freed the slab's block goes back to the pool.
+pub struct SlabCache<'a> {
+    pool: &'a mut BuddyPool, // The pool the slab and larger blocks come from
+    cell_size: usize,        // The size of each cell in bytes
+    cells: usize,            // The number of cells in the slab
+    slab: Option<usize>,     // The offset of the first cell, if a slab is live
+    used: [u64; SLAB_MAX_CELLS / u64::BITS as usize], // One set bit per allocated cell
+}
+
+impl<'a> SlabCache<'a> {
+    /// Create a new slab cache around an initialized pool. The slab block is only allocated once
+    /// the first small request comes in.
+    ///
+    /// # Arguments
+    /// * pool - The pool to allocate from
+    /// * cell_size - The largest request served from the slab, rounded up to a multiple of 16
+    ///
+    /// # Returns
+    /// a new SlabCache struct
+    pub fn new(pool: &'a mut BuddyPool, cell_size: usize) -> SlabCache<'a> {
+        let cell_size = cell_size.max(1).next_multiple_of(SLAB_CELL_ALIGN);
+        let usable = SLAB_BLOCK_BYTES - size_of::<Avail>();
+        SlabCache {
+            pool,
+            cell_size,
+            cells: (usable / cell_size).min(SLAB_MAX_CELLS),
+            slab: None,
+            used: [0; SLAB_MAX_CELLS / u64::BITS as usize],
+        }
+    }
+
+    /// Allocates size bytes, from a slab cell if size fits in one and otherwise from the pool.
+    ///
+    /// # Arguments
+    /// * size - The size of the user requested memory block in bytes
+    ///
+    /// # Returns
+    /// a pointer to the memory block
+    pub fn malloc(&mut self, size: usize) -> Result<*mut u8, BuddyError> {
+        if size > self.cell_size || self.cells == 0 {
+            return self.pool.malloc(size);
+        }
+        let slab = match self.slab {
+            Some(offset) => offset,
+            None => {
+                let block = self.pool.malloc(self.cells * self.cell_size)?;
+                let offset = self.pool.offset_of(block);
+                self.slab = Some(offset);
+                offset
+            }
+        };
+        let Some(cell) = (0..self.cells).find(|&i| !self.is_used(i)) else {
+            return self.pool.malloc(size);
+        };
+        self.used[cell / 64] |= 1 << (cell % 64);
+        Ok(self.pool.ptr_at(slab + cell * self.cell_size))
+    }
+
+    /// Frees a block from this cache, returning the slab's block to the pool once its last cell
+    /// is freed.
+    ///
+    /// # Arguments
+    /// * ptr - Pointer to the memory block to free
+    pub fn free(&mut self, ptr: *mut u8) {
+        let Some(cell) = self.cell_of(ptr) else {
+            self.pool.free(ptr);
+            return;
+        };
+        self.used[cell / 64] &= !(1 << (cell % 64));
+        if self.used.iter().all(|&word| word == 0) {
+            if let Some(offset) = self.slab.take() {
+                self.pool.free(self.pool.ptr_at(offset));
+            }
+        }
+    }
+
+    /// Returns the number of slab cells in use.
+    ///
+    /// # Returns
+    /// the number of allocated cells
+    pub fn cells_in_use(&self) -> usize {
+        self.used
+            .iter()
+            .map(|word| word.count_ones() as usize)
+            .sum()
+    }
+
+    /// Finds the cell that ptr points to.
+    ///
+    /// # Arguments
+    /// * ptr - Pointer to a memory block
+    ///
+    /// # Returns
+    /// the index of the cell, or None if ptr is not the start of a cell in the slab
+    fn cell_of(&self, ptr: *mut u8) -> Option<usize> {
+        let offset = self.pool.offset_of(ptr).checked_sub(self.slab?)?;
+        let cell = offset / self.cell_size;
+        (offset.is_multiple_of(self.cell_size) && cell < self.cells && self.is_used(cell))
+            .then_some(cell)
+    }
+
+    /// Returns whether a cell is allocated.
+    ///
+    /// # Arguments
+    /// * cell - The index of the cell
+    fn is_used(&self, cell: usize) -> bool {
+        self.used[cell / 64] & (1 << (cell % 64)) != 0
+    }
+}
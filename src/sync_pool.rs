@@ -0,0 +1,216 @@
+use crate::buddy_error::BuddyError;
+#[cfg(feature = "allocator_api")]
+use crate::Avail;
+use crate::BuddyPool;
+#[cfg(feature = "allocator_api")]
+use core::alloc::{AllocError, Allocator, Layout};
+use core::cell::UnsafeCell;
+use core::hint;
+#[cfg(feature = "allocator_api")]
+use core::ptr::{self, NonNull};
+use core::sync::atomic::{AtomicBool, Ordering};
+
+/// A memory pool that can be shared between threads without the standard library. Every operation
+/// takes a spin lock for its whole duration, splitting and merging included, so the avail lists
+/// are never seen half updated. Threads spin while they wait, which suits short operations like
+/// these but wastes time under heavy contention; ThreadSafeBuddyPool sleeps instead when std is
+/// available.
+///
+/// Like BuddyPool, the wrapper must be initialized before it is used.
+pub struct SyncBuddyPool {
+    locked: AtomicBool,          // Whether a thread is using the pool
+    pool: UnsafeCell<BuddyPool>, // The pool, only touched with the lock held
+}
+
+/// A held spin lock, released when the guard is dropped. Releasing on drop rather than after the
+/// locked code returns keeps a panic in that code from leaving the lock taken forever.
+pub(crate) struct SpinGuard<'a> {
+    locked: &'a AtomicBool, // The lock, true while held
+}
+
+impl SpinGuard<'_> {
+    /// Takes a spin lock, spinning until no other thread holds it.
+    ///
+    /// # Arguments
+    /// * locked - The lock to take
+    ///
+    /// # Returns
+    /// a guard that releases the lock when dropped
+    pub(crate) fn lock(locked: &AtomicBool) -> SpinGuard<'_> {
+        while locked
+            .compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            hint::spin_loop();
+        }
+        SpinGuard { locked }
+    }
+}
+
+impl Drop for SpinGuard<'_> {
+    fn drop(&mut self) {
+        self.locked.store(false, Ordering::Release);
+    }
+}
+
+// SAFETY: the pool is only touched with the spin lock held, and the raw pointers in it only point
+// into its own mapping, which is not tied to a thread.
+unsafe impl Send for SyncBuddyPool {}
+unsafe impl Sync for SyncBuddyPool {}
+
+impl SyncBuddyPool {
+    /// Create a new thread-safe pool around a pool from BuddyPool::new or the builder.
+    ///
+    /// For the pool to be usable, the caller must call the init function to initialize the pool.
+    ///
+    /// # Arguments
+    /// * pool - The pool to share
+    ///
+    /// # Returns
+    /// a new SyncBuddyPool struct
+    pub fn new(pool: BuddyPool) -> SyncBuddyPool {
+        SyncBuddyPool {
+            locked: AtomicBool::new(false),
+            pool: UnsafeCell::new(pool),
+        }
+    }
+
+    /// Initialize the pool, see BuddyPool::init
+    pub fn init(&mut self) {
+        self.pool.get_mut().init();
+    }
+
+    /// Runs f with the pool locked, spinning until no other thread holds it. f must not use this
+    /// pool again, as the lock is not reentrant. The lock is released even if f panics.
+    ///
+    /// # Arguments
+    /// * f - The function to run with the pool
+    ///
+    /// # Returns
+    /// the result of f
+    pub fn with_pool<R>(&self, f: impl FnOnce(&mut BuddyPool) -> R) -> R {
+        let _guard = SpinGuard::lock(&self.locked);
+        f(unsafe { &mut *self.pool.get() })
+    }
+
+    /// Locks the pool and calls malloc on it, see BuddyPool::malloc
+    ///
+    /// # Arguments
+    /// * size - The size of the user requested memory block in bytes
+    ///
+    /// # Returns
+    /// a pointer to the memory block
+    pub fn malloc(&self, size: usize) -> Result<*mut u8, BuddyError> {
+        self.with_pool(|pool| pool.malloc(size))
+    }
+
+    /// Locks the pool and calls free on it, see BuddyPool::free
+    ///
+    /// # Arguments
+    /// * ptr - Pointer to the memory block to free
+    pub fn free(&self, ptr: *mut u8) {
+        self.with_pool(|pool| pool.free(ptr))
+    }
+
+    /// Locks the pool and calls realloc on it, see BuddyPool::realloc
+    ///
+    /// # Arguments
+    /// * ptr - Pointer to a memory block
+    /// * size - The new size of the memory block
+    ///
+    /// # Returns
+    /// a pointer to the new memory block
+    pub fn realloc(&self, ptr: *mut u8, size: usize) -> Result<*mut u8, BuddyError> {
+        self.with_pool(|pool| pool.realloc(ptr, size))
+    }
+
+    /// Moves or resizes a block for Allocator::grow and Allocator::shrink. realloc only keeps
+    /// malloc's natural alignment, so blocks with a larger alignment and zero sized blocks, which
+    /// never came from the pool, are moved by hand instead.
+    ///
+    /// # Arguments
+    /// * ptr - Pointer to the block, as returned by allocate
+    /// * old_layout - The layout the block was allocated with
+    /// * new_layout - The layout the block must have afterwards
+    ///
+    /// # Returns
+    /// the resized block, sized to its usable length
+    #[cfg(feature = "allocator_api")]
+    unsafe fn resize(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: Layout,
+        new_layout: Layout,
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        let natural = old_layout.align().max(new_layout.align()) <= size_of::<Avail>();
+        if natural && old_layout.size() != 0 && new_layout.size() != 0 {
+            return self.with_pool(|pool| {
+                let new_ptr = pool
+                    .realloc(ptr.as_ptr(), new_layout.size())
+                    .map_err(|_| AllocError)?;
+                let usable = pool.usable_size(new_ptr);
+                Ok(NonNull::slice_from_raw_parts(
+                    NonNull::new(new_ptr).ok_or(AllocError)?,
+                    usable,
+                ))
+            });
+        }
+        let pool = self;
+        let new_block = pool.allocate(new_layout)?;
+        ptr::copy_nonoverlapping(
+            ptr.as_ptr(),
+            new_block.cast::<u8>().as_ptr(),
+            old_layout.size().min(new_layout.size()),
+        );
+        pool.deallocate(ptr, old_layout);
+        Ok(new_block)
+    }
+}
+
+/// Lets containers such as Vec and Box allocate from a shared pool on nightly. The slices handed
+/// out cover the whole usable region of their blocks, so containers can grow into the slack left
+/// by rounding up to a power of two without reallocating.
+#[cfg(feature = "allocator_api")]
+unsafe impl Allocator for &SyncBuddyPool {
+    fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        if layout.size() == 0 {
+            // Zero sized blocks take no memory, any aligned pointer will do
+            let dangling = NonNull::new(ptr::without_provenance_mut(layout.align())).unwrap();
+            return Ok(NonNull::slice_from_raw_parts(dangling, 0));
+        }
+        self.with_pool(|pool| {
+            let ptr = pool
+                .malloc_aligned(layout.size(), layout.align())
+                .map_err(|_| AllocError)?;
+            let usable = pool.usable_size_aligned(ptr);
+            Ok(NonNull::slice_from_raw_parts(
+                NonNull::new(ptr).ok_or(AllocError)?,
+                usable,
+            ))
+        })
+    }
+
+    unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+        if layout.size() != 0 {
+            self.free(ptr.as_ptr());
+        }
+    }
+
+    unsafe fn grow(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: Layout,
+        new_layout: Layout,
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        self.resize(ptr, old_layout, new_layout)
+    }
+
+    unsafe fn shrink(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: Layout,
+        new_layout: Layout,
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        self.resize(ptr, old_layout, new_layout)
+    }
+}
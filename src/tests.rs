@@ -1,7 +1,16 @@
 //tests
 #[cfg(test)]
+#[allow(clippy::module_inception)]
 mod tests {
+    extern crate std;
+
     use crate::*;
+    #[cfg(feature = "errno")]
+    use errno::errno;
+    use proptest::prelude::*;
+    #[cfg(feature = "allocator_api")]
+    use std::boxed::Box;
+    use std::vec::Vec;
 
     const AVAIL_SIZE: usize = size_of::<Avail>();
 
@@ -53,9 +62,9 @@ mod tests {
         //Make sure correct kval was allocated
         let min_kval = b_to_k(1 + AVAIL_SIZE);
         for k in min_kval..pool.kval_m {
-            assert_eq!(get_size_and_validate(&pool.avail[k]), 1);
+            assert_eq!(get_size_and_validate(&pool, k), 1);
         }
-        assert_eq!(get_size_and_validate(&pool.avail[pool.kval_m]), 0);
+        assert_eq!(get_size_and_validate(&pool, pool.kval_m), 0);
 
         // Check that memory is usable
         unsafe {
@@ -77,10 +86,10 @@ mod tests {
     }
 
     /**
-    * Tests the allocation of one massive block that should consume the entire memory
-    * pool and makes sure that after the pool is empty we correctly fail subsequent
-    calls.
-    */
+     * Tests the allocation of one massive block that should consume the entire memory
+     * pool and makes sure that after the pool is empty we correctly fail subsequent
+     * calls.
+     */
     #[test]
     fn test_buddy_malloc_one_large() {
         let bytes = (1u64 << MIN_K) as usize;
@@ -94,7 +103,7 @@ mod tests {
         unsafe {
             let tmp = &*(mem.offset(-(AVAIL_SIZE as isize)) as *mut Avail);
             assert_eq!(tmp.kval, MIN_K);
-            assert_eq!(tmp.tag, BLOCK_RESERVED);
+            assert_eq!(tmp.state(), BLOCK_RESERVED);
         }
         check_buddy_pool_empty(&pool);
         //Verify that a call on an empty pool fails as expected
@@ -121,8 +130,8 @@ mod tests {
             let avail2 = &*((mem2 as *mut u8).offset(-(AVAIL_SIZE as isize)) as *mut Avail);
             assert_eq!(avail1.kval, mem1_kval);
             assert_eq!(avail2.kval, mem2_kval);
-            assert_eq!(avail1.tag, BLOCK_RESERVED);
-            assert_eq!(avail2.tag, BLOCK_RESERVED);
+            assert_eq!(avail1.state(), BLOCK_RESERVED);
+            assert_eq!(avail2.state(), BLOCK_RESERVED);
         }
         pool.free(mem1);
         pool.free(mem2 as *mut u8);
@@ -135,18 +144,18 @@ mod tests {
         let mut pool = BuddyPool::new((1u64 << MIN_K) as usize).unwrap();
         pool.init();
         let mut mems: [*mut u8; 100] = [ptr::null_mut(); 100];
-        for i in 0..100 {
-            mems[i] = pool.malloc(i).unwrap();
+        for (i, mem) in mems.iter_mut().enumerate() {
+            *mem = pool.malloc(i).unwrap();
             let kval = b_to_k(i + AVAIL_SIZE);
             unsafe {
-                let avail = &*(mems[i].offset(-(AVAIL_SIZE as isize)) as *mut Avail);
+                let avail = &*(mem.offset(-(AVAIL_SIZE as isize)) as *mut Avail);
                 assert_eq!(avail.kval, kval);
-                assert_eq!(avail.tag, BLOCK_RESERVED);
+                assert_eq!(avail.state(), BLOCK_RESERVED);
             }
         }
         //Check to make sure that all pointers are unique
         for i in 0..100 {
-            assert_eq!(mems[i+1..100].contains(&mems[i]), false)
+            assert!(!mems[i + 1..100].contains(&mems[i]))
         }
         for mem in mems {
             pool.free(mem);
@@ -173,13 +182,13 @@ mod tests {
         unsafe {
             let avail = &*(mem.offset(-(AVAIL_SIZE as isize)) as *mut Avail);
             assert_eq!(avail.kval, b_to_k(256 + AVAIL_SIZE));
-            assert_eq!(avail.tag, BLOCK_RESERVED);
+            assert_eq!(avail.state(), BLOCK_RESERVED);
         }
         let mem2 = pool.realloc(mem, 8).unwrap();
         unsafe {
             let avail = &*(mem2.offset(-(AVAIL_SIZE as isize)) as *mut Avail);
             assert_eq!(avail.kval, b_to_k(8 + AVAIL_SIZE));
-            assert_eq!(avail.tag, BLOCK_RESERVED);
+            assert_eq!(avail.state(), BLOCK_RESERVED);
         }
         pool.free(mem2);
         check_buddy_pool_full(&pool);
@@ -195,7 +204,7 @@ mod tests {
         unsafe {
             let avail = &*(mem.offset(-(AVAIL_SIZE as isize)) as *mut Avail);
             assert_eq!(avail.kval, b_to_k(16 + AVAIL_SIZE));
-            assert_eq!(avail.tag, BLOCK_RESERVED);
+            assert_eq!(avail.state(), BLOCK_RESERVED);
         }
 
         let m = unsafe { mem.as_mut().unwrap() };
@@ -205,16 +214,50 @@ mod tests {
         unsafe {
             let avail = &*(mem2.offset(-(AVAIL_SIZE as isize)) as *mut Avail);
             assert_eq!(avail.kval, b_to_k(128 + AVAIL_SIZE));
-            assert_eq!(avail.tag, BLOCK_RESERVED);
+            assert_eq!(avail.state(), BLOCK_RESERVED);
         }
 
-        let m = unsafe { mem.as_mut().unwrap() };
+        let m = unsafe { mem2.as_mut().unwrap() };
         assert_eq!(*m, 123);
 
         pool.free(mem2);
         check_buddy_pool_full(&pool);
     }
 
+    /// Tests that realloc_copy only copies the requested prefix when the block moves
+    #[test]
+    fn test_realloc_copy() {
+        let mut pool = BuddyPool::new((1u64 << MIN_K) as usize).unwrap();
+        pool.init();
+        let mem = pool.malloc(256).unwrap();
+        // Keep the buddy reserved so the block can not grow in place
+        let guard = pool.malloc(256).unwrap();
+        unsafe {
+            mem.write_bytes(0xAA, 256);
+        }
+        // The new block comes from untouched pages, so anything not copied reads as zero, or as
+        // the allocated poison when debug_poison is on
+        #[cfg(not(feature = "debug_poison"))]
+        let untouched = 0;
+        #[cfg(feature = "debug_poison")]
+        let untouched = POISON_ALLOCATED;
+        let mem2 = pool.realloc_copy(mem, 1024, 16).unwrap();
+        assert_ne!(mem, mem2);
+        unsafe {
+            let avail = &*(mem2.offset(-(AVAIL_SIZE as isize)) as *mut Avail);
+            assert_eq!(avail.kval, b_to_k(1024 + AVAIL_SIZE));
+            for i in 0..16 {
+                assert_eq!(*mem2.add(i), 0xAA);
+            }
+            for i in 16..256 {
+                assert_eq!(*mem2.add(i), untouched);
+            }
+        }
+        pool.free(mem2);
+        pool.free(guard);
+        check_buddy_pool_full(&pool);
+    }
+
     /// Tests that a call to realloc which results in the same kval is successful
     #[test]
     fn test_realloc_same() {
@@ -224,19 +267,19 @@ mod tests {
         unsafe {
             let avail = &*(mem.offset(-(AVAIL_SIZE as isize)) as *mut Avail);
             assert_eq!(avail.kval, b_to_k(128 + AVAIL_SIZE));
-            assert_eq!(avail.tag, BLOCK_RESERVED);
+            assert_eq!(avail.state(), BLOCK_RESERVED);
         }
         let mem2 = pool.realloc(mem, 128).unwrap();
         unsafe {
             let avail = &*(mem2.offset(-(AVAIL_SIZE as isize)) as *mut Avail);
             assert_eq!(avail.kval, b_to_k(128 + AVAIL_SIZE));
-            assert_eq!(avail.tag, BLOCK_RESERVED);
+            assert_eq!(avail.state(), BLOCK_RESERVED);
         }
         let mem3 = pool.realloc(mem2, 129).unwrap();
         unsafe {
             let avail = &*(mem3.offset(-(AVAIL_SIZE as isize)) as *mut Avail);
             assert_eq!(avail.kval, b_to_k(128 + AVAIL_SIZE));
-            assert_eq!(avail.tag, BLOCK_RESERVED);
+            assert_eq!(avail.state(), BLOCK_RESERVED);
         }
         pool.free(mem2);
         check_buddy_pool_full(&pool);
@@ -251,85 +294,3587 @@ mod tests {
         unsafe {
             let avail = &*(mem.offset(-(AVAIL_SIZE as isize)) as *mut Avail);
             assert_eq!(avail.kval, b_to_k(128 + AVAIL_SIZE));
-            assert_eq!(avail.tag, BLOCK_RESERVED);
+            assert_eq!(avail.state(), BLOCK_RESERVED);
         }
         pool.free(mem);
         check_buddy_pool_full(&pool);
     }
 
-    /// Tests that allocating a block larger than the pool size fails and sets errno to ENOMEM
+    /// Tests that realloc only grows the pool past its capacity once auto grow is enabled, and that
+    /// the contents of the block survive the pool being remapped
     #[test]
-    fn test_alloc_too_large() {
+    fn test_realloc_auto_grow() {
+        let size = (1u64 << MIN_K) as usize;
+        let mut pool = BuddyPool::new(size).unwrap();
+        pool.init();
+        let mem = pool.malloc(16).unwrap();
+        unsafe {
+            *mem = 42;
+        }
+        assert_eq!(pool.realloc(mem, size), Err(BuddyError::NoMemory));
+
+        pool.set_auto_grow(true);
+        let mem2 = pool.realloc(mem, size).unwrap();
+        assert!(pool.kval_m > MIN_K);
+        unsafe {
+            let avail = &*(mem2.offset(-(AVAIL_SIZE as isize)) as *mut Avail);
+            assert_eq!(avail.kval, b_to_k(size + AVAIL_SIZE));
+            assert_eq!(avail.state(), BLOCK_RESERVED);
+            assert_eq!(*mem2, 42);
+        }
+        pool.free(mem2);
+        check_buddy_pool_full(&pool);
+    }
+
+    /// Tests that the usable size of an aligned allocation is measured from the aligned pointer to
+    /// the end of its block rather than from the header
+    #[test]
+    fn test_usable_size_aligned() {
         let mut pool = BuddyPool::new((1u64 << MIN_K) as usize).unwrap();
         pool.init();
-        let mem = pool.malloc(1 << (pool.kval_m + 1));
-        assert!(mem.is_err());
-        assert_eq!(mem, Err(BuddyError::NoMemory));
-        assert_eq!(errno(), ENOMEM);
+        let mem = pool.malloc_aligned(100, 256).unwrap();
+        assert_eq!(mem.addr() % 256, 0);
+        let avail = unsafe { &*pool.header_of(mem) };
+        assert_eq!(avail.state(), BLOCK_RESERVED);
+        let end = (avail as *const Avail).addr() + (1usize << avail.kval);
+        assert_eq!(pool.usable_size_aligned(mem), end - mem.addr());
+        assert!(pool.usable_size_aligned(mem) >= 100);
+        pool.free(mem);
         check_buddy_pool_full(&pool);
     }
 
-    /// A test which fails if the pool has any available blocks
-    ///
-    /// # Arguments
-    /// *pool - The buddy pool to check
-    fn check_buddy_pool_empty(pool: &BuddyPool) {
-        for i in 0..=pool.kval_m {
-            assert_eq!(pool.avail[i].kval, i);
-            assert_eq!(get_size_and_validate(&pool.avail[i]), 0);
+    /// Tests that the debug list check holds through a mix of allocations and frees that split and
+    /// coalesce blocks
+    #[test]
+    #[cfg(debug_assertions)]
+    fn test_debug_check_lists() {
+        let mut pool = BuddyPool::new((1u64 << MIN_K) as usize).unwrap();
+        pool.init();
+        pool.debug_check_lists();
+        let mem1 = pool.malloc(1).unwrap();
+        let mem2 = pool.malloc(1000).unwrap();
+        let mem3 = pool.malloc(50).unwrap();
+        pool.debug_check_lists();
+        pool.free(mem2);
+        pool.free(mem1);
+        pool.debug_check_lists();
+        pool.free(mem3);
+        pool.debug_check_lists();
+        check_buddy_pool_full(&pool);
+    }
+
+    /// Tests that the debug list check catches a broken back-link at the next list mutation
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "broken back-link")]
+    fn test_debug_check_lists_corrupted() {
+        let mut pool = BuddyPool::new((1u64 << MIN_K) as usize).unwrap();
+        pool.init();
+        let mem1 = pool.malloc(1).unwrap();
+        let mem2 = pool.malloc(1000).unwrap();
+        // Point the back-link of a free block at itself, as a buggy list splice would
+        let k = b_to_k(1 + AVAIL_SIZE);
+        let free_block = pool.avail[k].next;
+        unsafe {
+            (*(pool.ptr_at(free_block) as *mut Avail)).prev = free_block;
         }
+        pool.free(mem2);
+        pool.free(mem1);
     }
 
-    /// A test which fails if the pool has any blocks that are not available
-    ///
-    /// # Arguments
-    /// *pool - The buddy pool to check
-    fn check_buddy_pool_full(pool: &BuddyPool) {
-        //A full pool should have all values 0-(kval-1) as empty
-        for i in 0..pool.kval_m {
-            assert_eq!(pool.avail[i].kval, i);
-            assert_eq!(get_size_and_validate(&pool.avail[i]), 0);
+    /// Tests that malloc_no_split hands out a larger free block whole instead of splitting it
+    #[test]
+    fn test_malloc_no_split() {
+        let mut pool = BuddyPool::new((1u64 << MIN_K) as usize).unwrap();
+        pool.init();
+        let mem = pool.malloc_no_split(1).unwrap();
+        unsafe {
+            let avail = &*(mem.offset(-(AVAIL_SIZE as isize)) as *mut Avail);
+            assert_eq!(avail.kval, pool.kval_m);
+            assert_eq!(avail.state(), BLOCK_RESERVED);
         }
-        //The avail array at kval should have the base block
-        assert_eq!(pool.avail[pool.kval_m].kval, pool.kval_m);
-        assert_eq!(get_size_and_validate(&pool.avail[pool.kval_m]), 1);
-        assert_eq!(pool.avail[pool.kval_m].next, pool.avail[pool.kval_m].prev);
-        //Check to make sure the base address points to the starting pool
-        //If this fails either buddy_init is wrong or we have corrupted the
-        //buddy_pool struct.
+        check_buddy_pool_empty(&pool);
+        assert_eq!(pool.malloc_no_split(1), Err(BuddyError::NoMemory));
+        assert_eq!(pool.failure_count(), 1);
+        pool.free(mem);
+        check_buddy_pool_full(&pool);
+
+        // With out of band metadata the whole block is handed out headerless, like malloc does
+        let mut pool = BuddyPool::builder()
+            .size((1u64 << MIN_K) as usize)
+            .out_of_band_metadata(true)
+            .build()
+            .unwrap();
+        pool.init();
+        let mem = pool.malloc_no_split(1).unwrap();
+        assert_eq!(mem.addr(), pool.base.as_ptr().addr());
+        assert_eq!(pool.usable_size_aligned(mem), 1 << MIN_K);
+        pool.free(mem);
+        check_buddy_pool_full(&pool);
+
+        // The reserve floor keeps the last block from being taken whole
+        let mut pool = BuddyPool::builder()
+            .size((1u64 << MIN_K) as usize)
+            .reserve_floor_bytes(1)
+            .build()
+            .unwrap();
+        pool.init();
+        assert_eq!(pool.malloc_no_split(1), Err(BuddyError::ReserveFloor));
+    }
+
+    /// Tests that a pool of three times the minimum size is split into two segments and that
+    /// allocations, frees and reallocs are dispatched to the segment holding each pointer, with
+    /// ZERO_SIZE_PTR handled like null
+    #[test]
+    fn test_multi_pool() {
+        let min_size = (1u64 << MIN_K) as usize;
+        let mut pool = MultiBuddyPool::new(3 * min_size).unwrap();
+        pool.init();
+        let large = pool.segments[0].as_ref().unwrap();
+        let small = pool.segments[1].as_ref().unwrap();
+        assert_eq!(large.kval_m, MIN_K + 1);
+        assert_eq!(small.kval_m, MIN_K);
+        assert!(pool.segments[2].is_none());
+
+        // Takes the whole large segment, so the next allocation has to go to the small one
+        let mem1 = pool.malloc(2 * min_size - AVAIL_SIZE).unwrap();
+        let mem2 = pool.malloc(16).unwrap();
+        assert!(pool.segments[0].as_ref().unwrap().contains(mem1));
+        assert!(pool.segments[1].as_ref().unwrap().contains(mem2));
+        assert!(pool.contains(mem1) && pool.contains(mem2));
+
+        let mem2 = pool.realloc(mem2, 128).unwrap();
+        assert!(pool.segments[1].as_ref().unwrap().contains(mem2));
+        pool.free(mem1);
+        pool.free(mem2);
+
+        // ZERO_SIZE_PTR from a realloc to 0 is treated like null, as BuddyPool does
+        let mem = pool.malloc(100).unwrap();
+        assert_eq!(pool.realloc(mem, 0), Ok(ZERO_SIZE_PTR));
+        pool.free(ZERO_SIZE_PTR);
+        let mem = pool.realloc(ZERO_SIZE_PTR, 100).unwrap();
+        assert!(pool.contains(mem));
+        pool.free(mem);
+        check_buddy_pool_full(pool.segments[0].as_ref().unwrap());
+        check_buddy_pool_full(pool.segments[1].as_ref().unwrap());
+    }
+
+    /// Tests that hottest_kval finds the size with the most free blocks after fragmenting the pool
+    /// with small allocations
+    #[test]
+    fn test_hottest_kval() {
+        let mut pool = BuddyPool::new((1u64 << MIN_K) as usize).unwrap();
+        pool.init();
+        assert_eq!(pool.hottest_kval(), None);
+        let mut mems: [*mut u8; 16] = [ptr::null_mut(); 16];
+        for mem in mems.iter_mut() {
+            *mem = pool.malloc(1).unwrap();
+        }
+        // Freeing every other block leaves buddies that can not coalesce
+        for mem in mems.iter().step_by(2) {
+            pool.free(*mem);
+        }
+        let kval = b_to_k(1 + AVAIL_SIZE);
+        assert_eq!(get_size_and_validate(&pool, kval), 8);
+        assert_eq!(pool.free_counts[kval], 8);
+        assert_eq!(pool.hottest_kval(), Some(kval));
+        for mem in mems.iter().skip(1).step_by(2) {
+            pool.free(*mem);
+        }
+        assert_eq!(pool.hottest_kval(), None);
+        check_buddy_pool_full(&pool);
+    }
+
+    /// Tests that two distinct pools are not the same pool and that freeing one pool's pointer
+    /// through the other is rejected without touching either pool
+    #[test]
+    fn test_same_pool() {
+        let mut pool_a = BuddyPool::new((1u64 << MIN_K) as usize).unwrap();
+        let mut pool_b = BuddyPool::new((1u64 << MIN_K) as usize).unwrap();
+        pool_a.init();
+        pool_b.init();
+        assert!(BuddyPool::same_pool(&pool_a, &pool_a));
+        assert!(!BuddyPool::same_pool(&pool_a, &pool_b));
+
+        let mem = pool_a.malloc(64).unwrap();
+        pool_b.free(mem);
+        check_buddy_pool_full(&pool_b);
+        assert_eq!(
+            pool_b.realloc(mem, 128),
+            Err(BuddyError::CorruptedMemoryPool)
+        );
+        check_buddy_pool_full(&pool_b);
+        unsafe {
+            let avail = &*(mem.offset(-(AVAIL_SIZE as isize)) as *mut Avail);
+            assert_eq!(avail.state(), BLOCK_RESERVED);
+        }
+        pool_a.free(mem);
+        check_buddy_pool_full(&pool_a);
+    }
+
+    /// Tests that buddies freed within the no coalesce range stay separate while buddies outside
+    /// of it still merge, and that the range can be turned off again
+    #[test]
+    fn test_no_coalesce_range() {
+        let mut pool = BuddyPool::new((1u64 << MIN_K) as usize).unwrap();
+        pool.init();
+        let small = b_to_k(1 + AVAIL_SIZE);
+        let large = b_to_k(200 + AVAIL_SIZE);
+        pool.set_no_coalesce_range(small, small);
+        let mem1 = pool.malloc(1).unwrap();
+        let mem2 = pool.malloc(1).unwrap();
+        let mem3 = pool.malloc(200).unwrap();
+        let mem4 = pool.malloc(200).unwrap();
+
+        pool.free(mem1);
+        pool.free(mem2);
+        assert_eq!(get_size_and_validate(&pool, small), 2);
+        // mem4 was split from a larger block so its buddy is free and they merge
+        pool.free(mem4);
+        assert_eq!(get_size_and_validate(&pool, large), 0);
+        assert_eq!(get_size_and_validate(&pool, large + 1), 1);
+
+        pool.set_no_coalesce_range(1, 0);
+        let mem1 = pool.malloc(1).unwrap();
+        let mem2 = pool.malloc(1).unwrap();
+        pool.free(mem3);
+        pool.free(mem1);
+        pool.free(mem2);
+        check_buddy_pool_full(&pool);
+    }
+
+    /// Tests that latency sampling records one duration per public operation, measured with an
+    /// installed mock clock that advances by a chosen step on every read
+    #[test]
+    #[cfg(feature = "latency_sampling")]
+    fn test_latency_samples() {
+        use core::sync::atomic::{AtomicU64, Ordering};
+        static NOW: AtomicU64 = AtomicU64::new(0);
+        static STEP: AtomicU64 = AtomicU64::new(0);
+        fn mock_clock() -> u64 {
+            NOW.fetch_add(STEP.load(Ordering::SeqCst), Ordering::SeqCst)
+        }
+
+        let mut pool = BuddyPool::new((1u64 << MIN_K) as usize).unwrap();
+        pool.init();
+        let mem = pool.malloc(16).unwrap();
+        assert!(pool.latency_samples().is_empty());
+
+        pool.set_clock(mock_clock);
+        STEP.store(3, Ordering::SeqCst);
+        let mem2 = pool.malloc(16).unwrap();
+        STEP.store(7, Ordering::SeqCst);
+        // Moves the block, which mallocs and frees internally but is sampled once
+        let mem = pool.realloc(mem, 1024).unwrap();
+        STEP.store(11, Ordering::SeqCst);
+        pool.free(mem);
+        pool.free(mem2);
+        assert_eq!(pool.latency_samples(), &[3, 7, 11, 11]);
+        check_buddy_pool_full(&pool);
+    }
+
+    /// Tests that the slice for an allocation covers exactly the usable bytes of its block
+    #[test]
+    fn test_as_slice_mut() {
+        let mut pool = BuddyPool::new((1u64 << MIN_K) as usize).unwrap();
+        pool.init();
+        let mem = pool.malloc(100).unwrap();
+        let kval = b_to_k(100 + AVAIL_SIZE);
+        let slice = unsafe { pool.as_slice_mut(mem) };
+        assert_eq!(slice.len(), (1usize << kval) - AVAIL_SIZE);
+        for (i, byte) in slice.iter_mut().enumerate() {
+            *byte = i as u8;
+        }
+        // One past the end is the start of the next block, which is outside the slice
+        let end = slice.as_ptr_range().end;
+        assert_eq!(end.addr(), mem.addr() - AVAIL_SIZE + (1usize << kval));
+        assert!(slice.get_mut(slice.len()).is_none());
+        for i in 0..100 {
+            assert_eq!(unsafe { *mem.add(i) }, i as u8);
+        }
+        assert!(unsafe { pool.as_slice_mut(ptr::null_mut()) }.is_empty());
+        pool.free(mem);
+        check_buddy_pool_full(&pool);
+    }
+
+    /// Tests that a write to a freed block is detected when the block is next handed out, and that
+    /// freeing or reallocating an already freed pointer is rejected
+    #[test]
+    #[cfg(feature = "uaf_detect")]
+    fn test_use_after_free_detected() {
+        let mut pool = BuddyPool::new((1u64 << MIN_K) as usize).unwrap();
+        pool.init();
+        let kval = b_to_k(100 + AVAIL_SIZE);
+        // Keep the buddy reserved so the freed block stays in its avail list with its marker
+        let mem1 = pool.malloc(100).unwrap();
+        let mem2 = pool.malloc(100).unwrap();
+        pool.free(mem1);
+        assert_eq!(unsafe { *mem1.add(10) }, FREED_MARKER);
+        pool.free(mem1);
+        assert_eq!(get_size_and_validate(&pool, kval), 1);
+        assert_eq!(pool.realloc(mem1, 10), Err(BuddyError::CorruptedMemoryPool));
+
+        unsafe {
+            *mem1.add(10) = 1;
+        }
+        assert_eq!(pool.malloc(100), Err(BuddyError::CorruptedMemoryPool));
+        // The corrupted block is kept out of circulation
+        let mem3 = pool.malloc(100).unwrap();
+        assert_ne!(mem3, mem1);
+
+        // Blocks that are freed and reused without a stray write are handed out normally
+        pool.free(mem3);
+        let mem4 = pool.malloc(100).unwrap();
+        assert_eq!(mem4, mem3);
+        pool.free(mem4);
+        pool.free(mem2);
+    }
+
+    /// Tests that the growth factor decides how far realloc grows an exhausted pool, by one kval
+    /// when growing to fit and by two when doubling
+    #[test]
+    fn test_growth_factor() {
+        let size = (1u64 << MIN_K) as usize;
+        for (factor, kval_m) in [
+            (GrowthFactor::Fit, MIN_K + 1),
+            (GrowthFactor::Double, MIN_K + 2),
+        ] {
+            let mut pool = BuddyPool::builder()
+                .size(size)
+                .auto_grow(true)
+                .growth_factor(factor)
+                .build()
+                .unwrap();
+            pool.init();
+            let mem = pool.malloc(16).unwrap();
+            // Keep the buddy reserved so the block can not grow in place. Growing the pool may
+            // move it, so the buddy is found again by its offset
+            let guard = pool.malloc(16).unwrap();
+            let guard = pool.offset_of(guard);
+            let mem = pool.realloc(mem, size / 2).unwrap();
+            assert_eq!(pool.kval_m, kval_m);
+            pool.free(mem);
+            pool.free(pool.ptr_at(guard));
+            check_buddy_pool_full(&pool);
+        }
+    }
+
+    /// Tests swapping a buffer for a new one with swap_alloc, which hands back both pointers with
+    /// the old block still allocated
+    #[test]
+    fn test_swap_alloc() {
+        let mut pool = BuddyPool::new(0).unwrap();
+        pool.init();
+        let old = pool.malloc(64).unwrap();
+        unsafe { old.write_bytes(0xAB, 64) };
+        let (new, returned) = pool.swap_alloc(old, 128).unwrap();
+        assert_eq!(returned, old);
+        assert_ne!(new, old);
+        unsafe {
+            new.copy_from_nonoverlapping(old, 64);
+            assert!(core::slice::from_raw_parts(old, 64)
+                .iter()
+                .all(|&b| b == 0xAB));
+            assert!(core::slice::from_raw_parts(new, 64)
+                .iter()
+                .all(|&b| b == 0xAB));
+        }
+        pool.free(old);
+        pool.free(new);
+        check_buddy_pool_full(&pool);
+    }
+
+    /// Tests that config reflects the options a pool was built and configured with
+    #[test]
+    fn test_config() {
+        let mut pool = BuddyPool::builder()
+            .size((1u64 << MIN_K) as usize)
+            .auto_grow(true)
+            .growth_factor(GrowthFactor::Double)
+            .build()
+            .unwrap();
+        pool.init();
+        pool.set_no_coalesce_range(6, 8);
+        let config = pool.config();
+        assert_eq!(config.min_kval, b_to_k(AVAIL_SIZE));
+        assert_eq!(config.max_kval, MAX_K - 1);
+        assert_eq!(config.kval_m, MIN_K);
+        assert!(config.auto_grow);
+        assert_eq!(config.growth_factor, GrowthFactor::Double);
+        assert_eq!(config.no_coalesce, Some((6, 8)));
+    }
+
+    /// Tests that malloc_block_aligned returns the start of the block itself, aligned to the block
+    /// size, and that free still finds the block through the side table. The block is filled with
+    /// what looks like a free header so that freeing its buddy must not coalesce with it.
+    #[test]
+    fn test_malloc_block_aligned() {
+        let mut pool = BuddyPool::new(0).unwrap();
+        pool.init();
+        let plain = pool.malloc(64).unwrap();
+        let mem = pool.malloc_block_aligned(100).unwrap();
+        let offset = mem.addr() - pool.base.as_ptr().addr();
+        assert_eq!(offset, 128);
+        assert_eq!(mem.addr() % 128, 0);
+        assert_eq!(pool.usable_size_aligned(mem), 128);
+        unsafe {
+            let fake = mem as *mut Avail;
+            ptr::write(fake, Avail::new());
+            (*fake).set_state(BLOCK_AVAIL);
+            (*fake).kval = 7;
+        }
+        pool.free(plain);
+        assert_eq!(pool.avail[7].next, pool.offset_of(plain) - AVAIL_SIZE);
+        pool.free(mem);
+        check_buddy_pool_full(&pool);
+    }
+
+    /// Tests that the fragmentation trend is positive while every other block is freed and turns
+    /// negative once the remaining blocks are freed and coalesce again
+    #[cfg(feature = "fragmentation_trend")]
+    #[test]
+    fn test_fragmentation_trend() {
+        let mut pool = BuddyPool::new((1u64 << MIN_K) as usize).unwrap();
+        pool.init();
+        let mut mem = [ptr::null_mut(); 16];
+        for m in mem.iter_mut() {
+            *m = pool.malloc((1usize << (MIN_K - 4)) - AVAIL_SIZE).unwrap();
+        }
+        for m in mem.iter().skip(1).step_by(2) {
+            pool.free(*m);
+        }
+        assert!(pool.fragmentation_trend() > 0);
+        for m in mem.iter().step_by(2) {
+            pool.free(*m);
+        }
+        assert!(pool.fragmentation_trend() < 0);
+        check_buddy_pool_full(&pool);
+    }
+
+    /// Tests that verify accepts a pool with plain, aligned and resized allocations and catches a
+    /// reserved block whose recorded request does not fit it
+    #[test]
+    fn test_check_usable_sizes() {
+        let mut pool = BuddyPool::new(0).unwrap();
+        pool.init();
+        let a = pool.malloc(100).unwrap();
+        let b = pool.malloc_aligned(100, 256).unwrap();
+        let c = pool.malloc(10).unwrap();
+        let c = pool.realloc(c, 1000).unwrap();
+        let d = pool.malloc_block_aligned(64).unwrap();
+        assert!(pool.verify().is_ok());
+        unsafe {
+            let avail = a.offset(-(AVAIL_SIZE as isize)) as *mut Avail;
+            (*avail).req_size = (1u32 << (*avail).kval) - AVAIL_SIZE as u32 + 1;
+        }
+        assert!(pool.check_usable_sizes().is_err());
+        assert!(pool.verify().is_err());
+        for mem in [a, b, c, d] {
+            pool.free(mem);
+        }
+        check_buddy_pool_full(&pool);
+    }
+
+    /// Tests that a handle resolves to the rebased address after the pool grows and stops resolving
+    /// once the allocation is freed, even if the memory is allocated again
+    #[test]
+    fn test_handle_resolve() {
+        let mut pool = BuddyPool::new((1u64 << MIN_K) as usize).unwrap();
+        pool.init();
+        let mem = pool.malloc(100).unwrap();
+        unsafe { mem.write_bytes(0x5A, 100) };
+        let handle = pool.handle_of(mem);
+        assert_eq!(pool.resolve(handle), Some(mem));
+        let offset = mem.addr() - pool.base.as_ptr().addr();
+        pool.grow((1u64 << (MIN_K + 4)) as usize).unwrap();
+        let mem = pool.resolve(handle).unwrap();
+        assert_eq!(mem.addr() - pool.base.as_ptr().addr(), offset);
+        unsafe {
+            assert!(core::slice::from_raw_parts(mem, 100)
+                .iter()
+                .all(|&b| b == 0x5A));
+        }
+        pool.free(mem);
+        assert_eq!(pool.resolve(handle), None);
+        let again = pool.malloc(100).unwrap();
+        assert_eq!(again, mem);
+        assert_eq!(pool.resolve(handle), None);
+        pool.free(again);
+        check_buddy_pool_full(&pool);
+    }
+
+    /// Tests that repeated calls to with_zeroed_scratch of the same size reuse the same block and
+    /// still hand out a zeroed buffer
+    #[test]
+    fn test_with_zeroed_scratch() {
+        let mut pool = BuddyPool::new(0).unwrap();
+        pool.init();
+        let first = pool
+            .with_zeroed_scratch(4096, |buf| {
+                assert!(buf.iter().all(|&b| b == 0));
+                buf[..100].fill(0xFF);
+                buf.as_ptr()
+            })
+            .unwrap();
+        let second = pool
+            .with_zeroed_scratch(4096, |buf| {
+                assert!(buf.iter().all(|&b| b == 0));
+                buf.as_ptr()
+            })
+            .unwrap();
+        assert_eq!(first, second);
+        pool.release_scratch();
+        check_buddy_pool_full(&pool);
+    }
+
+    /// Tests that mapped_bytes exceeds capacity by exactly the size of a reserved pool-level
+    /// header, both before and after the pool grows, and equals it for a pool without one
+    #[test]
+    fn test_mapped_bytes() {
+        let header = 64;
+        let mut pool = BuddyPool::builder()
+            .size((1u64 << MIN_K) as usize)
+            .reserved_header_bytes(header)
+            .build()
+            .unwrap();
+        pool.init();
+        assert_eq!(pool.capacity(), (1u64 << MIN_K) as usize);
+        assert!(pool.mapped_bytes() > pool.capacity());
+        assert_eq!(pool.mapped_bytes() - pool.capacity(), header);
+        assert_eq!(pool.config().reserved_header_bytes, header);
+        pool.reserved_header().copy_from_slice(&[0xB7; 64]);
+
+        // The whole pool can still be handed out without touching the header
+        let max = pool.max_single_allocation();
+        let mem = pool.malloc(max).unwrap();
+        unsafe { mem.write_bytes(0x11, max) };
+        assert!(!pool.contains(unsafe { mem.add(max) }));
+        assert!(pool.reserved_header().iter().all(|&b| b == 0xB7));
+        pool.free(mem);
+
+        pool.grow((1u64 << (MIN_K + 1)) as usize).unwrap();
+        assert_eq!(pool.capacity(), (1u64 << (MIN_K + 1)) as usize);
+        assert_eq!(pool.mapped_bytes() - pool.capacity(), header);
+        assert!(pool.reserved_header().iter().all(|&b| b == 0xB7));
+        check_buddy_pool_full(&pool);
+
+        let mut pool = BuddyPool::new((1u64 << MIN_K) as usize).unwrap();
+        pool.init();
+        assert_eq!(pool.mapped_bytes(), pool.capacity());
+        assert!(pool.reserved_header().is_empty());
+    }
+
+    /// An operation in a randomly generated sequence for the coalescing property test
+    #[derive(Clone, Debug)]
+    enum Op {
+        Malloc(usize),
+        Free(usize),
+    }
+
+    proptest! {
+        /// Tests that after every operation in a random sequence of mallocs and frees, the reserved
+        /// blocks found by walking the pool are exactly the blocks a shadow model expects, and that
+        /// freeing everything coalesces the pool back into a single block
+        #[test]
+        fn test_coalescing_property(ops in proptest::collection::vec(
+            prop_oneof![(1usize..5000).prop_map(Op::Malloc), any::<usize>().prop_map(Op::Free)],
+            1..64,
+        )) {
+            let mut pool = BuddyPool::new((1u64 << MIN_K) as usize).unwrap();
+            pool.init();
+            let base = pool.base.as_mut_ptr();
+            let mut model: Vec<(usize, usize)> = Vec::new();
+            for op in ops {
+                match op {
+                    Op::Malloc(size) => {
+                        if let Ok(mem) = pool.malloc(size) {
+                            let offset = mem.addr() - base.addr() - AVAIL_SIZE;
+                            model.push((offset, b_to_k(size + AVAIL_SIZE)));
+                        }
+                    }
+                    Op::Free(index) if !model.is_empty() => {
+                        let (offset, _) = model.swap_remove(index % model.len());
+                        pool.free(base.wrapping_add(offset + AVAIL_SIZE));
+                    }
+                    Op::Free(_) => {}
+                }
+                let mut reserved: Vec<(usize, usize)> = pool
+                    .blocks()
+                    .filter(|&(_, _, tag)| tag == BLOCK_RESERVED)
+                    .map(|(offset, kval, _)| (offset, kval))
+                    .collect();
+                reserved.sort_unstable();
+                let mut expected = model.clone();
+                expected.sort_unstable();
+                prop_assert_eq!(reserved, expected);
+                prop_assert!(pool.verify().is_ok());
+            }
+            for (offset, _) in model {
+                pool.free(base.wrapping_add(offset + AVAIL_SIZE));
+            }
+            check_buddy_pool_full(&pool);
+        }
+    }
+
+    /// Tests that a capacity limit on a large pool makes allocations fail at the limit, and that
+    /// freeing memory under the limit lets allocations succeed again
+    #[test]
+    fn test_capacity_limit() {
+        let mut pool = BuddyPool::new(0).unwrap();
+        pool.init();
+        pool.set_capacity_limit((1u64 << MIN_K) as usize);
+        let half = (1usize << (MIN_K - 1)) - AVAIL_SIZE;
         assert_eq!(
-            pool.avail[pool.kval_m].next as *const Avail,
-            pool.base.as_ptr() as *const Avail
+            pool.malloc((1usize << MIN_K) + 1).unwrap_err(),
+            BuddyError::NoMemory
         );
+        let a = pool.malloc(half).unwrap();
+        let b = pool.malloc(half).unwrap();
+        assert_eq!(pool.malloc(1).unwrap_err(), BuddyError::NoMemory);
+        pool.free(a);
+        let a = pool.malloc(half).unwrap();
+        pool.free(a);
+        pool.free(b);
+        pool.set_capacity_limit(usize::MAX);
+        let big = pool.malloc((1usize << MIN_K) + 1).unwrap();
+        pool.free(big);
+        check_buddy_pool_full(&pool);
+    }
+
+    /// Tests that realloc_detailed reports a shrink as done in place
+    #[test]
+    fn test_realloc_detailed_in_place() {
+        let mut pool = BuddyPool::new(0).unwrap();
+        pool.init();
+        let mem = pool.malloc(1000).unwrap();
+        let result = pool.realloc_detailed(mem, 100).unwrap();
+        assert_eq!(result.new_ptr, mem);
+        assert!(!result.moved);
+        assert_eq!(result.old_ptr, None);
+        pool.free(result.new_ptr);
+        check_buddy_pool_full(&pool);
+    }
+
+    /// Tests that realloc_detailed reports a grow that relocates the block along with the old
+    /// pointer
+    #[test]
+    fn test_realloc_detailed_moved() {
+        let mut pool = BuddyPool::new(0).unwrap();
+        pool.init();
+        let mem = pool.malloc(100).unwrap();
+        let guard = pool.malloc(100).unwrap();
+        let result = pool.realloc_detailed(mem, 1000).unwrap();
+        assert_ne!(result.new_ptr, mem);
+        assert!(result.moved);
+        assert_eq!(result.old_ptr, Some(mem));
+        pool.free(result.new_ptr);
+        pool.free(guard);
+        check_buddy_pool_full(&pool);
+    }
+
+    /// Tests malloc at and around the capacity boundary. Exactly max_single_allocation succeeds on
+    /// an empty pool and one byte more is too large. Once anything is allocated, a request that
+    /// needs the whole pool fails with PoolNotEmpty while one that fits in half still succeeds.
+    /// The PoolNotEmpty failures are counted and call the OOM handler like NoMemory.
+    #[test]
+    fn test_capacity_boundary() {
+        use core::sync::atomic::{AtomicPtr, Ordering};
+        // The block the OOM handler frees to make room for the whole pool request
+        static CACHE: AtomicPtr<u8> = AtomicPtr::new(ptr::null_mut());
+
+        let mut pool = BuddyPool::new((1u64 << MIN_K) as usize).unwrap();
+        pool.init();
+        let max = pool.max_single_allocation();
+        assert_eq!(max, (1usize << MIN_K) - AVAIL_SIZE);
+        assert_eq!(pool.malloc(max + 1), Err(BuddyError::NoMemory));
+        let mem = pool.malloc(max).unwrap();
+        pool.free(mem);
+        check_buddy_pool_full(&pool);
+
+        let small = pool.malloc(1).unwrap();
+        assert_eq!(pool.malloc(max), Err(BuddyError::PoolNotEmpty));
+        #[cfg(feature = "errno")]
+        assert_eq!(errno().0, ENOMEM);
+        let half = (pool.capacity() / 2) - AVAIL_SIZE;
+        assert_eq!(pool.malloc(half + 1), Err(BuddyError::PoolNotEmpty));
+        let mem = pool.malloc(half).unwrap();
+        pool.free(mem);
+        pool.free(small);
+        check_buddy_pool_full(&pool);
+        assert_eq!(pool.failure_count(), 3);
+
+        CACHE.store(pool.malloc(1).unwrap(), Ordering::SeqCst);
+        pool.set_oom_handler(|pool, _| {
+            let cached = CACHE.swap(ptr::null_mut(), Ordering::SeqCst);
+            if !cached.is_null() {
+                pool.free(cached);
+            }
+            !cached.is_null()
+        });
+        let mem = pool.malloc(max).unwrap();
+        assert_eq!(pool.oom_stats(), (1, 1));
+        assert_eq!(pool.failure_count(), 3);
+        pool.free(mem);
+        let small = pool.malloc(1).unwrap();
+        assert_eq!(pool.malloc(max), Err(BuddyError::PoolNotEmpty));
+        assert_eq!(pool.oom_stats(), (2, 1));
+        assert_eq!(pool.failure_count(), 4);
+        pool.free(small);
+        check_buddy_pool_full(&pool);
+    }
+
+    /// Tests that a recorded session replays against a new pool with the same offsets and a pool
+    /// that verifies after every event
+    #[cfg(feature = "replay")]
+    #[test]
+    fn test_replay() {
+        let mut events = Vec::new();
+        let mut pool = BuddyPool::new(0).unwrap();
+        pool.init();
+        {
+            let mut recorder = Recorder::new(&mut pool, |event| events.push(event));
+            let a = recorder.malloc(100).unwrap();
+            let b = recorder.malloc(5000).unwrap();
+            let a = recorder.realloc(a, 300).unwrap();
+            recorder.free(b);
+            let c = recorder.malloc(64).unwrap();
+            assert!(recorder.malloc(usize::MAX >> 8).is_err());
+            recorder.free(a);
+            recorder.free(c);
+            // Null and ZERO_SIZE_PTR are recorded as themselves rather than as offsets
+            let d = recorder.realloc(ptr::null_mut(), 200).unwrap();
+            assert_eq!(recorder.realloc(d, 0), Ok(ZERO_SIZE_PTR));
+            recorder.free(ZERO_SIZE_PTR);
+            recorder.free(ptr::null_mut());
+        }
+        check_buddy_pool_full(&pool);
+        assert_eq!(events.len(), 12);
+        assert!(matches!(
+            events[8],
+            Event::Realloc {
+                offset: Location::Null,
+                ..
+            }
+        ));
+        assert!(matches!(
+            events[9],
+            Event::Realloc {
+                offset: Location::Offset(_),
+                size: 0,
+                new_offset: Some(Location::ZeroSize),
+            }
+        ));
+        assert_eq!(
+            events[10],
+            Event::Free {
+                offset: Location::ZeroSize
+            }
+        );
+        assert_eq!(
+            events[11],
+            Event::Free {
+                offset: Location::Null
+            }
+        );
+
+        // The replaying pool is created while the first is still live, so it has another base
+        let mut fresh = BuddyPool::new(0).unwrap();
+        fresh.init();
+        assert_ne!(fresh.base_addr(), pool.base_addr());
+        assert_eq!(
+            Player::new(&mut fresh).replay(events.iter().copied()),
+            Ok(())
+        );
+        assert!(fresh.verify().is_ok());
+        check_buddy_pool_full(&fresh);
+
+        let mut fresh = BuddyPool::new(0).unwrap();
+        fresh.init();
+        let wrong = [Event::Malloc {
+            size: 100,
+            offset: Some(4096),
+        }];
+        assert_eq!(Player::new(&mut fresh).replay(wrong), Err(0));
+    }
+
+    /// Tests that padding a 40-byte object by the suggested amount makes it and its header fill its
+    /// block exactly, so one more byte would need the next block size
+    #[test]
+    fn test_suggest_padding() {
+        let mut pool = BuddyPool::new(0).unwrap();
+        pool.init();
+        let padding = pool.suggest_padding(40);
+        let block = 1usize << b_to_k(40 + AVAIL_SIZE);
+        assert_eq!(40 + padding + AVAIL_SIZE, block);
+        assert_eq!(pool.suggest_padding(40 + padding), 0);
+        let mem = pool.malloc(40 + padding).unwrap();
+        let next = pool.malloc(40 + padding + 1).unwrap();
+        unsafe {
+            assert_eq!(1usize << (*pool.header_of(mem)).kval, block);
+            assert_eq!(1usize << (*pool.header_of(next)).kval, 2 * block);
+        }
+        pool.free(mem);
+        pool.free(next);
+        check_buddy_pool_full(&pool);
+    }
+
+    /// Tests that free_blocks_sorted lists the holes left by freeing every other block of a full
+    /// pool in address order
+    #[cfg(feature = "std")]
+    #[test]
+    fn test_free_blocks_sorted() {
+        let mut pool = BuddyPool::new((1u64 << MIN_K) as usize).unwrap();
+        pool.init();
+        let unit = 1usize << (MIN_K - 3);
+        let mut mem = [ptr::null_mut(); 8];
+        for m in mem.iter_mut() {
+            *m = pool.malloc(unit - AVAIL_SIZE).unwrap();
+        }
+        assert!(pool.free_blocks_sorted().is_empty());
+        for m in mem.iter().rev().step_by(2) {
+            pool.free(*m);
+        }
+        let expected: Vec<(usize, usize)> = (0..4).map(|i| ((2 * i + 1) * unit, unit)).collect();
+        assert_eq!(pool.free_blocks_sorted(), expected);
+        for m in mem.iter().step_by(2) {
+            pool.free(*m);
+        }
+        assert_eq!(pool.free_blocks_sorted(), [(0, 1usize << MIN_K)]);
+    }
+
+    /// Tests that malloc fails with ReserveFloor when it would eat into the reserve floor, and that
+    /// malloc_emergency can still allocate from the reserve, telling the observer and counting and
+    /// handling its failures like malloc
+    #[test]
+    fn test_reserve_floor() {
+        use core::sync::atomic::{AtomicUsize, Ordering};
+        // The number of blocks the observer was told about
+        static MALLOCS: AtomicUsize = AtomicUsize::new(0);
+
+        let size = (1u64 << MIN_K) as usize;
+        let mut pool = BuddyPool::builder()
+            .size(size)
+            .reserve_floor_bytes(size / 4)
+            .build()
+            .unwrap();
+        pool.init();
+        assert_eq!(pool.config().reserve_floor_bytes, size / 4);
+        let quarter = size / 4 - AVAIL_SIZE;
+        let a = pool.malloc(quarter).unwrap();
+        let b = pool.malloc(quarter).unwrap();
+        let c = pool.malloc(quarter).unwrap();
+        assert_eq!(pool.malloc(1), Err(BuddyError::ReserveFloor));
+        #[cfg(feature = "errno")]
+        assert_eq!(errno().0, ENOMEM);
+        pool.set_observer(|event| {
+            if let AllocEvent::Malloc { .. } = event {
+                MALLOCS.fetch_add(1, Ordering::Relaxed);
+            }
+        });
+        pool.set_oom_handler(|_, _| false);
+        let d = pool.malloc_emergency(quarter).unwrap();
+        assert_eq!(MALLOCS.load(Ordering::Relaxed), 1);
+        assert_eq!(pool.malloc_emergency(1), Err(BuddyError::NoMemory));
+        assert_eq!(pool.oom_stats(), (1, 0));
+        assert_eq!(pool.failure_count(), 1);
+        for mem in [a, b, c, d] {
+            pool.free(mem);
+        }
+        check_buddy_pool_full(&pool);
+    }
+
+    /// Tests that base_consistent holds for an empty and a partly used pool, and fails once the list
+    /// head for the whole pool no longer points at the base
+    #[test]
+    fn test_base_consistent() {
+        let mut pool = BuddyPool::new(0).unwrap();
+        pool.init();
+        assert!(pool.base_consistent());
+        let mem = pool.malloc(100).unwrap();
+        assert!(pool.base_consistent());
+        pool.free(mem);
+        assert!(pool.base_consistent());
+
+        let kval_m = pool.kval_m;
+        let base = pool.avail[kval_m].next;
+        pool.avail[kval_m].next = base + 64;
+        assert!(!pool.base_consistent());
+        pool.avail[kval_m].next = base;
+        check_buddy_pool_full(&pool);
+    }
+
+    /// Tests that the alignment padding in the stats is the sum of the offsets of the live aligned
+    /// allocations from where their plain pointers would have been
+    #[test]
+    fn test_stats_alignment_padding() {
+        let mut pool = BuddyPool::new(0).unwrap();
+        pool.init();
+        let plain = pool.malloc(10).unwrap();
+        let mut expected = 0;
+        let mut mem = [ptr::null_mut(); 4];
+        for (m, align) in mem.iter_mut().zip([64, 256, 1024, 4096]) {
+            *m = pool.malloc_aligned(100, align).unwrap();
+            let header = unsafe { pool.header_of(*m) };
+            expected += m.addr() - header.addr() - AVAIL_SIZE;
+        }
+        assert!(expected > 0);
+        assert_eq!(pool.stats().alignment_padding_bytes, expected);
+        for m in mem {
+            pool.free(m);
+        }
+        pool.free(plain);
+        assert_eq!(pool.stats().alignment_padding_bytes, 0);
+        check_buddy_pool_full(&pool);
+    }
+
+    /// Tests that coalesce_and_report merges buddies left unmerged by a no coalesce range and
+    /// reports the whole pool as the largest free block afterwards
+    #[test]
+    fn test_coalesce_and_report() {
+        let mut pool = BuddyPool::new((1u64 << MIN_K) as usize).unwrap();
+        pool.init();
+        pool.set_no_coalesce_range(0, MIN_K - 1);
+        let a = pool.malloc(1000).unwrap();
+        let b = pool.malloc(1000).unwrap();
+        pool.free(a);
+        pool.free(b);
+        assert_eq!(pool.free_counts[pool.kval_m], 0);
+        pool.set_no_coalesce_range(1, 0);
+        assert_eq!(pool.coalesce_and_report(), (1usize << MIN_K) - AVAIL_SIZE);
+        check_buddy_pool_full(&pool);
+    }
+
+    /// Tests that realloc_cost reports the splits of a shrink in place, the merges of growing back
+    /// into the split off halves, and a move for a grow that needs a larger block
+    #[test]
+    fn test_realloc_cost() {
+        let mut pool = BuddyPool::new(0).unwrap();
+        pool.init();
+        let mem = pool.malloc(4000).unwrap();
+        assert_eq!(pool.realloc_cost(mem, 100), Some((4, false)));
+        let shrunk = pool.realloc(mem, 100).unwrap();
+        assert_eq!(shrunk, mem);
+        assert_eq!(pool.realloc_cost(shrunk, 4000), Some((4, false)));
+        assert_eq!(pool.realloc(shrunk, 4000).unwrap(), mem);
+
+        // Taking one of the split off halves leaves no way to grow but to move
+        let shrunk = pool.realloc(mem, 100).unwrap();
+        let other = pool.malloc(100).unwrap();
+        let (operations, moved) = pool.realloc_cost(shrunk, 4000).unwrap();
+        assert!(moved);
+        assert!(operations >= 1);
+        let grown = pool.realloc(shrunk, 4000).unwrap();
+        assert_ne!(grown, shrunk);
+
+        assert_eq!(pool.realloc_cost(ptr::null_mut(), 100), None);
+        assert_eq!(pool.realloc_cost(grown, usize::MAX >> 8), None);
+        pool.free(other);
+        pool.free(grown);
+        check_buddy_pool_full(&pool);
+    }
+
+    /// Tests that a pool can manage a shared file mapping made by the caller, and that mappings
+    /// whose length is not a supported power of two are rejected
+    #[test]
+    fn test_from_mmap() {
+        let size = 1usize << MIN_K;
+        let path =
+            std::env::temp_dir().join(std::format!("buddy_from_mmap_{}", std::process::id()));
+        let file = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&path)
+            .unwrap();
+        file.set_len(size as u64).unwrap();
+        let map = unsafe { MmapMut::map_mut(&file).unwrap() };
+        let mut pool = BuddyPool::from_mmap(map).unwrap();
+        pool.init();
+        assert_eq!(pool.kval_m, MIN_K);
+        let mem = pool.malloc(100).unwrap();
+        unsafe { mem.write_bytes(0x42, 100) };
+        pool.free(mem);
+        check_buddy_pool_full(&pool);
+        drop(pool);
+        std::fs::remove_file(&path).unwrap();
+
+        let map = MmapMut::map_anon(size + 4096).unwrap();
+        assert_eq!(
+            BuddyPool::from_mmap(map).err(),
+            Some(BuddyError::InvalidSize)
+        );
+        let map = MmapMut::map_anon(size / 2).unwrap();
+        assert_eq!(
+            BuddyPool::from_mmap(map).err(),
+            Some(BuddyError::InvalidSize)
+        );
+        #[cfg(feature = "errno")]
+        assert_eq!(errno().0, EINVAL);
+    }
+
+    /// Tests that take_peak_used_bytes reports the peak of each interval, starting from the usage
+    /// at the previous take
+    #[test]
+    fn test_take_peak_used_bytes() {
+        let mut pool = BuddyPool::new((1u64 << MIN_K) as usize).unwrap();
+        pool.init();
+        let unit = 1usize << (MIN_K - 4);
+        let a = pool.malloc(unit - AVAIL_SIZE).unwrap();
+        let b = pool.malloc(unit - AVAIL_SIZE).unwrap();
+        assert_eq!(pool.used_bytes(), 2 * unit);
+        pool.free(b);
+        assert_eq!(pool.take_peak_used_bytes(), 2 * unit);
+        assert_eq!(pool.peak_used_bytes(), unit);
+        let c = pool.malloc(1024 - AVAIL_SIZE).unwrap();
+        assert_eq!(pool.take_peak_used_bytes(), unit + 1024);
+        pool.free(a);
+        pool.free(c);
+        assert_eq!(pool.take_peak_used_bytes(), unit + 1024);
+        assert_eq!(pool.take_peak_used_bytes(), 0);
+        check_buddy_pool_full(&pool);
+    }
+
+    /// Tests that with_scope frees everything allocated inside the closure and leaves earlier
+    /// allocations alone
+    #[test]
+    fn test_with_scope() {
+        let mut pool = BuddyPool::new((1u64 << MIN_K) as usize).unwrap();
+        pool.init();
+        let outer = pool.malloc(100).unwrap();
+        let used = pool.used_bytes();
+        let total = pool.with_scope(|pool| {
+            let mut total = 0;
+            for size in [1, 100, 5000, 70000] {
+                let ptr = pool.malloc(size).unwrap();
+                total += size;
+                let ptr = pool.realloc(ptr, size * 2).unwrap();
+                assert!(!ptr.is_null());
+            }
+            pool.malloc_aligned(64, 4096).unwrap();
+            pool.malloc_block_aligned(1024).unwrap();
+            total
+        });
+        assert_eq!(total, 75101);
+        assert_eq!(pool.used_bytes(), used);
+        assert_eq!(pool.stats().alignment_padding_bytes, 0);
+        assert_eq!(pool.verify(), Ok(()));
+        pool.free(outer);
+        check_buddy_pool_full(&pool);
+    }
+
+    /// Tests that malloc fails with LiveAllocationLimit once the live allocation limit is reached
+    /// and succeeds again after a free
+    #[test]
+    fn test_max_live_allocations() {
+        let mut pool = BuddyPool::builder()
+            .size((1u64 << MIN_K) as usize)
+            .max_live_allocations(4)
+            .build()
+            .unwrap();
+        pool.init();
+        assert_eq!(pool.config().max_live_allocations, Some(4));
+        let ptrs: Vec<*mut u8> = (0..4).map(|_| pool.malloc(16).unwrap()).collect();
+        assert_eq!(pool.live_allocations(), 4);
+        assert_eq!(pool.malloc(16), Err(BuddyError::LiveAllocationLimit));
+        #[cfg(feature = "errno")]
+        assert_eq!(errno().0, ENOMEM);
+        assert_eq!(
+            pool.malloc_no_split(16),
+            Err(BuddyError::LiveAllocationLimit)
+        );
+        pool.free(ptrs[0]);
+        assert_eq!(pool.live_allocations(), 3);
+        let ptr = pool.malloc(16).unwrap();
+        pool.free(ptr);
+        for ptr in &ptrs[1..] {
+            pool.free(*ptr);
+        }
+        assert_eq!(pool.live_allocations(), 0);
+        check_buddy_pool_full(&pool);
+    }
+
+    /// Tests that self_test passes on a fresh pool and leaves it empty, and refuses to run on a
+    /// pool that is in use
+    #[test]
+    fn test_self_test() {
+        let mut pool = BuddyPool::new((1u64 << MIN_K) as usize).unwrap();
+        pool.init();
+        let ptr = pool.malloc(100).unwrap();
+        assert_eq!(pool.self_test(), Err(BuddyError::PoolNotEmpty));
+        pool.free(ptr);
+
+        pool.set_no_coalesce_range(MIN_K - 4, MIN_K - 4);
+        let peak = pool.peak_used_bytes();
+        assert_eq!(pool.self_test(), Ok(()));
+        check_buddy_pool_full(&pool);
+        assert_eq!(pool.peak_used_bytes(), peak);
+        assert_eq!(pool.live_allocations(), 0);
+        assert_eq!(pool.config().no_coalesce, Some((MIN_K - 4, MIN_K - 4)));
+        assert_eq!(pool.largest_allocation_ever(), 256 - AVAIL_SIZE);
+
+        // Blocks larger than a sixteenth of the pool leave only the round of halves
+        let mut pool = BuddyPool::new_with_limits(1 << MIN_K, MIN_K - 2).unwrap();
+        pool.init();
+        assert_eq!(pool.self_test(), Ok(()));
+        assert_eq!(pool.largest_allocation_ever(), 0);
+        check_buddy_pool_full(&pool);
+    }
+
+    /// Tests that with out of band metadata malloc hands out pointers at the start of their blocks,
+    /// and that the metadata round-trips through realloc, free and coalescing
+    #[test]
+    fn test_out_of_band_metadata() {
+        let mut pool = BuddyPool::builder()
+            .size((1u64 << MIN_K) as usize)
+            .out_of_band_metadata(true)
+            .build()
+            .unwrap();
+        pool.init();
+        assert!(pool.config().out_of_band_metadata);
+        let base = pool.base.as_ptr().addr();
+        let sizes = [1, 32, 100, 4096, 5000];
+        let mut ptrs: Vec<*mut u8> = Vec::new();
+        for size in sizes {
+            let ptr = pool.malloc(size).unwrap();
+            let block = pool.usable_size_aligned(ptr);
+            assert_eq!(block, size.max(AVAIL_SIZE).next_power_of_two());
+            assert_eq!((ptr.addr() - base) % block, 0);
+            unsafe { pool.as_slice_mut(ptr) }.fill(size as u8);
+            ptrs.push(ptr);
+        }
+        assert_eq!(pool.verify(), Ok(()));
+
+        let handle = pool.handle_of(ptrs[2]);
+        ptrs[2] = pool.realloc(ptrs[2], 1000).unwrap();
+        assert_eq!(pool.resolve(handle), None);
+        assert!(unsafe { pool.as_slice_mut(ptrs[2]) }[..100].iter().all(|&b| b == 100));
+        assert_eq!(pool.resolve(pool.handle_of(ptrs[2])), Some(ptrs[2]));
+        let live = pool.live_allocations();
+        pool.with_scope(|pool| {
+            pool.malloc(64).unwrap();
+            pool.malloc(3000).unwrap();
+        });
+        assert_eq!(pool.live_allocations(), live);
+
+        for ptr in ptrs {
+            pool.free(ptr);
+        }
+        assert_eq!(pool.live_allocations(), 0);
+        check_buddy_pool_full(&pool);
+    }
+
+    /// Tests that allocation_density reports live allocations per MiB of capacity
+    #[test]
+    fn test_allocation_density() {
+        let mut pool = BuddyPool::new((1u64 << (MIN_K + 2)) as usize).unwrap();
+        pool.init();
+        assert_eq!(pool.allocation_density(), 0.0);
+        let ptrs: Vec<*mut u8> = (0..10).map(|_| pool.malloc(100).unwrap()).collect();
+        assert_eq!(pool.allocation_density(), 2.5);
+        for ptr in ptrs {
+            pool.free(ptr);
+        }
+        assert_eq!(pool.allocation_density(), 0.0);
+        check_buddy_pool_full(&pool);
+    }
+
+    /// Tests that small objects from a SlabCache share one block with far less overhead than a
+    /// header each, and that the block goes back to the pool once they are all freed
+    #[test]
+    fn test_slab_cache() {
+        let mut pool = BuddyPool::new((1u64 << MIN_K) as usize).unwrap();
+        pool.init();
+        let count = 200;
+        let mut slab = SlabCache::new(&mut pool, 16);
+        for _ in 0..count {
+            slab.malloc(16).unwrap();
+        }
+        let overhead = pool.used_bytes() - count * 16;
+        assert!(overhead < count * AVAIL_SIZE / 4);
+        pool.init();
+
+        let mut slab = SlabCache::new(&mut pool, 16);
+        let ptrs: Vec<*mut u8> = (0..count).map(|_| slab.malloc(16).unwrap()).collect();
+        assert_eq!(slab.cells_in_use(), count);
+        for (i, &ptr) in ptrs.iter().enumerate() {
+            unsafe { ptr.write_bytes(i as u8, 16) };
+        }
+        for (i, &ptr) in ptrs.iter().enumerate() {
+            assert!(unsafe { core::slice::from_raw_parts(ptr, 16) }
+                .iter()
+                .all(|&b| b == i as u8));
+        }
+        let large = slab.malloc(100).unwrap();
+        slab.free(large);
+        for ptr in ptrs {
+            slab.free(ptr);
+        }
+        assert_eq!(slab.cells_in_use(), 0);
+        check_buddy_pool_full(&pool);
+    }
+
+    /// Tests that repair relinks the free blocks after a next pointer has been corrupted
+    #[test]
+    fn test_repair() {
+        let mut pool = BuddyPool::new((1u64 << MIN_K) as usize).unwrap();
+        pool.init();
+        let ptrs: Vec<*mut u8> = [10, 100, 1000, 10000, 100]
+            .iter()
+            .map(|&size| pool.malloc(size).unwrap())
+            .collect();
+        pool.free(ptrs[1]);
+        let free_blocks: usize = pool.free_counts.iter().sum();
+        let k = (0..MAX_K).find(|&k| pool.free_counts[k] > 0).unwrap();
+        unsafe { (*(pool.ptr_at(pool.avail[k].next) as *mut Avail)).next = UNLINKED };
+
+        assert_eq!(pool.repair(), Ok(free_blocks));
+        assert_eq!(pool.verify(), Ok(()));
+        for k in 0..=pool.kval_m {
+            assert_eq!(get_size_and_validate(&pool, k), pool.free_counts[k]);
+        }
+        for (i, ptr) in ptrs.into_iter().enumerate() {
+            if i != 1 {
+                pool.free(ptr);
+            }
+        }
+        check_buddy_pool_full(&pool);
+    }
+
+    /// Tests that tasks waiting on a locked AsyncBuddyPool sleep until it is unlocked instead of
+    /// polling in a loop, and that two contending tasks both complete
+    #[cfg(feature = "async_pool")]
+    #[test]
+    fn test_async_pool() {
+        use core::future::Future;
+        use core::pin::pin;
+        use core::sync::atomic::{AtomicUsize, Ordering};
+        use core::task::{Context, Poll, Waker};
+        use std::sync::Arc;
+        use std::task::Wake;
+        use std::thread;
+        use std::time::Duration;
+
+        struct ThreadWaker(thread::Thread);
+        impl Wake for ThreadWaker {
+            fn wake(self: Arc<Self>) {
+                self.0.unpark();
+            }
+        }
+        // Polls the future on the current thread, parking the thread until it is woken
+        fn block_on<F: Future>(future: F, polls: &AtomicUsize) -> F::Output {
+            let waker = Waker::from(Arc::new(ThreadWaker(thread::current())));
+            let mut cx = Context::from_waker(&waker);
+            let mut future = pin!(future);
+            loop {
+                polls.fetch_add(1, Ordering::SeqCst);
+                if let Poll::Ready(output) = future.as_mut().poll(&mut cx) {
+                    return output;
+                }
+                thread::park();
+            }
+        }
+
+        let pool = BuddyPool::new((1u64 << MIN_K) as usize).unwrap();
+        let pool = Arc::new(AsyncBuddyPool::new(ThreadSafeBuddyPool::new(pool)));
+        let polls = Arc::new([AtomicUsize::new(0), AtomicUsize::new(0)]);
+        let guard = pool.pool().lock();
+        let tasks: Vec<_> = (0..2)
+            .map(|i| {
+                let pool = Arc::clone(&pool);
+                let polls = Arc::clone(&polls);
+                thread::spawn(move || {
+                    let task = async {
+                        for size in 1..200 {
+                            let ptr = pool.malloc(size).await.unwrap();
+                            let ptr = pool.realloc(ptr, size * 2).await.unwrap();
+                            pool.free(ptr).await;
+                        }
+                    };
+                    block_on(task, &polls[i]);
+                })
+            })
+            .collect();
+        thread::sleep(Duration::from_millis(50));
+        for polls in polls.iter() {
+            assert!(polls.load(Ordering::SeqCst) <= 2);
+        }
+        drop(guard);
+        for task in tasks {
+            task.join().unwrap();
+        }
+        check_buddy_pool_full(&pool.pool().lock());
+    }
+
+    /// Tests that largest_allocation_ever reports the largest block handed out, even after it has
+    /// been freed
+    #[test]
+    fn test_largest_allocation_ever() {
+        let mut pool = BuddyPool::new((1u64 << MIN_K) as usize).unwrap();
+        pool.init();
+        assert_eq!(pool.largest_allocation_ever(), 0);
+        let a = pool.malloc(100).unwrap();
+        let b = pool.malloc(5000).unwrap();
+        pool.free(b);
+        let c = pool.malloc(1000).unwrap();
+        assert_eq!(pool.largest_allocation_ever(), 8192 - AVAIL_SIZE);
+        pool.free(a);
+        pool.free(c);
+        check_buddy_pool_full(&pool);
+    }
+
+    /// Tests that clone_topology_into leaves the destination with the same free lists as the
+    /// source, and that it rejects a destination of a different size
+    #[test]
+    fn test_clone_topology_into() {
+        let mut source = BuddyPool::new((1u64 << MIN_K) as usize).unwrap();
+        source.init();
+        let ptrs: Vec<*mut u8> = [10, 3000, 100, 70000, 500, 20]
+            .iter()
+            .map(|&size| source.malloc(size).unwrap())
+            .collect();
+        source.free(ptrs[1]);
+        source.free(ptrs[4]);
+
+        let mut dest = BuddyPool::new((1u64 << MIN_K) as usize).unwrap();
+        dest.init();
+        dest.malloc(1000).unwrap();
+        source.clone_topology_into(&mut dest).unwrap();
+        assert_eq!(dest.free_counts, source.free_counts);
+        let largest = |pool: &BuddyPool| (0..MAX_K).rev().find(|&k| pool.free_counts[k] > 0);
+        assert_eq!(largest(&dest), largest(&source));
+        assert_eq!(dest.used_bytes(), source.used_bytes());
+        assert_eq!(dest.live_allocations(), 4);
+        assert_eq!(dest.verify(), Ok(()));
+        for k in 0..=dest.kval_m {
+            assert_eq!(get_size_and_validate(&dest, k), dest.free_counts[k]);
+        }
+
+        for (i, &ptr) in ptrs.iter().enumerate() {
+            if i != 1 && i != 4 {
+                dest.free(dest.ptr_at(source.offset_of(ptr)));
+                source.free(ptr);
+            }
+        }
+        check_buddy_pool_full(&dest);
+        check_buddy_pool_full(&source);
+
+        let mut larger = BuddyPool::new((1u64 << (MIN_K + 1)) as usize).unwrap();
+        larger.init();
+        assert_eq!(
+            source.clone_topology_into(&mut larger),
+            Err(BuddyError::InvalidSize)
+        );
+    }
+
+    /// Tests that realloc to a size of 0 frees the block and returns ZERO_SIZE_PTR, which free
+    /// ignores and realloc treats like null
+    #[test]
+    fn test_realloc_zero_sentinel() {
+        let mut pool = BuddyPool::new((1u64 << MIN_K) as usize).unwrap();
+        pool.init();
+        for size in [0, 100, 5000] {
+            let ptr = pool.malloc(size).unwrap();
+            assert_eq!(pool.realloc(ptr, 0), Ok(ZERO_SIZE_PTR));
+            check_buddy_pool_full(&pool);
+        }
+        assert!(!ZERO_SIZE_PTR.is_null());
+        assert_eq!(ZERO_SIZE_PTR.addr() % align_of::<Avail>(), 0);
+        pool.free(ZERO_SIZE_PTR);
+        check_buddy_pool_full(&pool);
+
+        let ptr = pool.realloc(ZERO_SIZE_PTR, 100).unwrap();
+        assert!(pool.contains(ptr));
+        pool.free(ptr);
+        check_buddy_pool_full(&pool);
+    }
+
+    /// Tests that free_coalesce_cost reports no merges while the buddy is reserved and every merge
+    /// up to the whole pool once the rest of the pool is free
+    #[test]
+    fn test_free_coalesce_cost() {
+        let mut pool = BuddyPool::new((1u64 << MIN_K) as usize).unwrap();
+        pool.init();
+        let a = pool.malloc(100).unwrap();
+        let b = pool.malloc(100).unwrap();
+        let kval = b_to_k(100 + AVAIL_SIZE);
+        assert_eq!(pool.free_coalesce_cost(a), 0);
+        pool.free(b);
+        assert_eq!(pool.free_coalesce_cost(a), pool.kval_m - kval);
+        assert_eq!(pool.free_coalesce_cost(ptr::null_mut()), 0);
+        pool.free(a);
+        check_buddy_pool_full(&pool);
+    }
+
+    /// Tests that every block header is aligned for Avail, and that from_mmap rejects a mapping
+    /// that would put the headers out of alignment
+    #[cfg(feature = "std")]
+    #[test]
+    fn test_header_alignment() {
+        let mut pool = BuddyPool::new((1u64 << MIN_K) as usize).unwrap();
+        pool.init();
+        let ptrs = [
+            pool.malloc(1).unwrap(),
+            pool.malloc(100).unwrap(),
+            pool.malloc_aligned(100, 256).unwrap(),
+            pool.malloc_block_aligned(64).unwrap(),
+            pool.malloc(5000).unwrap(),
+        ];
+        let base = pool.base.as_ptr().addr();
+        for (offset, _, _) in pool.blocks() {
+            assert_eq!((base + offset) % align_of::<Avail>(), 0);
+        }
+        for ptr in ptrs {
+            pool.free(ptr);
+        }
+        check_buddy_pool_full(&pool);
+
+        let path = std::env::temp_dir().join(std::format!("p3-align-{}", std::process::id()));
+        let file = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&path)
+            .unwrap();
+        let len = (1u64 << MIN_K) as usize;
+        file.set_len(len as u64 + 4096).unwrap();
+        let map = unsafe {
+            memmap2::MmapOptions::new()
+                .offset(3)
+                .len(len)
+                .map_mut(&file)
+        }
+        .unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(
+            BuddyPool::from_mmap(map).err(),
+            Some(BuddyError::InvalidAlignment)
+        );
+    }
+
+    /// Tests an allocate, write, free and coalesce cycle touching every kind of block. This is the
+    /// test to run under miri to check that every pointer into a block keeps the provenance of the
+    /// pool base:
+    /// MIRIFLAGS="-Zmiri-strict-provenance -Zmiri-disable-stacked-borrows" cargo +nightly miri test
+    /// Stacked borrows has to be off, as the avail list heads point into the pool struct itself
+    /// and every &mut borrow of the pool retags them
+    #[test]
+    fn test_strict_provenance() {
+        let mut pool = BuddyPool::new((1u64 << MIN_K) as usize).unwrap();
+        pool.init();
+        let ptrs = [
+            pool.malloc(100).unwrap(),
+            pool.malloc_no_split(200).unwrap(),
+            pool.malloc_aligned(64, 128).unwrap(),
+            pool.malloc_block_aligned(256).unwrap(),
+        ];
+        for (i, &ptr) in ptrs.iter().enumerate() {
+            unsafe { ptr.write_bytes(i as u8, 64) };
+        }
+        let grown = pool.realloc(ptrs[0], 1000).unwrap();
+        assert_eq!(unsafe { *grown.add(63) }, 0);
+        pool.free(grown);
+        for &ptr in &ptrs[1..] {
+            pool.free(ptr);
+        }
+        check_buddy_pool_full(&pool);
+    }
+
+    /// Tests that malloc_contiguous carves blocks that follow each other, which can be freed one at
+    /// a time
+    #[test]
+    fn test_malloc_contiguous() {
+        let mut pool = BuddyPool::new((1u64 << MIN_K) as usize).unwrap();
+        pool.init();
+        assert_eq!(pool.malloc_contiguous(100, 0), Err(BuddyError::InvalidSize));
+        let rounded = 1usize << b_to_k(100 + size_of::<Avail>());
+        let first = pool.malloc_contiguous(100, 4).unwrap();
+        let blocks: Vec<*mut u8> = (0..4).map(|i| unsafe { first.add(i * rounded) }).collect();
+        for pair in blocks.windows(2) {
+            assert_eq!(pair[1].addr() - pair[0].addr(), rounded);
+        }
+        for (i, &block) in blocks.iter().enumerate() {
+            assert_eq!(
+                pool.usable_size_aligned(block),
+                rounded - size_of::<Avail>()
+            );
+            unsafe { block.write_bytes(i as u8, 100) };
+        }
+        assert_eq!(pool.live_allocations(), 4);
+        // A count that is not a power of two gives the rest of the block back
+        let odd = pool.malloc_contiguous(100, 3).unwrap();
+        assert_eq!(pool.used_bytes(), 7 * rounded);
+        for &block in blocks.iter().rev() {
+            pool.free(block);
+        }
+        for i in 0..3 {
+            pool.free(unsafe { odd.add(i * rounded) });
+        }
+        check_buddy_pool_full(&pool);
+
+        // Counts whose power of two overflows, or whose blocks outgrow the pool, fail cleanly
+        assert_eq!(
+            pool.malloc_contiguous(16, usize::MAX),
+            Err(BuddyError::NoMemory)
+        );
+        assert_eq!(
+            pool.malloc_contiguous(16, (1 << MIN_K) / 64 + 1),
+            Err(BuddyError::NoMemory)
+        );
+        assert_eq!(pool.failure_count(), 2);
+
+        // The reserve floor counts the blocks handed out, not the block they were carved from
+        let mut pool = BuddyPool::builder()
+            .size((1u64 << MIN_K) as usize)
+            .reserve_floor_bytes(1 << (MIN_K - 2))
+            .build()
+            .unwrap();
+        pool.init();
+        let quarter = (1 << (MIN_K - 2)) - AVAIL_SIZE;
+        assert_eq!(
+            pool.malloc_contiguous(quarter, 4),
+            Err(BuddyError::ReserveFloor)
+        );
+        let first = pool.malloc_contiguous(quarter, 3).unwrap();
+        for i in 0..3 {
+            pool.free(unsafe { first.add(i << (MIN_K - 2)) });
+        }
+        check_buddy_pool_full(&pool);
+    }
+
+    /// Tests that could_allocate counts buddies left unmerged by a no coalesce range as the block
+    /// they would merge into, without merging them
+    #[test]
+    fn test_could_allocate() {
+        let mut pool = BuddyPool::new((1u64 << MIN_K) as usize).unwrap();
+        pool.init();
+        let whole = (1usize << MIN_K) - AVAIL_SIZE;
+        assert!(pool.could_allocate(whole));
+        assert!(!pool.could_allocate(whole + 1));
+        pool.set_no_coalesce_range(0, MIN_K - 1);
+        let a = pool.malloc(1000).unwrap();
+        let b = pool.malloc(1000).unwrap();
+        pool.free(a);
+        assert!(!pool.could_allocate(whole));
+        pool.free(b);
+        assert_eq!(pool.free_counts[pool.kval_m], 0);
+        assert!(pool.could_allocate(whole));
+        assert_eq!(pool.free_counts[pool.kval_m], 0);
+        pool.set_no_coalesce_range(1, 0);
+        assert_eq!(pool.coalesce_and_report(), whole);
+        check_buddy_pool_full(&pool);
+    }
+
+    /// Tests that allocation_table lists every block in address order and that the blocks cover
+    /// the whole pool
+    #[cfg(feature = "std")]
+    #[test]
+    fn test_allocation_table() {
+        let mut pool = BuddyPool::new((1u64 << MIN_K) as usize).unwrap();
+        pool.init();
+        let unit = 1usize << (MIN_K - 3);
+        let a = pool.malloc(2 * unit - AVAIL_SIZE).unwrap();
+        let b = pool.malloc(unit - AVAIL_SIZE).unwrap();
+        let table = pool.allocation_table();
+        assert_eq!(
+            table,
+            [
+                (0, 2 * unit, BlockTag::Reserved),
+                (2 * unit, unit, BlockTag::Reserved),
+                (3 * unit, unit, BlockTag::Avail),
+                (4 * unit, 4 * unit, BlockTag::Avail),
+            ]
+        );
+        assert_eq!(
+            table.iter().map(|&(_, size, _)| size).sum::<usize>(),
+            pool.capacity()
+        );
+        pool.free(a);
+        pool.free(b);
+        assert_eq!(pool.allocation_table(), [(0, 8 * unit, BlockTag::Avail)]);
+    }
+
+    /// Tests that state_checksum comes back after an allocation is freed, changes while it is live
+    /// and notices a corrupted list
+    #[test]
+    fn test_state_checksum() {
+        let mut pool = BuddyPool::new((1u64 << MIN_K) as usize).unwrap();
+        pool.init();
+        let kept = pool.malloc(100).unwrap();
+        let checksum = pool.state_checksum();
+        assert_eq!(pool.state_checksum(), checksum);
+        let mem = pool.malloc(5000).unwrap();
+        let live = pool.state_checksum();
+        assert_ne!(live, checksum);
+        pool.free(mem);
+        assert_eq!(pool.state_checksum(), checksum);
+        let k = pool.kval_m - 1;
+        let node = pool.ptr_at(pool.avail[k].next) as *mut Avail;
+        unsafe { (*node).kval = k - 1 };
+        assert_ne!(pool.state_checksum(), checksum);
+        unsafe { (*node).kval = k };
+        assert_eq!(pool.state_checksum(), checksum);
+        pool.free(kept);
+        check_buddy_pool_full(&pool);
+    }
+
+    /// Tests that a pool bound to NUMA node 0, which exists on every NUMA system, allocates as
+    /// usual and stays bound when it grows. On a kernel without NUMA support the pool is left
+    /// unbound. A node that can not exist is never bound.
+    #[cfg(all(feature = "numa", target_os = "linux"))]
+    #[test]
+    fn test_numa_node() {
+        let size = (1u64 << MIN_K) as usize;
+        let mut pool = BuddyPool::builder()
+            .size(size)
+            .numa_node(0)
+            .build()
+            .unwrap();
+        pool.init();
+        let bound = pool.numa_node();
+        assert!(bound.is_none() || bound == Some(0));
+        assert_eq!(pool.config().numa_node, bound);
+        let mem = pool.malloc(5000).unwrap();
+        unsafe { mem.write_bytes(0xAB, 5000) };
+        pool.grow(2 * size).unwrap();
+        assert_eq!(pool.numa_node(), bound);
+        // Growing may move the pool, so the block is found again by its offset
+        pool.free(pool.ptr_at(AVAIL_SIZE));
+        check_buddy_pool_full(&pool);
+
+        let pool = BuddyPool::builder()
+            .size(size)
+            .numa_node(u32::MAX)
+            .build()
+            .unwrap();
+        assert_eq!(pool.numa_node(), None);
+    }
+
+    /// Tests that shrinking a block by several kvals in one realloc keeps it in place with its
+    /// data, and gives back the upper half carved off at each level
+    #[test]
+    fn test_realloc_shrink_many_kvals() {
+        let mut pool = BuddyPool::new((1u64 << MIN_K) as usize).unwrap();
+        pool.init();
+        let mem = pool.malloc(4096).unwrap();
+        let old_kval = b_to_k(4096 + AVAIL_SIZE);
+        let new_kval = b_to_k(16 + AVAIL_SIZE);
+        for i in 0..4096 {
+            unsafe { *mem.add(i) = i as u8 };
+        }
+        for k in new_kval..old_kval {
+            assert_eq!(pool.free_counts[k], 0);
+        }
+        let shrunk = pool.realloc(mem, 16).unwrap();
+        assert_eq!(shrunk, mem);
+        let kept = (1usize << new_kval) - AVAIL_SIZE;
+        for i in 0..kept {
+            assert_eq!(unsafe { *shrunk.add(i) }, i as u8);
+        }
+        for k in new_kval..pool.kval_m {
+            assert_eq!(pool.free_counts[k], 1);
+            assert_eq!(pool.avail[k].next, 1 << k);
+            let node = pool.ptr_at(pool.avail[k].next) as *const Avail;
+            assert_eq!(unsafe { (*node).state() }, BLOCK_AVAIL);
+        }
+        pool.free(shrunk);
+        check_buddy_pool_full(&pool);
+    }
+
+    /// Tests that overhead_ratio is high for many tiny allocations and low for a few large ones
+    #[test]
+    fn test_overhead_ratio() {
+        let mut pool = BuddyPool::new((1u64 << MIN_K) as usize).unwrap();
+        pool.init();
+        assert_eq!(pool.overhead_ratio(), 0.0);
+        let tiny: Vec<*mut u8> = (0..100).map(|_| pool.malloc(1).unwrap()).collect();
+        assert_eq!(pool.overhead_ratio(), 0.5);
+        for &ptr in &tiny {
+            pool.free(ptr);
+        }
+        let large: Vec<*mut u8> = (0..3).map(|_| pool.malloc(100_000).unwrap()).collect();
+        assert!(pool.overhead_ratio() < 0.001);
+        for &ptr in &large {
+            pool.free(ptr);
+        }
+        check_buddy_pool_full(&pool);
+    }
+
+    /// Tests that bump_alloc packs aligned allocations into its region without headers, and that
+    /// bump_reset lets the region be reused
+    #[test]
+    fn test_bump_alloc() {
+        let mut pool = BuddyPool::new((1u64 << MIN_K) as usize).unwrap();
+        pool.init();
+        assert_eq!(pool.bump_alloc(8, 8), None);
+
+        let mut pool = BuddyPool::builder()
+            .size((1u64 << MIN_K) as usize)
+            .bump_region_bytes(4096)
+            .build()
+            .unwrap();
+        pool.init();
+        assert_eq!(pool.config().bump_region_bytes, 4096);
+        assert_eq!(pool.bump_alloc(8, 3), None);
+        let first = pool.bump_alloc(24, 8).unwrap();
+        assert_eq!(pool.live_allocations(), 1);
+        let used = pool.used_bytes();
+        assert_eq!(used, 1 << b_to_k(4096 + AVAIL_SIZE));
+        // Packed right after the previous allocation with no header in between
+        let second = pool.bump_alloc(8, 8).unwrap();
+        assert_eq!(second.addr(), first.addr() + 24);
+        let aligned = pool.bump_alloc(100, 256).unwrap();
+        assert!(aligned.addr().is_multiple_of(256));
+        unsafe { aligned.write_bytes(0xAB, 100) };
+        assert_eq!(pool.bump_alloc(4096, 1), None);
+        assert_eq!(pool.used_bytes(), used);
+        assert_eq!(pool.live_allocations(), 1);
+
+        pool.bump_reset();
+        assert_eq!(pool.bump_alloc(24, 8), Some(first));
+        assert!(pool.bump_alloc(4096 - 24, 1).is_some());
+        assert_eq!(pool.bump_alloc(1, 1), None);
+        assert_eq!(pool.used_bytes(), used);
+    }
+
+    /// Tests that is_page_contained accepts a small block within a page and rejects a block that
+    /// spans pages
+    #[test]
+    fn test_is_page_contained() {
+        let mut pool = BuddyPool::new((1u64 << MIN_K) as usize).unwrap();
+        pool.init();
+        let page = page_size();
+        assert!(!pool.is_page_contained(ptr::null_mut()));
+        let small = pool.malloc(100).unwrap();
+        assert!(pool.is_page_contained(small));
+        let large = pool.malloc(2 * page).unwrap();
+        assert!(!pool.is_page_contained(large));
+        pool.free(small);
+        pool.free(large);
+        check_buddy_pool_full(&pool);
+    }
+
+    /// Tests that with grow_in_place a growing realloc merges the block with a free lower buddy,
+    /// moving the data down to the start of the merged block, and falls back to moving the block
+    /// when its buddy is reserved
+    #[test]
+    fn test_realloc_grow_in_place() {
+        let mut pool = BuddyPool::builder()
+            .size((1u64 << MIN_K) as usize)
+            .grow_in_place(true)
+            .build()
+            .unwrap();
+        pool.init();
+        assert!(pool.config().grow_in_place);
+        let unit = 1usize << 10;
+        let lower = pool.malloc(unit - AVAIL_SIZE).unwrap();
+        let mem = pool.malloc(unit - AVAIL_SIZE).unwrap();
+        let guard = pool.malloc(2 * unit - AVAIL_SIZE).unwrap();
+        for i in 0..unit - AVAIL_SIZE {
+            unsafe { *mem.add(i) = i as u8 };
+        }
+        pool.free(lower);
+        let used = pool.used_bytes();
+        let grown = pool.realloc(mem, 2 * unit - AVAIL_SIZE).unwrap();
+        assert_eq!(grown, lower);
+        assert_eq!(pool.used_bytes(), used + unit);
+        for i in 0..unit - AVAIL_SIZE {
+            assert_eq!(unsafe { *grown.add(i) }, i as u8);
+        }
+        // The buddy of the merged block is reserved, so growing again moves the block
+        let moved = pool.realloc(grown, 4 * unit - AVAIL_SIZE).unwrap();
+        assert_ne!(moved, grown);
+        assert_eq!(unsafe { *moved.add(100) }, 100);
+        pool.free(moved);
+        pool.free(guard);
+        check_buddy_pool_full(&pool);
+    }
+
+    /// Tests that depth_histogram counts free and reserved blocks of each kval and that they add
+    /// up to the capacity
+    #[test]
+    fn test_depth_histogram() {
+        let mut pool = BuddyPool::new((1u64 << MIN_K) as usize).unwrap();
+        pool.init();
+        let mut expected = [0; MAX_K];
+        expected[MIN_K] = 1;
+        assert_eq!(pool.depth_histogram(), expected);
+        let small = MIN_K - 4;
+        let a = pool.malloc((1 << small) - AVAIL_SIZE).unwrap();
+        let b = pool.malloc((1 << small) - AVAIL_SIZE).unwrap();
+        let c = pool.malloc((1 << (MIN_K - 1)) - AVAIL_SIZE).unwrap();
+        // Two reserved blocks of small, free blocks of small + 1 and small + 2, and the upper half
+        let mut expected = [0; MAX_K];
+        expected[small] = 2;
+        expected[small + 1] = 1;
+        expected[small + 2] = 1;
+        expected[MIN_K - 1] = 1;
+        let histogram = pool.depth_histogram();
+        assert_eq!(histogram, expected);
+        let total: usize = histogram.iter().enumerate().map(|(k, &n)| n << k).sum();
+        assert_eq!(total, pool.capacity());
+        pool.free(a);
+        pool.free(b);
+        pool.free(c);
+        check_buddy_pool_full(&pool);
+    }
+
+    /// Tests GlobalBuddy through the GlobalAlloc interface: plain and over-aligned blocks come
+    /// from its pool, realloc keeps their contents and alignment, and dealloc returns everything
+    #[test]
+    fn test_global_alloc() {
+        use core::alloc::{GlobalAlloc, Layout};
+
+        let global = GlobalBuddy::new((1u64 << MIN_K) as usize);
+        let plain = Layout::from_size_align(100, 8).unwrap();
+        let aligned = Layout::from_size_align(256, 256).unwrap();
+        unsafe {
+            let ptr = global.alloc(plain);
+            ptr.write_bytes(1, plain.size());
+            let ptr = global.realloc(ptr, plain, 10_000);
+            assert!((0..plain.size()).all(|i| *ptr.add(i) == 1));
+
+            let big = global.alloc(aligned);
+            assert!(big.addr().is_multiple_of(256));
+            big.write_bytes(7, aligned.size());
+            let big = global.realloc(big, aligned, 4096);
+            assert!(big.addr().is_multiple_of(256));
+            assert!((0..aligned.size()).all(|i| *big.add(i) == 7));
+
+            assert_eq!(
+                global.with_pool(|pool| [ptr, big].map(|p| pool.contains(p))),
+                Some([true; 2])
+            );
+            global.dealloc(ptr, Layout::from_size_align(10_000, 8).unwrap());
+            global.dealloc(big, Layout::from_size_align(4096, 256).unwrap());
+        }
+        global.with_pool(|pool| check_buddy_pool_full(pool));
+    }
+
+    /// Tests that a panic while a GlobalBuddy is locked releases the lock, so later allocations
+    /// do not spin forever
+    #[test]
+    fn test_global_alloc_panic_unlocks() {
+        use core::alloc::{GlobalAlloc, Layout};
+        use std::panic::{self, AssertUnwindSafe};
+
+        let global = GlobalBuddy::new((1u64 << MIN_K) as usize);
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            global.with_pool(|_| panic!("panic with the pool locked"))
+        }));
+        assert!(result.is_err());
+        let layout = Layout::from_size_align(100, 8).unwrap();
+        unsafe {
+            let ptr = global.alloc(layout);
+            assert!(!ptr.is_null());
+            global.dealloc(ptr, layout);
+        }
+        global.with_pool(|pool| check_buddy_pool_full(pool));
+    }
+
+    /// Tests that calloc zeroes the whole usable region of blocks that held other data, leaves the
+    /// header intact and fails on overflow
+    #[test]
+    fn test_calloc() {
+        let mut pool = BuddyPool::new((1u64 << MIN_K) as usize).unwrap();
+        pool.init();
+        // Dirty the pool so that calloc has something to zero
+        let dirty = pool.malloc((1 << (MIN_K - 1)) - AVAIL_SIZE).unwrap();
+        unsafe { dirty.write_bytes(0xFF, (1 << (MIN_K - 1)) - AVAIL_SIZE) };
+        pool.free(dirty);
+        let mut ptrs = Vec::new();
+        for (count, size) in [(1, 1), (3, 7), (10, 100), (16, 1024), (1, 60_000)] {
+            let ptr = pool.calloc(count, size).unwrap();
+            let usable = pool.usable_size_aligned(ptr);
+            assert!(usable >= count * size);
+            assert!(unsafe { pool.as_slice_mut(ptr) }.iter().all(|&b| b == 0));
+            assert_eq!(unsafe { (*pool.header_of(ptr)).state() }, BLOCK_RESERVED);
+            ptrs.push(ptr);
+        }
+        #[cfg(feature = "errno")]
+        set_errno(0);
+        assert_eq!(pool.calloc(usize::MAX, 2), Err(BuddyError::NoMemory));
+        #[cfg(feature = "errno")]
+        assert_eq!(errno().0, ENOMEM);
+        for ptr in ptrs {
+            pool.free(ptr);
+        }
+        check_buddy_pool_full(&pool);
+    }
+
+    /// Tests that try_grow_in_place merges a block with its free upper buddies, and leaves the
+    /// block and errno untouched when a buddy is reserved or lies below the block
+    #[test]
+    fn test_try_grow_in_place() {
+        let mut pool = BuddyPool::new((1u64 << MIN_K) as usize).unwrap();
+        pool.init();
+        let unit = 1usize << 10;
+        let mem = pool.malloc(unit - AVAIL_SIZE).unwrap();
+        let upper = pool.malloc(unit - AVAIL_SIZE).unwrap();
+        let guard = pool.malloc(2 * unit - AVAIL_SIZE).unwrap();
+        unsafe { mem.write_bytes(0x5A, unit - AVAIL_SIZE) };
+        #[cfg(feature = "errno")]
+        set_errno(0);
+        let used = pool.used_bytes();
+        assert_eq!(
+            pool.try_grow_in_place(mem, 2 * unit - AVAIL_SIZE),
+            Err(BuddyError::NoMemory)
+        );
+        assert_eq!(pool.usable_size_aligned(mem), unit - AVAIL_SIZE);
+        assert_eq!(pool.used_bytes(), used);
+        assert!(unsafe { pool.as_slice_mut(mem) }.iter().all(|&b| b == 0x5A));
+
+        // The lower buddy of upper is free, but growing into it would move the block
+        pool.free(mem);
+        assert_eq!(
+            pool.try_grow_in_place(upper, 2 * unit - AVAIL_SIZE),
+            Err(BuddyError::NoMemory)
+        );
+        #[cfg(feature = "errno")]
+        assert_eq!(errno().0, 0);
+        pool.free(upper);
+
+        let mem = pool.malloc(unit - AVAIL_SIZE).unwrap();
+        unsafe { mem.write_bytes(0x5A, unit - AVAIL_SIZE) };
+        assert_eq!(pool.try_grow_in_place(mem, 2 * unit - AVAIL_SIZE), Ok(()));
+        assert_eq!(pool.usable_size_aligned(mem), 2 * unit - AVAIL_SIZE);
+        assert!(unsafe { pool.as_slice_mut(mem) }[..unit - AVAIL_SIZE]
+            .iter()
+            .all(|&b| b == 0x5A));
+        assert_eq!(pool.try_grow_in_place(mem, 100), Ok(()));
+        #[cfg(feature = "errno")]
+        assert_eq!(errno().0, 0);
+        pool.free(mem);
+        pool.free(guard);
+        check_buddy_pool_full(&pool);
+    }
+
+    /// Tests that base_addr is page aligned and base_len covers the pool, and that they bound the
+    /// pointers malloc returns
+    #[test]
+    fn test_base_addr() {
+        let mut pool = BuddyPool::new((1u64 << MIN_K) as usize).unwrap();
+        pool.init();
+        assert_ne!(pool.base_addr(), 0);
+        assert!(pool.base_addr().is_multiple_of(page_size()));
+        assert_eq!(pool.base_len(), pool.capacity());
+        let mem = pool.malloc(100).unwrap();
+        assert_eq!(mem.addr() - pool.base_addr(), AVAIL_SIZE);
+        pool.free(mem);
+    }
+
+    /// Tests that malloc_aligned honours 16, 64 and 4096 byte alignments, rejects an alignment that
+    /// is not a power of two, and that free finds the header of every aligned pointer
+    #[test]
+    fn test_malloc_aligned() {
+        let mut pool = BuddyPool::new((1u64 << MIN_K) as usize).unwrap();
+        pool.init();
+        #[cfg(feature = "errno")]
+        set_errno(0);
+        assert_eq!(
+            pool.malloc_aligned(100, 48),
+            Err(BuddyError::InvalidAlignment)
+        );
+        #[cfg(feature = "errno")]
+        assert_eq!(errno().0, EINVAL);
+        for round in 0..2 {
+            let mut mem = Vec::new();
+            for align in [16, 64, 4096] {
+                // An odd sized block first so the aligned one does not land aligned by chance
+                mem.push(pool.malloc(1 + round * 200).unwrap());
+                let ptr = pool.malloc_aligned(100, align).unwrap();
+                assert!(ptr.addr().is_multiple_of(align));
+                unsafe { ptr.write_bytes(0xCD, 100) };
+                mem.push(ptr);
+            }
+            for ptr in mem {
+                pool.free(ptr);
+            }
+            check_buddy_pool_full(&pool);
+        }
+    }
+
+    /// Tests that the flag bits of a block tag can be set and read independently of each other
+    /// and of the state bits, and that a flagged free buddy still coalesces
+    #[test]
+    fn test_tag_flags() {
+        let mut pool = BuddyPool::new((1u64 << MIN_K) as usize).unwrap();
+        pool.init();
+        let half = (1usize << (MIN_K - 1)) - AVAIL_SIZE;
+        let lower = pool.malloc(half).unwrap();
+        let upper = pool.malloc(half).unwrap();
+        let other_flag = 1 << 5;
+        unsafe {
+            let header = &mut *pool.header_of(lower);
+            assert_eq!(header.state(), BLOCK_RESERVED);
+            header.set_flag(TAG_ALIGNED, true);
+            assert!(header.has_flag(TAG_ALIGNED));
+            assert!(!header.has_flag(other_flag));
+            header.set_flag(other_flag, true);
+            assert!(header.has_flag(TAG_ALIGNED) && header.has_flag(other_flag));
+            header.set_flag(TAG_ALIGNED, false);
+            assert!(!header.has_flag(TAG_ALIGNED) && header.has_flag(other_flag));
+            assert_eq!(header.state(), BLOCK_RESERVED);
+            header.set_state(BLOCK_UNUSED);
+            assert!(header.has_flag(other_flag));
+            header.set_state(BLOCK_RESERVED);
+            header.set_flag(other_flag, false);
+        }
+
+        // The flag on the free buddy must not hide its state from the coalescing in free
+        pool.free(upper);
+        unsafe {
+            let header = &mut *pool.header_of(upper);
+            header.set_flag(other_flag, true);
+            assert_eq!(header.state(), BLOCK_AVAIL);
+        }
+        pool.free(lower);
+        check_buddy_pool_full(&pool);
+
+        // malloc_aligned flags the header when it moves the pointer, and the flag is dropped once
+        // the block is handed out again
+        let mem = pool.malloc_aligned(100, 4096).unwrap();
+        unsafe {
+            let header = pool.header_of(mem);
+            assert_ne!(header as *mut u8, mem.wrapping_sub(AVAIL_SIZE));
+            assert!((*header).has_flag(TAG_ALIGNED));
+            assert_eq!((*header).state(), BLOCK_RESERVED);
+        }
+        pool.free(mem);
+        let mem = pool.malloc(100).unwrap();
+        unsafe { assert!(!(*pool.header_of(mem)).has_flag(TAG_ALIGNED)) };
+        pool.free(mem);
+        check_buddy_pool_full(&pool);
+    }
+
+    /// Tests that usable_size returns the block size minus the header, which covers the request,
+    /// and 0 for a null pointer
+    #[test]
+    fn test_usable_size() {
+        let mut pool = BuddyPool::new((1u64 << MIN_K) as usize).unwrap();
+        pool.init();
+        assert_eq!(pool.usable_size(ptr::null_mut()), 0);
+        let mem = pool.malloc(100).unwrap();
+        let usable = pool.usable_size(mem);
+        assert_eq!(usable, (1 << b_to_k(100 + AVAIL_SIZE)) - AVAIL_SIZE);
+        assert!(usable >= 100);
+        unsafe { mem.write_bytes(0xA5, usable) };
+        pool.free(mem);
+        check_buddy_pool_full(&pool);
+    }
+
+    /// Tests that malloc_min_kval hands out a block of at least min_kval, and that the block can
+    /// then grow up to that size without moving. Like malloc it tells the observer about each
+    /// block, and its failures call the OOM handler and are counted.
+    #[test]
+    fn test_malloc_min_kval() {
+        use core::sync::atomic::{AtomicUsize, Ordering};
+        // The number of blocks the observer was told about
+        static MALLOCS: AtomicUsize = AtomicUsize::new(0);
+
+        let mut pool = BuddyPool::new((1u64 << MIN_K) as usize).unwrap();
+        pool.init();
+        pool.set_observer(|event| {
+            if let AllocEvent::Malloc { .. } = event {
+                MALLOCS.fetch_add(1, Ordering::Relaxed);
+            }
+        });
+        let min_kval = 12;
+        let mem = pool.malloc_min_kval(16, min_kval).unwrap();
+        assert_eq!(unsafe { (*pool.header_of(mem)).kval }, min_kval);
+        unsafe { mem.write_bytes(0x3C, 16) };
+        let mem2 = pool.realloc(mem, (1 << min_kval) - AVAIL_SIZE).unwrap();
+        assert_eq!(mem2, mem);
+        assert!(unsafe { pool.as_slice_mut(mem) }[..16].iter().all(|&b| b == 0x3C));
+
+        // A request larger than the floor still gets the block it needs
+        let big = pool.malloc_min_kval(1 << 13, min_kval).unwrap();
+        assert_eq!(unsafe { (*pool.header_of(big)).kval }, 14);
+        assert_eq!(MALLOCS.load(Ordering::Relaxed), 2);
+
+        pool.set_oom_handler(|_, _| false);
+        assert_eq!(
+            pool.malloc_min_kval(16, MIN_K),
+            Err(BuddyError::PoolNotEmpty)
+        );
+        assert_eq!(
+            pool.malloc_with_hint(16, usize::MAX),
+            Err(BuddyError::NoMemory)
+        );
+        assert_eq!(pool.oom_stats(), (1, 0));
+        assert_eq!(pool.failure_count(), 2);
+        assert_eq!(MALLOCS.load(Ordering::Relaxed), 2);
+        pool.free(big);
+        pool.free(mem);
+        check_buddy_pool_full(&pool);
+    }
+
+    /// Tests that growing a block by moving it copies its usable bytes up to the last one, and
+    /// nothing past the end of the block into the new one or over its neighbour
+    #[test]
+    fn test_realloc_grow_copies_usable_bytes() {
+        let mut pool = BuddyPool::new((1u64 << MIN_K) as usize).unwrap();
+        pool.init();
+        let usable = (1usize << 10) - AVAIL_SIZE;
+        let mem = pool.malloc(usable).unwrap();
+        let neighbour = pool.malloc(usable).unwrap();
+        assert_eq!(neighbour, mem.wrapping_add(usable + AVAIL_SIZE));
+        for i in 0..usable {
+            unsafe { mem.add(i).write(i as u8 ^ 0xA5) };
+        }
+        unsafe { neighbour.write_bytes(0x5A, usable) };
+
+        let grown = pool.realloc(mem, 4 * usable).unwrap();
+        assert_ne!(grown, mem);
+        let bytes = unsafe { pool.as_slice_mut(grown) };
+        assert!((0..usable).all(|i| bytes[i] == i as u8 ^ 0xA5));
+        assert!(unsafe { pool.as_slice_mut(neighbour) }.iter().all(|&b| b == 0x5A));
+        pool.free(grown);
+        pool.free(neighbour);
+        check_buddy_pool_full(&pool);
+    }
+
+    /// Tests that a maintainer thread merges the buddies another thread left unmerged, and that it
+    /// stops when asked
+    #[cfg(feature = "maintainer")]
+    #[test]
+    fn test_spawn_maintainer() {
+        use std::sync::Arc;
+        use std::thread;
+        use std::time::{Duration, Instant};
+
+        let pool = BuddyPool::new((1u64 << MIN_K) as usize).unwrap();
+        let pool = Arc::new(ThreadSafeBuddyPool::new(pool));
+        let maintainer = spawn_maintainer(Arc::clone(&pool), Duration::from_millis(5));
+        let fragmenter = Arc::clone(&pool);
+        let free_blocks = thread::spawn(move || {
+            let mut pool = fragmenter.lock();
+            pool.set_no_coalesce_range(10, 10);
+            let ptrs: Vec<_> = (0..64)
+                .map(|_| pool.malloc(1000 - AVAIL_SIZE).unwrap())
+                .collect();
+            for ptr in ptrs {
+                pool.free(ptr);
+            }
+            pool.set_no_coalesce_range(1, 0);
+            pool.free_counts.iter().sum::<usize>()
+        })
+        .join()
+        .unwrap();
+        assert!(free_blocks > 64);
+
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while pool.lock().free_counts.iter().sum::<usize>() > 1 {
+            assert!(
+                Instant::now() < deadline,
+                "the maintainer never coalesced the pool"
+            );
+            thread::sleep(Duration::from_millis(1));
+        }
+        maintainer.stop();
+        check_buddy_pool_full(&pool.lock());
+    }
+
+    /// Tests that sizes that overflow once the header is added fail with NoMemory instead of
+    /// wrapping around to a small block
+    #[test]
+    fn test_malloc_size_overflow() {
+        let mut pool = BuddyPool::new((1u64 << MIN_K) as usize).unwrap();
+        pool.init();
+        for size in [usize::MAX, usize::MAX - 8] {
+            #[cfg(feature = "errno")]
+            set_errno(0);
+            assert_eq!(pool.malloc(size), Err(BuddyError::NoMemory));
+            #[cfg(feature = "errno")]
+            assert_eq!(errno().0, ENOMEM);
+        }
+        assert_eq!(pool.calloc(1, usize::MAX), Err(BuddyError::NoMemory));
+        let mem = pool.malloc(100).unwrap();
+        #[cfg(feature = "errno")]
+        set_errno(0);
+        assert_eq!(pool.realloc(mem, usize::MAX - 8), Err(BuddyError::NoMemory));
+        #[cfg(feature = "errno")]
+        assert_eq!(errno().0, ENOMEM);
+        assert!(!pool.could_allocate(usize::MAX));
+        pool.free(mem);
+        check_buddy_pool_full(&pool);
+    }
+
+    /// Tests that with trailing headers malloc returns block aligned pointers whose header sits at
+    /// the end of the block, and that free finds it there and coalesces the blocks again
+    #[test]
+    fn test_trailing_headers() {
+        let mut pool = BuddyPool::builder()
+            .size((1u64 << MIN_K) as usize)
+            .trailing_headers(true)
+            .build()
+            .unwrap();
+        pool.init();
+        let sizes = [1, 100, 1000 - AVAIL_SIZE, 5000, 3];
+        let ptrs: Vec<_> = sizes
+            .iter()
+            .map(|&size| pool.malloc(size).unwrap())
+            .collect();
+        for (&ptr, &size) in ptrs.iter().zip(&sizes) {
+            let block = 1usize << b_to_k(size + AVAIL_SIZE);
+            assert!(pool.offset_of(ptr).is_multiple_of(block));
+            assert_eq!(pool.usable_size(ptr), block - AVAIL_SIZE);
+            // Filling the whole usable region must leave the trailer intact
+            unsafe { ptr.write_bytes(0xFF, block - AVAIL_SIZE) };
+            let trailer = unsafe { &*(ptr.add(block - AVAIL_SIZE) as *const Avail) };
+            assert!(trailer.has_flag(TAG_TRAILING));
+            assert_eq!(1usize << trailer.kval, block);
+        }
+        assert_eq!(pool.verify(), Ok(()));
+        let handle = pool.handle_of(ptrs[1]);
+        assert_eq!(pool.resolve(handle), Some(ptrs[1]));
+        let grown = pool.realloc(ptrs[4], 2000).unwrap();
+        assert!(pool.offset_of(grown).is_multiple_of(2048));
+        pool.free(ptrs[1]);
+        assert_eq!(pool.resolve(handle), None);
+        for ptr in [ptrs[0], ptrs[2], ptrs[3], grown] {
+            pool.free(ptr);
+        }
+        check_buddy_pool_full(&pool);
+    }
+
+    /// Tests that stats reports the used, free and largest free bytes of a known allocation pattern
+    #[test]
+    fn test_stats() {
+        let mut pool = BuddyPool::new((1u64 << MIN_K) as usize).unwrap();
+        pool.init();
+        let total = 1usize << MIN_K;
+        let stats = pool.stats();
+        assert_eq!(
+            (stats.total_bytes, stats.used_bytes, stats.free_bytes),
+            (total, 0, total)
+        );
+        assert_eq!(stats.largest_free_block, total);
+
+        let a = pool.malloc(1000 - AVAIL_SIZE).unwrap();
+        let b = pool.malloc(total / 4 - AVAIL_SIZE).unwrap();
+        let stats = pool.stats();
+        assert_eq!(stats.total_bytes, total);
+        assert_eq!(stats.used_bytes, 1024 + total / 4);
+        assert_eq!(stats.used_bytes + stats.free_bytes, stats.total_bytes);
+        assert_eq!(stats.largest_free_block, total / 2);
+        pool.free(a);
+        pool.free(b);
+        assert_eq!(pool.stats().free_bytes, total);
+        check_buddy_pool_full(&pool);
+    }
+
+    /// Tests that oom_stats counts the calls to the OOM handler and the retries that succeed after
+    /// it
+    #[test]
+    fn test_oom_stats() {
+        use core::sync::atomic::{AtomicPtr, Ordering};
+        // The cached block the handler may evict, null once it has been evicted
+        static CACHE: AtomicPtr<u8> = AtomicPtr::new(ptr::null_mut());
+
+        let mut pool = BuddyPool::new((1u64 << MIN_K) as usize).unwrap();
+        pool.init();
+        let half = (1usize << (MIN_K - 1)) - AVAIL_SIZE;
+        CACHE.store(pool.malloc(half).unwrap(), Ordering::SeqCst);
+        let held = pool.malloc(half).unwrap();
+        assert_eq!(pool.malloc(100), Err(BuddyError::NoMemory));
+        assert_eq!(pool.oom_stats(), (0, 0));
+
+        pool.set_oom_handler(|pool, _| {
+            let cached = CACHE.swap(ptr::null_mut(), Ordering::SeqCst);
+            if !cached.is_null() {
+                pool.free(cached);
+            }
+            !cached.is_null()
+        });
+        let mem = pool.malloc(half).unwrap();
+        assert_eq!(pool.oom_stats(), (1, 1));
+        assert_eq!(pool.malloc(100), Err(BuddyError::NoMemory));
+        assert_eq!(pool.oom_stats(), (2, 1));
+        pool.free(mem);
+        pool.free(held);
+        check_buddy_pool_full(&pool);
+    }
+
+    /// Tests that fragmentation is high when every other block is free and drops back to 0 once
+    /// everything is freed
+    #[test]
+    fn test_fragmentation() {
+        let mut pool = BuddyPool::new((1u64 << MIN_K) as usize).unwrap();
+        pool.init();
+        assert_eq!(pool.fragmentation(), 0.0);
+        let block = 1usize << 10;
+        let ptrs: Vec<_> = (0..(1 << MIN_K) / block)
+            .map(|_| pool.malloc(block - AVAIL_SIZE).unwrap())
+            .collect();
+        assert_eq!(pool.fragmentation(), 0.0);
+        for ptr in ptrs.iter().step_by(2) {
+            pool.free(*ptr);
+        }
+        let expected = 1.0 - 1.0 / (ptrs.len() / 2) as f64;
+        assert!((pool.fragmentation() - expected).abs() < 1e-9);
+        assert!(pool.fragmentation() > 0.99);
+        for ptr in ptrs.iter().skip(1).step_by(2) {
+            pool.free(*ptr);
+        }
+        assert_eq!(pool.fragmentation(), 0.0);
+        check_buddy_pool_full(&pool);
+    }
+
+    /// Tests that growing a shrunk block back to its former size takes back the halves split off
+    /// by the shrink, in place and without copying, and that it moves once one of them is reused
+    #[test]
+    fn test_realloc_shrink_then_grow() {
+        let mut pool = BuddyPool::new((1u64 << MIN_K) as usize).unwrap();
+        pool.init();
+        let size = (1usize << 13) - AVAIL_SIZE;
+        let mem = pool.malloc(size).unwrap();
+        let guard = pool.malloc(size).unwrap();
+        unsafe { mem.write_bytes(0x6B, 200) };
+        assert_eq!(pool.realloc(mem, 100).unwrap(), mem);
+        assert_eq!(pool.usable_size(mem), 256 - AVAIL_SIZE);
+        let used = pool.used_bytes();
+
+        assert_eq!(pool.realloc(mem, 2000).unwrap(), mem);
+        assert_eq!(pool.realloc(mem, size).unwrap(), mem);
+        assert_eq!(pool.used_bytes(), used + size + AVAIL_SIZE - 256);
+        // Bytes past the shrunk size were never touched, which shows nothing was copied over them
+        assert!(unsafe { pool.as_slice_mut(mem) }[..200].iter().all(|&b| b == 0x6B));
+
+        // Once a split off half is taken, the grow has to move the block
+        assert_eq!(pool.realloc(mem, 100).unwrap(), mem);
+        let other = pool.malloc(100).unwrap();
+        let moved = pool.realloc(mem, size).unwrap();
+        assert_ne!(moved, mem);
+        pool.free(other);
+        pool.free(moved);
+        pool.free(guard);
+        check_buddy_pool_full(&pool);
+    }
+
+    /// Tests that the hold cache keeps the newest shrunk blocks of each kval up to its bound, that
+    /// growing a block back takes it out of the cache, and that coalescing clears the cache
+    #[test]
+    fn test_realloc_hold_cache() {
+        let mut pool = BuddyPool::new((1u64 << MIN_K) as usize).unwrap();
+        pool.init();
+        let size = (1usize << 13) - AVAIL_SIZE;
+        let blocks: Vec<*mut u8> = (0..=HELD_SLOTS).map(|_| pool.malloc(size).unwrap()).collect();
+        for &block in &blocks {
+            assert_eq!(pool.realloc(block, 100).unwrap(), block);
+        }
+        let held = |pool: &BuddyPool, block: *mut u8| {
+            let offset = pool.offset_of(block) - AVAIL_SIZE;
+            pool.held[8].contains(&Some((offset, 13)))
+        };
+        // The oldest block was pushed out by the newer ones
+        assert!(!held(&pool, blocks[0]));
+        assert!(blocks[1..].iter().all(|&block| held(&pool, block)));
+
+        // Growing back is in place and takes the block out of the cache
+        let last = blocks[HELD_SLOTS];
+        assert_eq!(pool.realloc(last, size).unwrap(), last);
+        assert!(!held(&pool, last));
+        assert!(pool.held[8][HELD_SLOTS - 1].is_none());
+
+        // Growing part of the way back holds the block under its new kval
+        let block = blocks[1];
+        assert_eq!(pool.realloc(block, 900).unwrap(), block);
+        let offset = pool.offset_of(block) - AVAIL_SIZE;
+        assert_eq!(pool.held[10][0], Some((offset, 13)));
+
+        pool.coalesce_and_report();
+        assert!(pool.held.iter().flatten().all(Option::is_none));
+        for block in blocks {
+            pool.free(block);
+        }
+        check_buddy_pool_full(&pool);
+    }
+
+    /// Tests that freeing a block twice leaves the pool consistent, whether the block stayed on an
+    /// avail list or merged into its lower buddy, and that try_free reports it
+    #[test]
+    fn test_double_free() {
+        let mut pool = BuddyPool::new((1u64 << MIN_K) as usize).unwrap();
+        pool.init();
+        let lower = pool.malloc(1000 - AVAIL_SIZE).unwrap();
+        let upper = pool.malloc(1000 - AVAIL_SIZE).unwrap();
+        let live = pool.live_allocations();
+
+        // The upper block stays on its list while the lower one is reserved
+        assert_eq!(pool.try_free(upper), Ok(()));
+        assert_eq!(pool.try_free(upper), Err(BuddyError::CorruptedMemoryPool));
+        pool.free(upper);
+        assert_eq!(pool.live_allocations(), live - 1);
+        assert_eq!(pool.verify(), Ok(()));
+
+        // The lower block merges with the upper one and the whole pool comes back together
+        pool.free(lower);
+        pool.free(lower);
+        pool.free(upper);
+        check_buddy_pool_full(&pool);
+
+        // An upper block freed after its lower buddy merges into it, leaving its own header unused
+        let lower = pool.malloc(1000 - AVAIL_SIZE).unwrap();
+        let upper = pool.malloc(1000 - AVAIL_SIZE).unwrap();
+        pool.free(lower);
+        pool.free(upper);
+        assert_eq!(pool.try_free(upper), Err(BuddyError::CorruptedMemoryPool));
+        check_buddy_pool_full(&pool);
+
+        assert_eq!(pool.try_free(ptr::null_mut()), Ok(()));
+        let foreign = [0u8; 64];
+        assert_eq!(
+            pool.try_free(foreign.as_ptr() as *mut u8),
+            Err(BuddyError::CorruptedMemoryPool)
+        );
+    }
+
+    /// Tests that pools built with the same parameters share a fingerprint that allocations do not
+    /// change, and that assert_config rejects pools built differently
+    #[test]
+    fn test_config_fingerprint() {
+        // The fingerprint does not need the pools to be initialized
+        let build = |size: usize, auto_grow: bool| {
+            BuddyPool::builder()
+                .size(size)
+                .auto_grow(auto_grow)
+                .reserve_floor_bytes(4096)
+                .build()
+                .unwrap()
+        };
+        let mut pool = build(1 << MIN_K, false);
+        pool.init();
+        let fingerprint = pool.config_fingerprint();
+        assert_eq!(pool.assert_config(fingerprint), Ok(()));
+        let mem = pool.malloc(100).unwrap();
+        assert_eq!(pool.assert_config(fingerprint), Ok(()));
+        pool.free(mem);
+        assert_eq!(build(1 << MIN_K, false).assert_config(fingerprint), Ok(()));
+
+        assert_eq!(
+            build(1 << (MIN_K + 1), false).assert_config(fingerprint),
+            Err(BuddyError::ConfigMismatch)
+        );
+        assert_eq!(
+            build(1 << MIN_K, true).assert_config(fingerprint),
+            Err(BuddyError::ConfigMismatch)
+        );
+        pool.set_no_coalesce_range(10, 12);
+        assert_eq!(
+            pool.assert_config(fingerprint),
+            Err(BuddyError::ConfigMismatch)
+        );
+    }
+
+    /// Tests that a pool can manage a static buffer instead of a mapping, that it can not grow out
+    /// of it, and that unsupported buffers are rejected
+    #[test]
+    fn test_from_raw() {
+        #[repr(align(4096))]
+        struct Buffer([u8; 1 << MIN_K]);
+        static mut BUFFER: Buffer = Buffer([0; 1 << MIN_K]);
+
+        let base = unsafe { (&raw mut BUFFER.0).cast::<u8>() };
+        let mut pool = unsafe { BuddyPool::from_raw(base, 1 << MIN_K) }.unwrap();
+        pool.init();
+        assert_eq!(pool.base_addr(), base.addr());
+        let ptrs: Vec<_> = [100, 5000, 1 << 16]
+            .iter()
+            .map(|&size| pool.malloc(size).unwrap())
+            .collect();
+        for &ptr in &ptrs {
+            assert!(pool.contains(ptr));
+            unsafe { ptr.write_bytes(0xC3, 100) };
+        }
+        assert_eq!(pool.grow(1 << (MIN_K + 1)), Err(BuddyError::NoMemory));
+        for ptr in ptrs {
+            pool.free(ptr);
+        }
+        check_buddy_pool_full(&pool);
+        drop(pool);
+
+        assert!(matches!(
+            unsafe { BuddyPool::from_raw(base, 3 << 18) },
+            Err(BuddyError::InvalidSize)
+        ));
+        assert!(matches!(
+            unsafe { BuddyPool::from_raw(base.wrapping_add(1), 1 << MIN_K) },
+            Err(BuddyError::InvalidAlignment)
+        ));
+    }
+
+    /// Tests that malloc_aligned honours small alignments on a pool whose base is only aligned to
+    /// the header alignment, where the natural pointer of a block is not
+    #[test]
+    fn test_malloc_aligned_offset_base() {
+        #[repr(align(4096))]
+        struct Buffer([u8; 2 << MIN_K]);
+        static mut BUFFER: Buffer = Buffer([0; 2 << MIN_K]);
+
+        let base = unsafe { (&raw mut BUFFER.0).cast::<u8>().add(8) };
+        let mut pool = unsafe { BuddyPool::from_raw(base, 1 << MIN_K) }.unwrap();
+        pool.init();
+        let mut ptrs = Vec::new();
+        for align in [8, 16, 32, 64] {
+            let ptr = pool.malloc_aligned(10, align).unwrap();
+            assert!(ptr.addr().is_multiple_of(align));
+            unsafe { ptr.write_bytes(0x5A, 10) };
+            ptrs.push(ptr);
+        }
+        for ptr in ptrs {
+            pool.free(ptr);
+        }
+        check_buddy_pool_full(&pool);
+    }
+
+    /// Tests that a chaos failure rate of one half fails about half of the allocations, and that
+    /// the same seed fails the same ones
+    #[cfg(feature = "chaos")]
+    #[test]
+    fn test_chaos_failure_rate() {
+        let mut pool = BuddyPool::new((1u64 << MIN_K) as usize).unwrap();
+        pool.init();
+        let mut run = |seed: u64| -> Vec<bool> {
+            pool.set_chaos_failure_rate(0.5, seed);
+            (0..1000)
+                .map(|_| match pool.malloc(100) {
+                    Ok(ptr) => {
+                        pool.free(ptr);
+                        false
+                    }
+                    Err(error) => {
+                        assert_eq!(error, BuddyError::NoMemory);
+                        #[cfg(feature = "errno")]
+                        assert_eq!(errno().0, ENOMEM);
+                        true
+                    }
+                })
+                .collect()
+        };
+        let failures = run(7);
+        let failed = failures.iter().filter(|&&failed| failed).count();
+        assert!((400..=600).contains(&failed), "{failed} of 1000 failed");
+        assert_eq!(run(7), failures);
+        assert_ne!(run(8), failures);
+
+        pool.set_chaos_failure_rate(0.0, 7);
+        for _ in 0..100 {
+            let ptr = pool.malloc(100).unwrap();
+            pool.free(ptr);
+        }
+        check_buddy_pool_full(&pool);
+    }
+
+    /// Tests that several threads can allocate from and free to a SyncBuddyPool at once without
+    /// losing any memory
+    #[test]
+    fn test_sync_buddy_pool() {
+        use std::thread;
+
+        let mut pool = SyncBuddyPool::new(BuddyPool::new((1u64 << MIN_K) as usize).unwrap());
+        pool.init();
+        thread::scope(|scope| {
+            for t in 0..4 {
+                let pool = &pool;
+                scope.spawn(move || {
+                    for i in 0..500 {
+                        let ptr = pool.malloc(16 << ((t + i) % 8)).unwrap();
+                        unsafe { ptr.write_bytes(t as u8, 16) };
+                        let ptr = pool.realloc(ptr, 64 << (i % 4)).unwrap();
+                        assert_eq!(unsafe { ptr.read() }, t as u8);
+                        pool.free(ptr);
+                    }
+                });
+            }
+        });
+        pool.with_pool(|pool| check_buddy_pool_full(pool));
+    }
+
+    /// Tests that a panic while a SyncBuddyPool is locked releases the lock, so later calls do not
+    /// spin forever
+    #[test]
+    fn test_sync_buddy_pool_panic_unlocks() {
+        use std::panic::{self, AssertUnwindSafe};
+
+        let mut pool = SyncBuddyPool::new(BuddyPool::new((1u64 << MIN_K) as usize).unwrap());
+        pool.init();
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            pool.with_pool(|_| panic!("panic with the pool locked"))
+        }));
+        assert!(result.is_err());
+        let ptr = pool.malloc(100).unwrap();
+        pool.free(ptr);
+        pool.with_pool(|pool| check_buddy_pool_full(pool));
+    }
+
+    /// Tests that reset frees every allocation at once and leaves a usable pool
+    #[test]
+    fn test_reset() {
+        let mut pool = BuddyPool::new((1u64 << MIN_K) as usize).unwrap();
+        pool.init();
+        for i in 0..200 {
+            pool.malloc(16 << (i % 6)).unwrap();
+        }
+        assert_eq!(pool.live_allocations(), 200);
+
+        pool.reset();
+        check_buddy_pool_full(&pool);
+        assert_eq!(pool.live_allocations(), 0);
+        assert_eq!(pool.used_bytes(), 0);
+
+        let ptr = pool.malloc((1 << (MIN_K - 1)) - AVAIL_SIZE).unwrap();
+        pool.free(ptr);
+        check_buddy_pool_full(&pool);
+    }
+
+    /// Tests that gather_free merges free buddies of one size but leaves free blocks that are not
+    /// buddies apart
+    #[test]
+    fn test_gather_free() {
+        let mut pool = BuddyPool::new((1u64 << MIN_K) as usize).unwrap();
+        pool.init();
+        pool.set_no_coalesce_range(10, 10);
+        let ptrs: Vec<_> = (0..4)
+            .map(|_| pool.malloc(1000 - AVAIL_SIZE).unwrap())
+            .collect();
+        for (i, &ptr) in ptrs.iter().enumerate() {
+            assert_eq!(pool.offset_of(ptr), (i << 10) + AVAIL_SIZE);
+        }
+
+        // The first and third blocks are the same size but not buddies
+        pool.free(ptrs[0]);
+        pool.free(ptrs[2]);
+        assert_eq!(pool.free_counts[10], 2);
+        assert_eq!(pool.gather_free(10), 0);
+        assert_eq!(pool.free_counts[10], 2);
+
+        // The second block is the buddy of the first
+        pool.free(ptrs[1]);
+        assert_eq!(pool.free_counts[10], 3);
+        let free_11 = pool.free_counts[11];
+        assert_eq!(pool.gather_free(10), 1);
+        assert_eq!(pool.free_counts[10], 1);
+        assert_eq!(pool.free_counts[11], free_11 + 1);
+        assert_eq!(pool.gather_free(MIN_K), 0);
+
+        pool.set_no_coalesce_range(1, 0);
+        pool.free(ptrs[3]);
+        check_buddy_pool_full(&pool);
+    }
+
+    /// Tests that a block allocated with a size hint reports the whole block as usable and grows
+    /// up to the hint without moving, even after shrinking
+    #[test]
+    fn test_malloc_with_hint() {
+        let mut pool = BuddyPool::new((1u64 << MIN_K) as usize).unwrap();
+        pool.init();
+        let hint = 4000;
+        let mem = pool.malloc_with_hint(10, hint).unwrap();
+        assert_eq!(unsafe { (*pool.header_of(mem)).req_size }, 10);
+        assert_eq!(pool.usable_size(mem), (1 << 12) - AVAIL_SIZE);
+        unsafe { mem.write_bytes(0x5A, 10) };
+
+        for size in [100, 1000, 50, hint] {
+            assert_eq!(pool.realloc_cost(mem, size), Some((0, false)));
+            assert_eq!(pool.realloc(mem, size).unwrap(), mem);
+            assert_eq!(unsafe { (*pool.header_of(mem)).req_size }, size as u32);
+        }
+        assert!(unsafe { pool.as_slice_mut(mem) }[..10].iter().all(|&b| b == 0x5A));
+
+        // Growing past the hint takes the free buddy above as usual
+        let grown = pool.realloc(mem, 2 * hint).unwrap();
+        assert_eq!(grown, mem);
+        assert_eq!(unsafe { (*pool.header_of(mem)).kval }, 13);
+        pool.free(grown);
+        check_buddy_pool_full(&pool);
+    }
+
+    /// Tests that a pool with a larger smallest block rounds every request up to it and never
+    /// splits below it, and that the smallest block must be between a header and the pool
+    #[test]
+    fn test_new_with_limits() {
+        let min_k = 12;
+        let mut pool = BuddyPool::new_with_limits((1u64 << MIN_K) as usize, min_k).unwrap();
+        pool.init();
+        assert_eq!(pool.config().min_kval, min_k);
+        let ptrs: Vec<_> = [1, 100, 1 << 11]
+            .map(|size| pool.malloc(size).unwrap())
+            .into();
+        for &ptr in &ptrs {
+            assert_eq!(unsafe { (*pool.header_of(ptr)).kval }, min_k);
+        }
+        let big = pool.malloc(1 << 12).unwrap();
+        assert_eq!(unsafe { (*pool.header_of(big)).kval }, min_k + 1);
+
+        // Shrinking a block does not split it below the floor either
+        let small = pool.realloc(big, 1).unwrap();
+        assert_eq!(small, big);
+        assert_eq!(unsafe { (*pool.header_of(small)).kval }, min_k);
+        assert!(pool.free_counts[..min_k].iter().all(|&count| count == 0));
+        for ptr in ptrs {
+            pool.free(ptr);
+        }
+        pool.free(small);
+        check_buddy_pool_full(&pool);
+
+        let header_kval = AVAIL_SIZE.trailing_zeros() as usize;
+        let size = (1u64 << MIN_K) as usize;
+        assert!(BuddyPool::new_with_limits(size, header_kval).is_ok());
+        assert!(BuddyPool::new_with_limits(size, MIN_K).is_ok());
+        for min_k in [header_kval - 1, MIN_K + 1] {
+            let result = BuddyPool::new_with_limits(size, min_k);
+            assert_eq!(result.err(), Some(BuddyError::InvalidSize));
+            #[cfg(feature = "errno")]
+            assert_eq!(errno().0, EINVAL);
+        }
+    }
+
+    /// Tests that the blocks add up to the pool throughout a long run of allocations and frees,
+    /// and that a header grown over its free buddy breaks the count
+    #[test]
+    fn test_conservation_ok() {
+        let mut pool = BuddyPool::new((1u64 << MIN_K) as usize).unwrap();
+        pool.init();
+        assert!(pool.conservation_ok());
+        let mut live = Vec::new();
+        let mut seed = 0x2545f4914f6cdd1du64;
+        for _ in 0..2000 {
+            seed ^= seed << 13;
+            seed ^= seed >> 7;
+            seed ^= seed << 17;
+            if live.is_empty() || !seed.is_multiple_of(3) {
+                if let Ok(ptr) = pool.malloc((seed >> 8) as usize % 5000) {
+                    live.push(ptr);
+                }
+            } else {
+                let ptr = live.swap_remove((seed >> 8) as usize % live.len());
+                pool.free(ptr);
+            }
+            assert!(pool.conservation_ok());
+        }
+        for ptr in live {
+            pool.free(ptr);
+        }
+        check_buddy_pool_full(&pool);
+        assert!(pool.conservation_ok());
+
+        // The block's buddy is free, so a header claiming both counts the buddy twice
+        let mem = pool.malloc(1000 - AVAIL_SIZE).unwrap();
+        let header = unsafe { pool.header_of(mem) };
+        unsafe { (*header).kval = 11 };
+        assert!(!pool.conservation_ok());
+        unsafe { (*header).kval = 10 };
+        assert!(pool.conservation_ok());
+        pool.free(mem);
+        check_buddy_pool_full(&pool);
+    }
+
+    /// Tests that realloc grows a block in place when the buddies above it are free up to the new
+    /// size, and moves it when a buddy is reserved
+    #[test]
+    fn test_realloc_grow_coalesces() {
+        let mut pool = BuddyPool::new((1u64 << MIN_K) as usize).unwrap();
+        pool.init();
+        let mem = pool.malloc(100).unwrap();
+        unsafe { mem.write_bytes(0x7E, 100) };
+        let live = pool.live_allocations();
+        assert_eq!(pool.realloc_cost(mem, 4000), Some((4, false)));
+        let grown = pool.realloc(mem, 4000).unwrap();
+        assert_eq!(grown, mem);
+        assert_eq!(unsafe { (*pool.header_of(mem)).kval }, 12);
+        assert_eq!(pool.live_allocations(), live);
+        assert!(unsafe { pool.as_slice_mut(mem) }[..100].iter().all(|&b| b == 0x7E));
+        pool.free(mem);
+        check_buddy_pool_full(&pool);
+
+        // The buddy two levels up is reserved, so the block can not reach the new size in place
+        let mem = pool.malloc(100).unwrap();
+        let guard = pool.malloc(300).unwrap();
+        unsafe { mem.write_bytes(0x7E, 100) };
+        let moved = pool.realloc(mem, 4000).unwrap();
+        assert_ne!(moved, mem);
+        assert!(unsafe { pool.as_slice_mut(moved) }[..100].iter().all(|&b| b == 0x7E));
+        pool.free(moved);
+        pool.free(guard);
+        check_buddy_pool_full(&pool);
+    }
+
+    /// Tests that debug_poison fills newly allocated blocks with the allocated pattern and freed
+    /// blocks with the freed pattern, or with the freed marker when uaf_detect is on as well
+    #[cfg(feature = "debug_poison")]
+    #[test]
+    fn test_debug_poison() {
+        #[cfg(feature = "uaf_detect")]
+        let freed = FREED_MARKER;
+        #[cfg(not(feature = "uaf_detect"))]
+        let freed = POISON_FREED;
+
+        let mut pool = BuddyPool::new((1u64 << MIN_K) as usize).unwrap();
+        pool.init();
+        let mem = pool.malloc(1000 - AVAIL_SIZE).unwrap();
+        let usable = pool.usable_size(mem);
+        assert!(unsafe { pool.as_slice_mut(mem) }.iter().all(|&b| b == POISON_ALLOCATED));
+        unsafe { mem.write_bytes(0x11, usable) };
+
+        // The header was left alone, so the block still merges back into the whole pool
+        pool.free(mem);
+        let body = unsafe { core::slice::from_raw_parts(mem, usable) };
+        assert!(body.iter().all(|&b| b == freed));
+        check_buddy_pool_full(&pool);
+    }
+
+    /// Tests that alloc_one hands out memory aligned for its type that can be written and read back
+    /// through the typed pointer, and that dealloc_one returns it to the pool
+    #[test]
+    fn test_alloc_one() {
+        #[repr(align(64))]
+        struct Line([u8; 64]);
+
+        let mut pool = BuddyPool::new((1u64 << MIN_K) as usize).unwrap();
+        pool.init();
+        let value = pool.alloc_one::<u64>().unwrap();
+        unsafe {
+            value.as_ptr().write(0x0123_4567_89AB_CDEF);
+            assert_eq!(value.as_ptr().read(), 0x0123_4567_89AB_CDEF);
+        }
+        let line = pool.alloc_one::<Line>().unwrap();
+        assert!(line.as_ptr().is_aligned());
+        unsafe { line.as_ptr().write(Line([7; 64])) };
+        let empty = pool.alloc_one::<()>().unwrap();
+        unsafe {
+            assert_eq!((*line.as_ptr()).0, [7; 64]);
+            pool.dealloc_one(empty);
+            pool.dealloc_one(line);
+            pool.dealloc_one(value);
+        }
+        check_buddy_pool_full(&pool);
+    }
+
+    /// Tests that alloc_array hands out a slice of the requested length that can be written and
+    /// read back, gives empty arrays a dangling pointer and rejects lengths whose size overflows
+    #[test]
+    fn test_alloc_array() {
+        let mut pool = BuddyPool::new((1u64 << MIN_K) as usize).unwrap();
+        pool.init();
+        let array = pool.alloc_array::<u32>(256).unwrap();
+        assert_eq!(array.len(), 256);
+        assert!(array.cast::<u32>().as_ptr().is_aligned());
+        unsafe {
+            let elements = array.cast::<u32>().as_ptr();
+            for i in 0..256 {
+                elements.add(i).write(i as u32 * 3);
+            }
+            assert!((*array.as_ptr()).iter().copied().eq((0..256).map(|i| i * 3)));
+        }
+
+        let empty = pool.alloc_array::<u64>(0).unwrap();
+        assert_eq!(empty.len(), 0);
+        let units = pool.alloc_array::<()>(usize::MAX).unwrap();
+        assert_eq!(units.len(), usize::MAX);
+        assert_eq!(
+            pool.alloc_array::<u32>(usize::MAX / 2),
+            Err(BuddyError::NoMemory)
+        );
+        #[cfg(feature = "errno")]
+        assert_eq!(errno().0, ENOMEM);
+        unsafe {
+            pool.dealloc_array(units);
+            pool.dealloc_array(empty);
+            pool.dealloc_array(array);
+        }
+        check_buddy_pool_full(&pool);
+    }
+
+    /// Tests that validate accepts the lists after a normal allocation cycle and reports a broken
+    /// link or a wrong tag instead of panicking
+    #[test]
+    fn test_validate() {
+        let mut pool = BuddyPool::new((1u64 << MIN_K) as usize).unwrap();
+        pool.init();
+        assert_eq!(pool.validate(), Ok(()));
+        let ptrs: Vec<_> = (0..50).map(|i| pool.malloc(24 << (i % 7)).unwrap()).collect();
+        for ptr in ptrs.iter().step_by(2) {
+            pool.free(*ptr);
+        }
+        assert_eq!(pool.validate(), Ok(()));
+
+        let k = (0..pool.kval_m).find(|&k| pool.free_counts[k] > 0).unwrap();
+        let link = pool.avail[k].next;
+        let node = pool.ptr_at(link) as *mut Avail;
+        unsafe {
+            let prev = (*node).prev;
+            (*node).prev = link;
+            assert_eq!(pool.validate(), Err(BuddyError::CorruptedMemoryPool));
+            (*node).prev = prev;
+            (*node).set_state(BLOCK_RESERVED);
+            assert_eq!(pool.validate(), Err(BuddyError::CorruptedMemoryPool));
+            (*node).set_state(BLOCK_AVAIL);
+        }
+        assert_eq!(pool.validate(), Ok(()));
+
+        for ptr in ptrs.iter().skip(1).step_by(2) {
+            pool.free(*ptr);
+        }
+        assert_eq!(pool.validate(), Ok(()));
+        check_buddy_pool_full(&pool);
+    }
+
+    /// Tests that sizes at the ceiling of the platform's usize are capped or rejected with
+    /// NoMemory rather than overflowing
+    #[test]
+    fn test_platform_ceiling() {
+        assert!(MAX_K < usize::BITS as usize);
+        assert!(1usize << (MAX_K - 1) <= isize::MAX as usize);
+
+        // The largest pool is capped at the platform maximum, which may be too large to map
+        match BuddyPool::new(usize::MAX) {
+            Ok(pool) => assert_eq!(pool.capacity(), 1usize << (MAX_K - 1)),
+            Err(error) => assert_eq!(error, BuddyError::NoMemory),
+        }
+        assert_eq!(MultiBuddyPool::new(usize::MAX).err(), Some(BuddyError::NoMemory));
+
+        let mut pool = BuddyPool::new((1u64 << MIN_K) as usize).unwrap();
+        pool.init();
+        assert_eq!(pool.grow(usize::MAX), Err(BuddyError::NoMemory));
+        assert_eq!(pool.malloc(usize::MAX), Err(BuddyError::NoMemory));
+        assert_eq!(pool.suggest_padding(usize::MAX), 0);
+        assert_eq!(pool.suggest_padding(usize::MAX >> 1), 0);
+        check_buddy_pool_full(&pool);
+    }
+
+    /// Tests that a pool kept in a file can be reopened with its allocations intact, and that the
+    /// blocks found by offset can then be freed
+    #[cfg(feature = "std")]
+    #[test]
+    fn test_file_backed_pool() {
+        use std::fs::{self, OpenOptions};
+
+        let path = std::env::temp_dir().join(std::format!("buddy_pool_{}", std::process::id()));
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&path)
+            .unwrap();
+        let (kept, other) = {
+            let mut pool = BuddyPool::new_file(&file, (1u64 << MIN_K) as usize).unwrap();
+            pool.init();
+            let kept = pool.malloc(100).unwrap();
+            let freed = pool.malloc(100).unwrap();
+            let other = pool.malloc(5000).unwrap();
+            unsafe { kept.write_bytes(0x42, 100) };
+            pool.free(freed);
+            (pool.offset_of(kept), pool.offset_of(other))
+        };
+
+        let mut pool = unsafe { BuddyPool::reopen_file(&file, MIN_K) }.unwrap();
+        assert!(pool.repair().unwrap() > 0);
+        assert_eq!(pool.validate(), Ok(()));
+        assert_eq!(pool.live_allocations(), 2);
+        let kept = pool.ptr_at(kept);
+        assert!(unsafe { pool.as_slice_mut(kept) }[..100].iter().all(|&b| b == 0x42));
+        pool.free(kept);
+        pool.free(pool.ptr_at(other));
+        check_buddy_pool_full(&pool);
+        drop(pool);
+
+        assert_eq!(
+            unsafe { BuddyPool::reopen_file(&file, MIN_K + 1) }.err(),
+            Some(BuddyError::InvalidSize)
+        );
+        drop(file);
+        fs::remove_file(path).unwrap();
+    }
+
+    /// Tests that a request which fails with NoMemory in a full pool succeeds once the pool has
+    /// grown, and that the blocks from before growing can still be freed by their offsets
+    #[test]
+    fn test_grow_satisfies_failed_request() {
+        let size = (1u64 << MIN_K) as usize;
+        let mut pool = BuddyPool::new(size).unwrap();
+        pool.init();
+        let mem = pool.malloc(size - AVAIL_SIZE).unwrap();
+        let offset = pool.offset_of(mem);
+        assert_eq!(pool.malloc(1), Err(BuddyError::NoMemory));
+        pool.grow(size + 1).unwrap();
+        assert_eq!(pool.capacity(), 2 * size);
+        let more = pool.malloc(size - AVAIL_SIZE).unwrap();
+        assert_eq!(pool.offset_of(more), size + AVAIL_SIZE);
+        // Growing may move the pool, so the old block is found again by its offset
+        pool.free(pool.ptr_at(offset));
+        pool.free(more);
+        check_buddy_pool_full(&pool);
+    }
+
+    /// Tests that trimming a pool after everything was freed leaves it usable, with the dropped
+    /// pages faulting back in when the blocks are written again
+    #[cfg(unix)]
+    #[test]
+    fn test_trim() {
+        let size = (1u64 << MIN_K) as usize;
+        let mut pool = BuddyPool::new(size).unwrap();
+        pool.init();
+        let mem = pool.malloc(size - AVAIL_SIZE).unwrap();
+        unsafe { mem.write_bytes(0x7E, size - AVAIL_SIZE) };
+        pool.free(mem);
+        pool.trim();
+        check_buddy_pool_full(&pool);
+        assert!(pool.validate().is_ok());
+
+        let ptrs: Vec<_> = (0..4).map(|_| pool.malloc(5000).unwrap()).collect();
+        for &ptr in &ptrs {
+            unsafe {
+                ptr.write_bytes(0x3C, 5000);
+                assert!(core::slice::from_raw_parts(ptr, 5000)
+                    .iter()
+                    .all(|&b| b == 0x3C));
+            }
+        }
+        for ptr in ptrs {
+            pool.free(ptr);
+        }
+        pool.trim();
+        let mem = pool.malloc(size - AVAIL_SIZE).unwrap();
+        pool.free(mem);
+        check_buddy_pool_full(&pool);
+    }
+
+    /// Tests that auto_maintain trims the pool, so the pages of a freed block read back as zeroes
+    #[cfg(all(target_os = "linux", not(miri)))]
+    #[test]
+    fn test_auto_maintain_trims() {
+        let size = (1u64 << MIN_K) as usize;
+        let mut pool = BuddyPool::new(size).unwrap();
+        pool.init();
+        let mem = pool.malloc(size - AVAIL_SIZE).unwrap();
+        unsafe { mem.write_bytes(0x7E, size - AVAIL_SIZE) };
+        pool.free(mem);
+        assert_eq!(pool.auto_maintain(), size - AVAIL_SIZE);
+        let page = page_size();
+        let body = unsafe { core::slice::from_raw_parts(pool.base.as_ptr().add(page), size - page) };
+        assert!(body.iter().all(|&b| b == 0));
+        check_buddy_pool_full(&pool);
+    }
+
+    /// Tests that allocated_blocks reports exactly the outstanding allocations with their usable
+    /// sizes, in address order
+    #[test]
+    fn test_allocated_blocks() {
+        let mut pool = BuddyPool::new(0).unwrap();
+        pool.init();
+        assert_eq!(pool.allocated_blocks().count(), 0);
+        let mut ptrs: Vec<_> = [100, 5000, 1 << 16]
+            .iter()
+            .map(|&size| pool.malloc(size).unwrap())
+            .collect();
+        ptrs.sort();
+        let expected: Vec<_> = ptrs.iter().map(|&ptr| (ptr, pool.usable_size(ptr))).collect();
+        assert_eq!(pool.allocated_blocks().collect::<Vec<_>>(), expected);
+        assert!(expected.iter().any(|&(_, usable)| usable == 256 - AVAIL_SIZE));
+
+        pool.free(ptrs[1]);
+        let remaining: Vec<_> = pool.allocated_blocks().map(|(ptr, _)| ptr).collect();
+        assert_eq!(remaining, [ptrs[0], ptrs[2]]);
+        pool.free(ptrs[0]);
+        pool.free(ptrs[2]);
+        check_buddy_pool_full(&pool);
+    }
+
+    /// Tests that a Vec allocating from a SyncBuddyPool keeps its contents through several
+    /// reallocations, is given the slack of its blocks and returns everything to the pool
+    #[cfg(feature = "allocator_api")]
+    #[test]
+    fn test_allocator_api() {
+        let mut pool = SyncBuddyPool::new(BuddyPool::new((1u64 << MIN_K) as usize).unwrap());
+        pool.init();
+        {
+            let mut v = Vec::new_in(&pool);
+            for i in 0..10_000u32 {
+                v.push(i);
+            }
+            assert!(v.iter().copied().eq(0..10_000));
+            // The Vec is given the whole usable block, which is rounded up to a power of two
+            assert!(v.capacity() * size_of::<u32>() + AVAIL_SIZE >= 1 << 16);
+            v.truncate(10);
+            v.shrink_to_fit();
+            assert!(v.iter().copied().eq(0..10));
+
+            let aligned = Box::new_in(Aligned([7; 64]), &pool);
+            assert!((&raw const *aligned).addr().is_multiple_of(256));
+            assert_eq!(aligned.0, [7; 64]);
+            assert_eq!(*Box::new_in((), &pool), ());
+        }
+        pool.with_pool(|pool| check_buddy_pool_full(pool));
+    }
+
+    /// A type whose alignment is larger than malloc's natural alignment
+    #[cfg(feature = "allocator_api")]
+    #[repr(align(256))]
+    struct Aligned([u8; 64]);
+
+    /// Tests that realloc_zeroed keeps the old contents and zeroes the bytes the block gains, both
+    /// when the block grows in place and when it has to move
+    #[test]
+    fn test_realloc_zeroed() {
+        let mut pool = BuddyPool::new((1u64 << MIN_K) as usize).unwrap();
+        pool.init();
+        let mem = pool.malloc(100).unwrap();
+        let usable = pool.usable_size(mem);
+        unsafe { mem.write_bytes(0xAA, usable) };
+        // Still fits in the block, so there is nothing to zero
+        assert_eq!(pool.realloc_zeroed(mem, usable).unwrap(), mem);
+        let check = |ptr: *mut u8, old: usize, new: usize| unsafe {
+            let bytes = core::slice::from_raw_parts(ptr, new);
+            assert!(bytes[..old].iter().all(|&b| b == 0xAA));
+            assert!(bytes[old..].iter().all(|&b| b == 0));
+        };
+
+        // The free buddies above the block are absorbed in place
+        let grown = pool.realloc_zeroed(mem, 5000).unwrap();
+        assert_eq!(grown, mem);
+        check(grown, usable, pool.usable_size(grown));
+        let usable = pool.usable_size(grown);
+        unsafe { grown.write_bytes(0xAA, usable) };
+
+        // A guard in the buddy above forces the block to move
+        let guard = pool.malloc(100).unwrap();
+        let moved = pool.realloc_zeroed(grown, 10_000).unwrap();
+        assert_ne!(moved, grown);
+        check(moved, usable, pool.usable_size(moved));
+
+        assert_eq!(pool.realloc_zeroed(moved, 0).unwrap(), ZERO_SIZE_PTR);
+        pool.free(guard);
+        let fresh = pool.realloc_zeroed(ptr::null_mut(), 100).unwrap();
+        check(fresh, 0, pool.usable_size(fresh));
+        pool.free(fresh);
+        check_buddy_pool_full(&pool);
+    }
+
+    /// Tests that the avail lists stay consistent when the pool's memory is copied to a mapping at
+    /// a different address and the pool itself is moved, as the lists only hold offsets from the
+    /// base and name their heads by kval
+    #[test]
+    fn test_position_independent_lists() {
+        let size = (1u64 << MIN_K) as usize;
+        let mut pool = BuddyPool::new(size).unwrap();
+        pool.init();
+        let ptrs: Vec<_> = [100, 5000, 300, 1 << 14, 24]
+            .iter()
+            .map(|&size| pool.malloc(size).unwrap())
+            .collect();
+        pool.free(ptrs[1]);
+        pool.free(ptrs[2]);
+        let offsets: Vec<_> = [0, 3, 4].iter().map(|&i| pool.offset_of(ptrs[i])).collect();
+        let free_counts = pool.free_counts;
+
+        let mut map = MmapMut::map_anon(size).unwrap();
+        map.copy_from_slice(unsafe { core::slice::from_raw_parts(pool.base.as_ptr(), size) });
+        let old_base = pool.base_addr();
+        pool.base = Region::Mapped(map);
+        pool.base_ptr = pool.base.as_mut_ptr();
+        assert_ne!(pool.base_addr(), old_base);
+        let mut pools = Vec::from([pool]);
+        let pool = &mut pools[0];
+
+        assert_eq!(pool.validate(), Ok(()));
+        assert!(pool.conservation_ok());
+        assert_eq!(pool.free_counts, free_counts);
+        for k in 0..=pool.kval_m {
+            assert_eq!(get_size_and_validate(pool, k), pool.free_counts[k]);
+        }
+        for offset in offsets {
+            pool.free(pool.ptr_at(offset));
+        }
+        check_buddy_pool_full(pool);
+    }
+
+    /// Tests that an observer sees a malloc, realloc and free in order, with the moving realloc
+    /// reported as a single event, and that failed operations are not reported
+    #[test]
+    fn test_observer() {
+        use core::sync::atomic::{AtomicUsize, Ordering};
+        static LOG: [AtomicUsize; 12] = [const { AtomicUsize::new(0) }; 12];
+        static LEN: AtomicUsize = AtomicUsize::new(0);
+        fn record(event: AllocEvent) {
+            let entry = match event {
+                AllocEvent::Malloc { ptr, size, kval } => [1, ptr.addr(), size, kval],
+                AllocEvent::Free { ptr, kval } => [2, ptr.addr(), 0, kval],
+                AllocEvent::Realloc {
+                    old_ptr,
+                    new_ptr,
+                    new_size,
+                } => [3, old_ptr.addr(), new_ptr.addr(), new_size],
+            };
+            let start = LEN.fetch_add(entry.len(), Ordering::Relaxed);
+            for (slot, value) in LOG[start..].iter().zip(entry) {
+                slot.store(value, Ordering::Relaxed);
+            }
+        }
+
+        let mut pool = BuddyPool::new((1u64 << MIN_K) as usize).unwrap();
+        pool.init();
+        let guard = pool.malloc(100).unwrap();
+        pool.set_observer(record);
+        let mem = pool.malloc(100).unwrap();
+        assert!(pool.malloc(usize::MAX).is_err());
+        let moved = pool.realloc(mem, 5000).unwrap();
+        pool.free(moved);
+        pool.free(moved);
+        let log: Vec<_> = LOG.iter().map(|slot| slot.load(Ordering::Relaxed)).collect();
+        assert_eq!(LEN.load(Ordering::Relaxed), 12);
+        assert_eq!(
+            log,
+            [
+                [1, mem.addr(), 100, 8],
+                [3, mem.addr(), moved.addr(), 5000],
+                [2, moved.addr(), 0, 13],
+            ]
+            .concat()
+        );
+        pool.free(guard);
+        check_buddy_pool_full(&pool);
+    }
+
+    /// Tests that realloc to 0 frees a block that is already at the kval a zero sized request
+    /// maps to, through every realloc variant, rather than keeping it because it fits
+    #[test]
+    fn test_realloc_0_smallest_block() {
+        let mut pool = BuddyPool::new((1u64 << MIN_K) as usize).unwrap();
+        pool.init();
+        let smallest = b_to_k(AVAIL_SIZE);
+        for variant in 0..3 {
+            let ptr = pool.malloc(0).unwrap();
+            assert_eq!(unsafe { (*pool.header_of(ptr)).kval }, smallest);
+            let result = match variant {
+                0 => pool.realloc(ptr, 0),
+                1 => pool.realloc_copy(ptr, 0, 0),
+                _ => pool.realloc_zeroed(ptr, 0),
+            };
+            assert_eq!(result, Ok(ZERO_SIZE_PTR));
+            assert_eq!(pool.live_allocations(), 0);
+            assert_eq!(pool.allocated_blocks().count(), 0);
+            check_buddy_pool_full(&pool);
+        }
+    }
+
+    /// Tests that a pool with fewer avail list heads is smaller, works like a default pool, and
+    /// refuses sizes and growth that would need a kval past its heads
+    #[test]
+    fn test_const_generic_heads() {
+        const SMALL: usize = MIN_K + 3;
+        assert!(size_of::<BuddyPool<SMALL>>() < size_of::<BuddyPool>());
+        assert_eq!(
+            BuddyPool::<SMALL>::with_max_k(1 << SMALL).err(),
+            Some(BuddyError::InvalidSize)
+        );
+
+        let mut pool = BuddyPool::<SMALL>::with_max_k(1 << (SMALL - 1)).unwrap();
+        pool.init();
+        assert_eq!(pool.capacity(), 1 << (SMALL - 1));
+        assert_eq!(pool.config().max_kval, SMALL - 1);
+        let ptrs: Vec<_> = (0..4).map(|_| pool.malloc(100).unwrap()).collect();
+        for ptr in ptrs {
+            pool.free(ptr);
+        }
+        assert!(pool.validate().is_ok());
+        assert_eq!(pool.free_bytes(), pool.capacity());
+        assert_eq!(pool.grow(1 << SMALL), Err(BuddyError::NoMemory));
+    }
+
+    /// Tests that free_many frees a batch in any order, skipping nulls and repeated pointers
+    #[test]
+    fn test_free_many() {
+        let mut pool = BuddyPool::new(0).unwrap();
+        pool.init();
+        let ptrs: Vec<_> = (0..50).map(|_| pool.malloc(100).unwrap()).collect();
+        // Visit the blocks in a scrambled order, 17 being coprime with 50
+        let mut batch: Vec<_> = (0..50).map(|i| ptrs[i * 17 % 50]).collect();
+        batch.push(ptr::null_mut());
+        batch.push(ptrs[3]);
+        pool.free_many(&batch);
+        check_buddy_pool_full(&pool);
+    }
+
+    /// Tests that capacity reports the rounded up size of the pool and min_alloc_size the usable
+    /// bytes of its smallest block
+    #[test]
+    fn test_capacity_and_min_alloc_size() {
+        let mut pool = BuddyPool::new((1 << MIN_K) + 12345).unwrap();
+        pool.init();
+        assert_eq!(pool.capacity(), 1 << (MIN_K + 1));
+        let min = pool.min_alloc_size();
+        assert_eq!(min, (1 << b_to_k(AVAIL_SIZE + 1)) - AVAIL_SIZE);
+        let ptr = pool.malloc(1).unwrap();
+        assert_eq!(pool.usable_size(ptr), min);
+        pool.free(ptr);
+
+        let pool = BuddyPool::new_with_limits(1 << MIN_K, 10).unwrap();
+        assert_eq!(pool.min_alloc_size(), (1 << 10) - AVAIL_SIZE);
+    }
+
+    /// Tests that the Debug output of a pool lists the free blocks on each non-empty avail list,
+    /// and marks a list whose links leave the pool as truncated
+    #[test]
+    fn test_debug_free_lists() {
+        let mut pool = BuddyPool::new(1 << MIN_K).unwrap();
+        pool.init();
+        let full = std::format!("{:?}", pool);
+        assert!(full.contains(&std::format!("kval_m: {}", MIN_K)));
+        assert!(full.ends_with(&std::format!("free: {{ {}: 1 }} }}", MIN_K)));
+
+        // A block of kval 8 leaves one free buddy at every level from 8 up to MIN_K - 1
+        let ptr = pool.malloc(100).unwrap();
+        let split = std::format!("{:?}", pool);
+        for kval in 8..MIN_K {
+            assert!(split.contains(&std::format!(" {kval}: 1")));
+        }
+        assert!(!split.contains(&std::format!(" {}: ", MIN_K)));
+
+        let next = pool.avail[8].next;
+        pool.avail[8].next = usize::MAX / 2;
+        assert!(std::format!("{:?}", pool).contains(" 8: 0+"));
+        pool.avail[8].next = next;
+        pool.free(ptr);
+        check_buddy_pool_full(&pool);
+    }
+
+    /// Tests that free turns away a pointer from another pool and a pointer into the middle of a
+    /// block, leaving both pools intact
+    #[test]
+    fn test_free_foreign_and_interior_pointers() {
+        let mut a = BuddyPool::new(1 << MIN_K).unwrap();
+        let mut b = BuddyPool::new(1 << MIN_K).unwrap();
+        a.init();
+        b.init();
+        let from_a = a.malloc(100).unwrap();
+        let from_b = b.malloc(5000).unwrap();
+
+        assert_eq!(b.try_free(from_a), Err(BuddyError::CorruptedMemoryPool));
+        b.free(from_a);
+        for interior in [8, 100, 256, 1024] {
+            let ptr = unsafe { from_b.add(interior) };
+            assert_eq!(b.try_free(ptr), Err(BuddyError::CorruptedMemoryPool));
+        }
+        assert!(a.validate().is_ok());
+        assert!(b.validate().is_ok());
+
+        a.free(from_a);
+        b.free(from_b);
+        check_buddy_pool_full(&a);
+        check_buddy_pool_full(&b);
+    }
+
+    /// Tests that shrink splits a block down in place, returning the upper halves to the avail
+    /// lists, and refuses to grow a block
+    #[test]
+    fn test_shrink() {
+        let mut pool = BuddyPool::new(0).unwrap();
+        pool.init();
+        let ptr = pool.malloc(512 - AVAIL_SIZE).unwrap();
+        assert_eq!(get_size_and_validate(&pool, 9), 1);
+        pool.shrink(ptr, 32).unwrap();
+        assert_eq!(pool.usable_size(ptr), 64 - AVAIL_SIZE);
+        for kval in 6..=8 {
+            assert_eq!(get_size_and_validate(&pool, kval), 1);
+        }
+        assert_eq!(get_size_and_validate(&pool, 9), 1);
+
+        assert_eq!(pool.shrink(ptr, 64), Err(BuddyError::InvalidSize));
+        assert_eq!(
+            pool.shrink(unsafe { ptr.add(8) }, 8),
+            Err(BuddyError::CorruptedMemoryPool)
+        );
+        pool.free(ptr);
+        check_buddy_pool_full(&pool);
+    }
+
+    /// Tests that the peak usage keeps the earlier high point after frees, that NoMemory errors
+    /// from malloc, calloc and realloc are each counted once, and that reset_metrics zeroes both
+    #[test]
+    fn test_failure_count_and_peak() {
+        let mut pool = BuddyPool::new(1 << MIN_K).unwrap();
+        pool.init();
+        let quarter = pool.capacity() / 4 - AVAIL_SIZE;
+        let ptrs: Vec<_> = (0..4).map(|_| pool.malloc(quarter).unwrap()).collect();
+        assert_eq!(pool.failure_count(), 0);
+        assert_eq!(pool.malloc(1), Err(BuddyError::NoMemory));
+        assert_eq!(pool.failure_count(), 1);
+        pool.free_many(&ptrs);
+
+        let ptrs: Vec<_> = (0..3).map(|_| pool.malloc(quarter).unwrap()).collect();
+        assert_eq!(pool.peak_usage(), pool.capacity());
+        assert_eq!(pool.peak_used_bytes(), pool.peak_usage());
+        assert_eq!(pool.used_bytes(), pool.capacity() / 4 * 3);
+
+        // The buddy above the first block is reserved, so realloc falls back to a malloc that fails
+        assert_eq!(
+            pool.realloc(ptrs[0], pool.capacity() / 2),
+            Err(BuddyError::NoMemory)
+        );
+        assert_eq!(pool.failure_count(), 2);
+        assert_eq!(
+            pool.realloc(ptrs[0], pool.capacity()),
+            Err(BuddyError::NoMemory)
+        );
+        assert_eq!(pool.failure_count(), 3);
+        assert_eq!(pool.calloc(usize::MAX, 2), Err(BuddyError::NoMemory));
+        assert_eq!(pool.failure_count(), 4);
+
+        pool.reset_metrics();
+        assert_eq!(pool.failure_count(), 0);
+        assert_eq!(pool.peak_usage(), 0);
+        // The next allocation brings the peak back up to everything reserved
+        let mem = pool.malloc(100).unwrap();
+        assert_eq!(pool.peak_usage(), pool.used_bytes());
+        pool.free(mem);
+        pool.free_many(&ptrs);
+        check_buddy_pool_full(&pool);
+    }
+
+    /// Tests that new_exact rejects sizes that new clamps to the minimum or maximum pool size, and
+    /// that capacity reports the size either one granted
+    #[test]
+    fn test_new_exact() {
+        let small = 1 << (MIN_K - 1);
+        assert_eq!(BuddyPool::new_exact(small).err(), Some(BuddyError::InvalidSize));
+        assert_eq!(BuddyPool::new_exact(0).err(), Some(BuddyError::InvalidSize));
+        assert_eq!(BuddyPool::new(small).unwrap().capacity(), 1 << MIN_K);
+        assert_eq!(BuddyPool::new(small).unwrap().granted_size(), 1 << MIN_K);
+
+        let pool = BuddyPool::new_exact((1 << MIN_K) + 1).unwrap();
+        assert_eq!(pool.capacity(), 1 << (MIN_K + 1));
+        assert_eq!(pool.granted_size(), pool.capacity());
+
+        let huge = (1usize << (MAX_K - 1)) + 1;
+        assert_eq!(BuddyPool::new_exact(huge).err(), Some(BuddyError::InvalidSize));
+        assert_eq!(
+            BuddyPool::new_exact(usize::MAX).err(),
+            Some(BuddyError::InvalidSize)
+        );
+    }
+
+    /// Tests that the buddy of a block's buddy is the block itself at every kval, and that the two
+    /// differ only in the bit of their size
+    #[test]
+    fn test_list_buddy_offset() {
+        for kval in 5..MIN_K {
+            for offset in (0..1usize << MIN_K).step_by(1 << kval).take(64) {
+                let buddy = list::buddy_offset(offset, kval);
+                assert_eq!(list::buddy_offset(buddy, kval), offset);
+                assert_eq!(buddy ^ offset, 1 << kval);
+            }
+        }
+    }
+
+    /// Tests the list primitives on crafted nodes: inserting links blocks in at the tail of their
+    /// list, and removing them in any order leaves the list as an empty circle around its head
+    #[test]
+    fn test_list_insert_remove() {
+        let mut heads: Vec<_> = (0..4).map(|_| Avail::new()).collect();
+        for (kval, head) in heads.iter_mut().enumerate() {
+            head.next = head_link(kval);
+            head.prev = head_link(kval);
+        }
+        let mut nodes: Vec<_> = (0..3).map(|_| Avail::new()).collect();
+        let base = nodes.as_mut_ptr() as *mut u8;
+        let node = |i: usize| unsafe { &mut *(base as *mut Avail).add(i) };
+        let link = |i: usize| i * AVAIL_SIZE;
+
+        for i in 0..3 {
+            node(i).kval = 2;
+            unsafe { list::insert(&mut heads, base, node(i)) };
+        }
+        assert_eq!(heads[2].next, link(0));
+        assert_eq!(heads[2].prev, link(2));
+        assert_eq!((node(1).prev, node(1).next), (link(0), link(2)));
+        assert_eq!(node(2).next, head_link(2));
+
+        for i in [1, 2, 0] {
+            unsafe { list::remove(&mut heads, base, node(i)) };
+            assert_eq!((node(i).next, node(i).prev), (list::UNLINKED, list::UNLINKED));
+        }
+        for (kval, head) in heads.iter().enumerate() {
+            assert_eq!((head.next, head.prev), (head_link(kval), head_link(kval)));
+        }
+    }
+
+    /// Tests that allocating a block larger than the pool size fails with NoMemory, and sets errno
+    /// to ENOMEM when the errno feature is on
+    #[test]
+    fn test_alloc_too_large() {
+        let mut pool = BuddyPool::new((1u64 << MIN_K) as usize).unwrap();
+        pool.init();
+        let mem = pool.malloc(1 << (pool.kval_m + 1));
+        assert!(mem.is_err());
+        assert_eq!(mem, Err(BuddyError::NoMemory));
+        #[cfg(feature = "errno")]
+        assert_eq!(errno().0, ENOMEM);
+        check_buddy_pool_full(&pool);
+    }
+
+    /// A test which fails if the pool has any available blocks
+    ///
+    /// # Arguments
+    /// *pool - The buddy pool to check
+    fn check_buddy_pool_empty(pool: &BuddyPool) {
+        for i in 0..=pool.kval_m {
+            assert_eq!(pool.avail[i].kval, i);
+            assert_eq!(get_size_and_validate(pool, i), 0);
+        }
+    }
+
+    /// A test which fails if the pool has any blocks that are not available
+    ///
+    /// # Arguments
+    /// *pool - The buddy pool to check
+    fn check_buddy_pool_full(pool: &BuddyPool) {
+        //A full pool should have all values 0-(kval-1) as empty
+        for i in 0..pool.kval_m {
+            assert_eq!(pool.avail[i].kval, i);
+            assert_eq!(get_size_and_validate(pool, i), 0);
+        }
+        //The avail array at kval should have the base block
+        assert_eq!(pool.avail[pool.kval_m].kval, pool.kval_m);
+        assert_eq!(get_size_and_validate(pool, pool.kval_m), 1);
+        assert_eq!(pool.avail[pool.kval_m].next, pool.avail[pool.kval_m].prev);
+        //Check to make sure the base block is the one on the list
+        //If this fails either buddy_init is wrong or we have corrupted the
+        //buddy_pool struct.
+        assert_eq!(pool.avail[pool.kval_m].next, 0);
     }
 
     /// Tests that an Avail list has the correct values and returns the number of free blocks of
     /// that size. The list is also checked in reverse to ensure that it is circular.
     ///
     /// # Arguments
-    /// *list - The avail list to check, which should be a pointer to the first block in the list
+    /// *pool - The buddy pool holding the list
+    /// *kval - The kval of the avail list to check
     ///
     /// # Returns
     /// * The number of blocks in the list
-    fn get_size_and_validate(list: &Avail) -> usize {
-        let kval = list.kval;
-        assert_eq!(list.tag, BLOCK_UNUSED);
+    fn get_size_and_validate(pool: &BuddyPool, kval: usize) -> usize {
+        let list = &pool.avail[kval];
+        assert_eq!(list.kval, kval);
+        assert_eq!(list.state(), BLOCK_UNUSED);
         let mut count = 0;
-        let mut current = list.next as *const Avail;
-        while current != list {
+        let mut current = list.next;
+        while current != head_link(kval) {
             count += 1;
-            let a = unsafe { current.as_ref().unwrap() };
-            assert_eq!(a.tag, BLOCK_AVAIL);
+            let a = unsafe { (pool.ptr_at(current) as *const Avail).as_ref().unwrap() };
+            assert_eq!(a.state(), BLOCK_AVAIL);
             assert_eq!(a.kval, kval);
             current = a.next;
         }
 
         let mut count_rev = 0;
         current = list.prev;
-        while current != list {
+        while current != head_link(kval) {
             count_rev += 1;
-            let a = unsafe { current.as_ref().unwrap() };
-            assert_eq!(a.tag, BLOCK_AVAIL);
+            let a = unsafe { (pool.ptr_at(current) as *const Avail).as_ref().unwrap() };
+            assert_eq!(a.state(), BLOCK_AVAIL);
             assert_eq!(a.kval, kval);
             current = a.prev;
         }
@@ -0,0 +1,151 @@
+use crate::buddy_error::BuddyError;
+use crate::BuddyPool;
+use core::mem;
+use core::ops::{Deref, DerefMut};
+use core::task::Waker;
+use std::sync::{Mutex, MutexGuard, PoisonError, TryLockError};
+use std::vec::Vec;
+
+/// A memory pool that can be shared between threads. Every operation locks the whole pool, as the
+/// avail lists must not be seen half updated.
+pub struct ThreadSafeBuddyPool {
+    pool: Mutex<BuddyPool>,     // The pool, only touched with the mutex held
+    waiters: Mutex<Vec<Waker>>, // Tasks waiting for the pool to be unlocked
+}
+
+// SAFETY: the raw pointers in a BuddyPool only point into its own mapping, which is not tied to a
+// thread, and they are only touched with the mutex held.
+unsafe impl Send for ThreadSafeBuddyPool {}
+unsafe impl Sync for ThreadSafeBuddyPool {}
+
+impl ThreadSafeBuddyPool {
+    /// Create a new thread-safe pool around a pool from BuddyPool::new or the builder. The pool
+    /// is initialized here, so anything allocated from it before is lost.
+    ///
+    /// # Arguments
+    /// * pool - The pool to share
+    ///
+    /// # Returns
+    /// a new ThreadSafeBuddyPool struct
+    pub fn new(mut pool: BuddyPool) -> ThreadSafeBuddyPool {
+        pool.init();
+        ThreadSafeBuddyPool {
+            pool: Mutex::new(pool),
+            waiters: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Locks the pool, blocking the thread until it is available. A pool whose lock was poisoned
+    /// by a panicking thread is still handed out, as every operation leaves the lists consistent
+    /// before it can panic.
+    ///
+    /// # Returns
+    /// a guard giving access to the pool until it is dropped
+    pub fn lock(&self) -> PoolGuard<'_> {
+        let guard = self.pool.lock().unwrap_or_else(PoisonError::into_inner);
+        PoolGuard {
+            guard: Some(guard),
+            pool: self,
+        }
+    }
+
+    /// Locks the pool if no other thread holds it.
+    ///
+    /// # Returns
+    /// a guard giving access to the pool, or None if the pool is locked
+    pub fn try_lock(&self) -> Option<PoolGuard<'_>> {
+        let guard = match self.pool.try_lock() {
+            Ok(guard) => guard,
+            Err(TryLockError::Poisoned(poisoned)) => poisoned.into_inner(),
+            Err(TryLockError::WouldBlock) => return None,
+        };
+        Some(PoolGuard {
+            guard: Some(guard),
+            pool: self,
+        })
+    }
+
+    /// Locks the pool and calls malloc on it, see BuddyPool::malloc
+    ///
+    /// # Arguments
+    /// * size - The size of the user requested memory block in bytes
+    ///
+    /// # Returns
+    /// a pointer to the memory block
+    pub fn malloc(&self, size: usize) -> Result<*mut u8, BuddyError> {
+        self.lock().malloc(size)
+    }
+
+    /// Locks the pool and calls free on it, see BuddyPool::free
+    ///
+    /// # Arguments
+    /// * ptr - Pointer to the memory block to free
+    pub fn free(&self, ptr: *mut u8) {
+        self.lock().free(ptr)
+    }
+
+    /// Locks the pool and calls realloc on it, see BuddyPool::realloc
+    ///
+    /// # Arguments
+    /// * ptr - Pointer to a memory block
+    /// * size - The new size of the memory block
+    ///
+    /// # Returns
+    /// a pointer to the new memory block
+    pub fn realloc(&self, ptr: *mut u8, size: usize) -> Result<*mut u8, BuddyError> {
+        self.lock().realloc(ptr, size)
+    }
+
+    /// Registers a task to be woken the next time the pool is unlocked. The caller must try to
+    /// lock the pool again after registering, as it may have been unlocked in between.
+    ///
+    /// # Arguments
+    /// * waker - The waker of the waiting task
+    #[cfg(feature = "async_pool")]
+    pub(crate) fn wait(&self, waker: &Waker) {
+        let mut waiters = self.waiters.lock().unwrap_or_else(PoisonError::into_inner);
+        if !waiters.iter().any(|w| w.will_wake(waker)) {
+            waiters.push(waker.clone());
+        }
+    }
+}
+
+/// Access to a locked ThreadSafeBuddyPool. Dropping the guard unlocks the pool and wakes every
+/// task waiting for it, the ones that lose the race for the lock wait again.
+pub struct PoolGuard<'a> {
+    guard: Option<MutexGuard<'a, BuddyPool>>, // The lock, taken on drop to unlock first
+    pool: &'a ThreadSafeBuddyPool,            // The pool whose waiters are woken on drop
+}
+
+impl Deref for PoolGuard<'_> {
+    type Target = BuddyPool;
+
+    fn deref(&self) -> &BuddyPool {
+        self.guard.as_ref().unwrap()
+    }
+}
+
+impl DerefMut for PoolGuard<'_> {
+    fn deref_mut(&mut self) -> &mut BuddyPool {
+        self.guard.as_mut().unwrap()
+    }
+}
+
+impl Drop for PoolGuard<'_> {
+    /// Unlocks the pool before waking the waiters, so that a woken task can not find it still
+    /// locked and go back to sleep with nobody left to wake it.
+    fn drop(&mut self) {
+        drop(self.guard.take());
+        let waiters = {
+            let mut waiters = self
+                .pool
+                .waiters
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner);
+            mem::take(&mut *waiters)
+        };
+        for waker in waiters {
+            waker.wake();
+        }
+    }
+}